@@ -1,8 +1,13 @@
-use config::ConfigFile;
+use config::{self, CfgEntryStruct, CfgStructState, CfgStructView,
+             ConfigFile};
 use display::{Color,Display};
 use file::File;
+use number::parse_number;
+use output_view::OutputView;
 use regex::Regex;
-use structs::Structs;
+use script::Script;
+use std::time::{Duration, Instant};
+use structs::{CompareOp, DebugStop, FieldRange, Link, Struct, Structs};
 use undo_file::UndoFile;
 
 enum Mode {
@@ -11,12 +16,24 @@ enum Mode {
     Replace,
 }
 
+struct FieldEdit {
+    line: usize,
+    name: String,
+    input: String,
+}
+
 pub struct Buffer {
     file: File,
     undo_file: UndoFile,
     display: Display,
+    config: ConfigFile,
 
     structs: Structs,
+    // Command scripts, which provide the commands not built in
+    scripts: Vec<Script>,
+    // Structs shown in the struct area, from top to bottom; the active struct
+    // (the one commands and the keyboard act on) is always one of them
+    struct_panes: Vec<usize>,
     active_struct: Option<usize>,
 
     base_offset: u64,
@@ -42,23 +59,46 @@ pub struct Buffer {
     // TODO: Proper commands with their own local data?
     jump_stack: Vec<u64>,
 
+    // Struct field currently being edited in the struct pane
+    field_edit: Option<FieldEdit>,
+
+    // Whether keyboard input scrolls the struct pane instead of moving the
+    // cursor
+    struct_focus: bool,
+
+    // Field ranges of the active struct to color in the hex view (when the
+    // overlay is enabled)
+    struct_overlay: Option<Vec<FieldRange>>,
+    // Set when the overlay has changed, so the hex view must be redrawn
+    struct_overlay_changed: bool,
+
+    // Lengthy command output shown instead of the hex view
+    output_view: Option<OutputView>,
+
     mouse_input_regex_1006: Regex,
     mouse_input_regex_1015: Regex,
 }
 
 const SCROLL_OFFSET: u64 = 0x100;
+// Lines scrolled in the struct pane per mouse wheel step
+const STRUCT_SCROLL_LINES: usize = 3;
 
 impl Buffer {
     pub fn new(display: Display, file: File, undo_file: UndoFile,
-               config: &mut ConfigFile)
+               config: ConfigFile)
         -> Result<Self, String>
     {
+        let structs = Structs::load(&config);
+
         let mut buf = Buffer {
             file: file,
             undo_file: undo_file,
             display: display,
+            config,
 
-            structs: Structs::load(config)?,
+            structs,
+            scripts: Vec::new(),
+            struct_panes: Vec::new(),
             active_struct: None,
 
             base_offset: 0,
@@ -80,12 +120,43 @@ impl Buffer {
 
             jump_stack: vec![],
 
+            field_edit: None,
+
+            struct_focus: false,
+
+            struct_overlay: None,
+            struct_overlay_changed: false,
+
+            output_view: None,
+
             mouse_input_regex_1006:
                 Regex::new(r"^\[<([0-9]+);([0-9]+);([0-9]+)([mM])$").unwrap(),
             mouse_input_regex_1015:
                 Regex::new(r"^\[([0-9]+);([0-9]+);([0-9]+)M$").unwrap(),
         };
 
+        buf.restore_struct_view();
+        if buf.struct_panes.is_empty() {
+            let matches = buf.structs.detect(&mut buf.file);
+            if let Some(m) = matches.first() {
+                buf.struct_panes.push(m.index);
+                buf.active_struct = Some(m.index);
+            }
+        }
+
+        let failed = buf.structs.load_errors().len();
+        if failed > 0 {
+            buf.status_info = Some((format!("Failed to load {} struct{} \
+                                             (see :structs)",
+                                            failed,
+                                            if failed == 1 { "" } else { "s" }),
+                                    Color::ErrorInfo));
+        }
+
+        if let Err(e) = buf.load_scripts() {
+            buf.status_info = Some((e, Color::ErrorInfo));
+        }
+
         if let Err(e) = buf.term_update() {
             buf.restore_display();
             return Err(e);
@@ -94,6 +165,84 @@ impl Buffer {
         Ok(buf)
     }
 
+    // Loads the command scripts from the configuration, skipping broken ones
+    fn load_scripts(&mut self) -> Result<(), String> {
+        let limits = self.structs.get_limits().clone();
+        let mut error = None;
+
+        for path in self.config.get_scripts().clone() {
+            let mut full_path = config::base_dir()?;
+            full_path.push(&path);
+
+            let res = match std::fs::read(&full_path) {
+                Ok(source)  => Script::compile(&source),
+                Err(e)      => Err(format!("{}", e))
+            }.and_then(|mut script| {
+                script.init(&self.file, self.loc, &limits)?;
+                Ok(script)
+            });
+
+            match res {
+                Ok(script)  => self.scripts.push(script),
+                Err(e)      => error = Some(format!("Script {}: {}", path, e))
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None    => Ok(())
+        }
+    }
+
+    // Restores the struct panes (and their state) saved for the file
+    fn restore_struct_view(&mut self) {
+        let view = match self.config.get_struct_view(self.file.get_filename()) {
+            Some(v) => v.clone(),
+            None    => return
+        };
+
+        for name in view.panes.iter() {
+            let si = match self.find_struct(name) {
+                Ok(i)   => i,
+                Err(_)  => continue
+            };
+            if self.struct_panes.contains(&si) {
+                continue;
+            }
+
+            if let Some(state) = view.structs.get(name) {
+                self.structs.get_mut(si).restore_view(&state.folded,
+                                                      state.scroll);
+            }
+            self.struct_panes.push(si);
+        }
+
+        self.active_struct = view.active_struct.as_ref()
+                                 .and_then(|n| self.find_struct(n).ok())
+                                 .filter(|si| self.struct_panes.contains(si))
+                                 .or(self.struct_panes.first().cloned());
+    }
+
+    // Saves the struct panes for the file, so they are restored next time
+    pub fn save_struct_view(&mut self) -> Result<(), String> {
+        let mut view = CfgStructView::default();
+
+        for &si in self.struct_panes.iter() {
+            let s = self.structs.get(si);
+            view.panes.push(String::from(s.get_name()));
+            view.structs.insert(String::from(s.get_name()), CfgStructState {
+                folded: s.get_folded(),
+                scroll: s.get_scroll(),
+            });
+        }
+        view.active_struct = self.active_struct.map(|si| {
+            String::from(self.structs.get(si).get_name())
+        });
+
+        let filename = String::from(self.file.get_filename());
+        self.config.set_struct_view(&filename, view)
+    }
+
     pub fn restore_display(&mut self) {
         let restore_res = self.display.restore();
         self.display.clear();
@@ -120,6 +269,11 @@ impl Buffer {
     }
 
     pub fn update(&mut self) -> Result<(), String> {
+        if self.output_view.is_some() {
+            self.display.clear();
+            return self.update_status(); // Draws the view
+        }
+
         let end_offset = self.end_offset()?;
 
         let mut current_offset = self.base_offset;
@@ -139,26 +293,213 @@ impl Buffer {
     }
 
     fn update_struct(&mut self) -> Result<(), String> {
+        let limits = self.structs.get_limits().clone();
+
+        for &si in self.struct_panes.iter() {
+            match self.structs.reload_if_modified(si) {
+                Ok(true) if self.structs.get(si).is_debugging() => {
+                    self.status_info =
+                        Some((format!("struct {}: Reloaded, debugging \
+                                       restarted",
+                                      self.structs.get(si).get_name()),
+                              Color::StatusLoc));
+                },
+                Ok(_)   => (),
+                Err(e)  => {
+                    self.status_info = Some((format!("struct {}: Reloading \
+                                                      failed: {}",
+                                                     self.structs.get(si)
+                                                                 .get_name(),
+                                                     e),
+                                             Color::ErrorInfo));
+                }
+            }
+
+            let s = self.structs.get_mut(si);
+            let res = if s.is_debugging() {
+                s.debug_redraw(&mut self.file, &limits)
+            } else {
+                s.update(&mut self.file, self.loc, &limits)
+            };
+            if let Err(e) = res {
+                // TODO: Don't just overwrite this
+                self.status_info = Some((format!("struct {}: {}", s.get_name(),
+                                                 e),
+                                         Color::ErrorInfo));
+            }
+        }
+
+        if self.struct_overlay.is_some() {
+            let ranges = match self.active_struct {
+                Some(si) => self.structs.get(si).field_ranges(),
+                None     => Vec::new()
+            };
+            if self.struct_overlay.as_ref() != Some(&ranges) {
+                self.struct_overlay = Some(ranges);
+                self.struct_overlay_changed = true;
+            }
+        }
+
+        self.draw_struct()
+    }
+
+    /*
+     * Returns the first row and the height of each struct pane's output, in
+     * the order of self.struct_panes.  With more than one pane, every pane
+     * has a title line above that.
+     */
+    fn struct_pane_layout(&mut self) -> Vec<(usize, usize)> {
+        let debugging = match self.active_struct {
+            Some(si) => self.structs.get(si).is_debugging(),
+            None     => false
+        };
+        let rows = if debugging {
+            Struct::debug_output_height(&mut self.display)
+        } else {
+            (self.display.h() as usize).saturating_sub(2)
+        };
+
+        let count = self.struct_panes.len();
+        if count <= 1 {
+            return vec![(0, rows); count];
+        }
+
+        // The last pane gets whatever is left over
+        let pane_rows = rows / count;
+        (0..count).map(|i| {
+            let rows = if i == count - 1 { rows - i * pane_rows }
+                       else { pane_rows };
+            (i * pane_rows + 1, rows.saturating_sub(1))
+        }).collect()
+    }
+
+    // Position of the given struct's pane (see struct_pane_layout())
+    fn struct_pane(&mut self, si: usize) -> (usize, usize) {
+        let layout = self.struct_pane_layout();
+        match self.struct_panes.iter().position(|&s| s == si) {
+            Some(i) => layout[i],
+            None    => (0, 0)
+        }
+    }
+
+    // Draws the struct panes without re-executing the structs
+    fn draw_struct(&mut self) -> Result<(), String> {
         // FIXME: Hard-coding is bad
         let start_x = 92;
 
+        let layout = self.struct_pane_layout();
+        let width = self.display.w() as usize;
+
+        for (i, &si) in self.struct_panes.clone().iter().enumerate() {
+            let (y, height) = layout[i];
+
+            if self.struct_panes.len() > 1 {
+                let title = format!("── {} {}", self.structs.get(si).get_name(),
+                                    "─".repeat(width));
+                let title: String =
+                    title.chars().take(width.saturating_sub(start_x)).collect();
+
+                self.display.set_cursor_pos(start_x, y - 1);
+                self.display.clear_line();
+                if self.active_struct == Some(si) {
+                    self.display.color_on(Color::Highlight);
+                    self.display.write(title);
+                    self.display.color_off(Color::Highlight);
+                } else {
+                    self.display.write(title);
+                }
+            }
+
+            self.structs.get_mut(si).draw(&mut self.display, start_x, y,
+                                          height);
+        }
+
         let a_s_i = match self.active_struct {
             Some(i) => i,
             None    => return Ok(())
         };
-        let a_s = self.structs.get_mut(a_s_i);
+        let (y, height) = self.struct_pane(a_s_i);
+        let a_s = self.structs.get(a_s_i);
 
-        if let Err(e) = a_s.update(&mut self.file, self.loc,
-                                   &mut self.display, start_x)
-        {
-            // TODO: Don't just overwrite this
-            self.status_info = Some((format!("struct: {}", e),
-                                     Color::ErrorInfo));
+        if a_s.is_debugging() {
+            a_s.debug_draw_panel(&mut self.display, start_x);
+        }
+
+        if let Some(ref edit) = self.field_edit {
+            if let Some(row) = a_s.screen_row(edit.line, height) {
+                self.display.set_cursor_pos(start_x, y + row);
+                self.display.clear_line();
+                self.display.color_on(Color::Highlight);
+                self.display.write(format!("{}: {}", edit.name, edit.input));
+                self.display.color_off(Color::Highlight);
+            }
         }
 
         Ok(())
     }
 
+    // Scrolls a struct pane by @delta lines
+    fn scroll_struct(&mut self, si: usize, delta: isize)
+        -> Result<(), String>
+    {
+        self.structs.get_mut(si).scroll_by(delta);
+        self.draw_struct()?;
+        self.reset_term_cursor_pos()?;
+        self.display.flush();
+        Ok(())
+    }
+
+    /*
+     * Returns the struct whose pane contains screen row @y, and the row in its
+     * output (None for the pane's title line).
+     */
+    fn struct_pane_at(&mut self, y: usize) -> Option<(usize, Option<usize>)> {
+        let layout = self.struct_pane_layout();
+
+        for (i, &(pane_y, height)) in layout.iter().enumerate() {
+            if y >= pane_y && y < pane_y + height {
+                return Some((self.struct_panes[i], Some(y - pane_y)));
+            } else if layout.len() > 1 && y + 1 == pane_y {
+                return Some((self.struct_panes[i], None));
+            }
+        }
+        None
+    }
+
+    // Makes the struct in another pane the active one
+    fn focus_struct_pane(&mut self, si: usize) {
+        if let Some(old_si) = self.active_struct {
+            if old_si != si {
+                self.structs.get_mut(old_si).debug_stop();
+            }
+        }
+        self.active_struct = Some(si);
+    }
+
+    /*
+     * Makes @si the active struct.  If it is not shown yet, it replaces the
+     * active struct's pane (or gets a new pane if there is none).
+     */
+    fn set_active_struct(&mut self, si: usize) {
+        if let Some(old_si) = self.active_struct {
+            if old_si != si {
+                self.structs.get_mut(old_si).debug_stop();
+            }
+        }
+
+        if !self.struct_panes.contains(&si) {
+            let pane = self.active_struct.and_then(|a| {
+                self.struct_panes.iter().position(|&s| s == a)
+            });
+            match pane {
+                Some(i) => self.struct_panes[i] = si,
+                None    => self.struct_panes.push(si)
+            }
+        }
+
+        self.active_struct = Some(si);
+    }
+
     fn update_status(&mut self) -> Result<(), String> {
         let height = self.display.h();
         let y = if height >= 2 { height - 2 } else { 0 };
@@ -176,6 +517,8 @@ impl Buffer {
             self.display.write(format!(":{:<88}", cmd_line));
         } else {
             let (mode_str, mode_col) = match self.mode {
+                _ if self.output_view.is_some() => ("VIEW", Color::StatusLoc),
+                _ if self.struct_focus => ("STRUCT", Color::StatusLoc),
                 Mode::Read      => ("READ-ONLY", Color::StatusModeRead),
                 Mode::Modify    => ("MODIFY", Color::StatusModeModify),
                 Mode::Replace   => ("REPLACE", Color::StatusModeReplace),
@@ -187,7 +530,26 @@ impl Buffer {
             self.display.write_static(mode_str);
             self.display.color_off(mode_col);
 
-            self.display.write(format!("{:55}", ""));
+            // Name of the field under the cursor (with the overlay enabled)
+            let field = match (self.active_struct, &self.struct_overlay) {
+                (Some(si), &Some(_)) => match self.structs.get(si)
+                                                  .field_at(self.loc)
+                {
+                    Some(f) => {
+                        let name = match f.bits {
+                            Some((a, b)) if a == b =>
+                                format!("{} (bit {})", f.name, a),
+                            Some((a, b)) =>
+                                format!("{} (bits {}–{})", f.name, a, b),
+                            None => f.name
+                        };
+                        name.chars().take(51).collect()
+                    },
+                    None    => String::new()
+                },
+                _ => String::new()
+            };
+            self.display.write(format!("  {:53}", field));
 
             let loc_str = format!("{:#x}", self.loc);
             self.display.write(format!("{:width$}", "",
@@ -208,6 +570,22 @@ impl Buffer {
         Ok(())
     }
 
+    // Switches an overlaid field's color on or off (@alt for every other
+    // field, so adjacent ones can be told apart)
+    fn overlay_color(&mut self, color: Color, alt: bool, on: bool) {
+        if on {
+            self.display.color_on(color);
+            if alt {
+                self.display.color_on(Color::StructFieldAlt);
+            }
+        } else {
+            if alt {
+                self.display.color_off(Color::StructFieldAlt);
+            }
+            self.display.color_off(color);
+        }
+    }
+
     /* NOTE: This method does not flush the output, and it assumes self.buffer
      *       to be up-to-date */
     fn redraw_line(&mut self, base: u64) -> Result<(), String> {
@@ -228,6 +606,47 @@ impl Buffer {
 
         let active_line = (self.loc & !0xf) == base;
 
+        // While debugging a struct, highlight what it has just read
+        let debug_access = match self.active_struct {
+            Some(i) => self.structs.get(i).debug_access(),
+            None    => None
+        };
+        let highlight = match (self.highlight_end, debug_access) {
+            (Some(x), _)            => Some((self.loc, x)),
+            (None, Some((o, l)))    => Some((o, o + l)),
+            (None, None)            => None
+        };
+
+        // Overlay colors (header level color, alternation) of the fields
+        // in this line
+        let overlay: Vec<Option<(usize, Color, bool)>> =
+            match self.struct_overlay {
+                Some(ref ranges) => {
+                    let candidates: Vec<(usize, &FieldRange)> =
+                        ranges.iter().enumerate()
+                              .filter(|&(_, r)| r.start < base + 16 &&
+                                                r.start + r.length > base)
+                              .collect();
+
+                    (0..16u64).map(|i| {
+                        let ofs = base + i;
+                        candidates.iter()
+                                  .find(|&&(_, r)| ofs >= r.start &&
+                                                   ofs < r.start + r.length)
+                                  .map(|&(idx, r)| {
+                                      let color = match r.level {
+                                          0 => Color::StructH0,
+                                          1 => Color::StructH1,
+                                          2 => Color::StructH2,
+                                          _ => Color::StructH3P,
+                                      };
+                                      (idx, color, idx % 2 == 1)
+                                  })
+                    }).collect()
+                },
+                None => vec![None; 16]
+            };
+
         if active_line {
             self.display.color_on(Color::ActiveLine);
         }
@@ -242,22 +661,34 @@ impl Buffer {
         for i in 0..16 {
             let file_offset = base + (i as u64);
             let (in_highlight, first_highlight, last_highlight) =
-                if let Some(x) = self.highlight_end {
-                    (file_offset >= self.loc && file_offset < x,
-                     file_offset == self.loc,
-                     i == 15 || file_offset == x - 1)
+                if let Some((start, end)) = highlight {
+                    (file_offset >= start && file_offset < end,
+                     file_offset == start,
+                     i == 15 || file_offset == end - 1)
                 } else {
                     (false, false, false)
                 };
 
+            // Color the gap between two bytes of the same field, too
+            let field_cont = i > 0 && match (overlay[i - 1], overlay[i]) {
+                (Some((a, _, _)), Some((b, _, _))) => a == b,
+                _ => false
+            };
+
             if in_highlight && !first_highlight {
                 self.display.color_on(Color::Highlight);
             }
+            if let (true, Some((_, color, alt))) = (field_cont, overlay[i]) {
+                self.overlay_color(color, alt, true);
+            }
             if i == 4 || i == 12 {
                 self.display.write_static(" ");
             } else if i == 8 {
                 self.display.write_static("  ");
             }
+            if let (true, Some((_, color, alt))) = (field_cont, overlay[i]) {
+                self.overlay_color(color, alt, false);
+            }
             if in_highlight && !first_highlight {
                 self.display.color_off(Color::Highlight);
             }
@@ -265,6 +696,9 @@ impl Buffer {
             if file_offset < end_offset {
                 let val = self.buffer[buffer_base + i];
 
+                if let Some((_, color, alt)) = overlay[i] {
+                    self.overlay_color(color, alt, true);
+                }
                 if in_highlight {
                     self.display.color_on(Color::Highlight);
                 }
@@ -276,6 +710,9 @@ impl Buffer {
                 if in_highlight {
                     self.display.color_off(Color::Highlight);
                 }
+                if let Some((_, color, alt)) = overlay[i] {
+                    self.overlay_color(color, alt, false);
+                }
                 if in_highlight && last_highlight {
                     self.display.write_static(" ");
                 }
@@ -290,8 +727,8 @@ impl Buffer {
         for i in 0..16 {
             let file_offset = base + (i as u64);
             let in_highlight =
-                if let Some(x) = self.highlight_end {
-                    file_offset >= self.loc && file_offset < x
+                if let Some((start, end)) = highlight {
+                    file_offset >= start && file_offset < end
                 } else {
                     false
                 };
@@ -302,6 +739,9 @@ impl Buffer {
                 None
             };
 
+            if let Some((_, color, alt)) = overlay[i] {
+                self.overlay_color(color, alt, true);
+            }
             if in_highlight {
                 self.display.color_on(Color::Highlight);
             }
@@ -321,7 +761,7 @@ impl Buffer {
                     self.display.write_static("¶");
                 } else if c == 0x20 {
                     self.display.write_static("␣");
-                } else if c < 0x20 || c > 0x7e {
+                } else if !(0x20..=0x7e).contains(&c) {
                     self.display.write_static("·");
                 } else {
                     self.display.write((c as char).to_string());
@@ -336,6 +776,9 @@ impl Buffer {
             if in_highlight {
                 self.display.color_off(Color::Highlight);
             }
+            if let Some((_, color, alt)) = overlay[i] {
+                self.overlay_color(color, alt, false);
+            }
         }
 
         if active_line {
@@ -383,6 +826,28 @@ impl Buffer {
         if let Some(ref cmd_line) = self.command_line {
             x = cmd_line.len() + 1;
             y = self.display.h() as usize - 1;
+        } else if self.output_view.is_some() {
+            x = 0;
+            y = 0;
+        } else if self.field_edit.is_some() {
+            let (line, text_len) = match self.field_edit {
+                Some(ref edit) => (edit.line, edit.name.chars().count() + 2 +
+                                              edit.input.chars().count()),
+                None           => (0, 0)
+            };
+
+            let row = match self.active_struct {
+                Some(si) => {
+                    let (pane_y, height) = self.struct_pane(si);
+                    self.structs.get(si).screen_row(line, height)
+                                        .map(|r| pane_y + r)
+                },
+                None => None
+            };
+
+            // FIXME: Hard-coding is bad (see draw_struct())
+            x = 92 + text_len;
+            y = row.unwrap_or(0);
         } else {
             x = (Self::byte_to_x((self.loc % 16) as u8) + self.replacing_nibble)
                 as usize + 19;
@@ -393,10 +858,17 @@ impl Buffer {
     }
 
     pub fn update_cursor(&mut self) -> Result<(), String> {
+        if let Some(ref view) = self.output_view {
+            view.draw(&mut self.display);
+            self.reset_term_cursor_pos()?;
+            self.display.flush();
+            return Ok(());
+        }
+
         let loc = self.loc;
         let old_loc = self.old_loc;
 
-        let full_update =
+        let mut full_update =
             if (loc != old_loc || self.command_line.is_some()) &&
                 self.highlight_end.is_some()
             {
@@ -407,6 +879,10 @@ impl Buffer {
             };
 
         self.update_struct()?;
+        if self.struct_overlay_changed {
+            self.struct_overlay_changed = false;
+            full_update = true;
+        }
 
         if loc < self.base_offset {
             if full_update {
@@ -739,10 +1215,17 @@ impl Buffer {
         self.status_info = None;
 
         if let Some(mut cmd_line) = self.command_line.take() {
+            if input == '\t' {
+                self.complete_cmdline(&mut cmd_line);
+                self.command_line = Some(cmd_line);
+                self.update_status()?;
+                return Ok(());
+            }
+
             if (input as u8) < 0x20 && input != '\n' {
                 // TODO (Whenever this manages to sufficiently annoy me)
                 cmd_line.push('^');
-                input = (input as u8 + '@' as u8) as char;
+                input = (input as u8 + b'@') as char;
             }
 
             match input {
@@ -774,19 +1257,63 @@ impl Buffer {
             return Ok(());
         }
 
+        if let Some(mut edit) = self.field_edit.take() {
+            match input {
+                '\n' => {
+                    if let Err(e) = self.commit_field_edit(&edit) {
+                        self.status_info = Some((format!("Error: {}", e),
+                                                 Color::ErrorInfo));
+                    }
+                    self.update()?;
+                    return Ok(());
+                },
+
+                // Backspace
+                '\x7f' => {
+                    edit.input.pop();
+                },
+
+                '\x1b' => {
+                    // Cancel (and drop whatever escape sequence this was)
+                    while self.display.readchar_nonblock()?.is_some() {
+                    }
+                    self.update_status()?;
+                    return Ok(());
+                },
+
+                _ => {
+                    if input >= ' ' {
+                        edit.input.push(input);
+                    }
+                }
+            }
+
+            self.field_edit = Some(edit);
+            self.update_status()?;
+            return Ok(());
+        }
+
+        if self.output_view.is_some() {
+            if let Err(e) = self.handle_output_view_input(input) {
+                self.status_info = Some((format!("Error: {}", e),
+                                         Color::ErrorInfo));
+                self.update_status()?;
+            }
+            return Ok(());
+        }
+
+        if self.struct_focus {
+            if let Err(e) = self.handle_struct_focus_input(input) {
+                self.status_info = Some((format!("Error: {}", e),
+                                         Color::ErrorInfo));
+                self.update_status()?;
+            }
+            return Ok(());
+        }
+
         if let Mode::Replace = self.mode {
-            let input_asc = input as u8;
-            if (input_asc >= '0' as u8 && input_asc <= '9' as u8) ||
-               (input_asc >= 'a' as u8 && input_asc <= 'f' as u8) ||
-               (input_asc >= 'A' as u8 && input_asc <= 'F' as u8)
-            {
-                let val = if input_asc >= '0' as u8 && input_asc <= '9' as u8 {
-                    input_asc - '0' as u8
-                } else if input_asc >= 'a' as u8 && input_asc <= 'f' as u8 {
-                    input_asc - 'a' as u8 + 10
-                } else {
-                    input_asc - 'A' as u8 + 10
-                };
+            if let Some(val) = input.to_digit(16) {
+                let val = val as u8;
 
                 let buf_offset = (self.loc - self.base_offset) as usize;
                 let shift = 4 - self.replacing_nibble * 4;
@@ -842,10 +1369,26 @@ impl Buffer {
                 Ok(())
             },
 
+            'c' => {
+                self.do_struct_debug_step(true)
+            },
+
+            'e' => {
+                self.cmd_edit_field(vec![String::from("e")])
+            },
+
+            '\n' => {
+                self.cmd_follow_link(vec![String::from("\n")])
+            },
+
             'M' => {
                 self.cmd_modify_mode(vec![String::from("M")])
             },
 
+            'n' => {
+                self.do_struct_debug_step(false)
+            },
+
             'q' => {
                 self.cmd_quit(vec![String::from("q")])
             },
@@ -862,25 +1405,12 @@ impl Buffer {
                 self.cmd_undo(vec![String::from("u")])
             },
 
-            '\x1b' => {
-                let mut escape_sequence = String::with_capacity(256);
-
-                // FIXME: This is a very arbitrary max length.
-                //        Also, we need proper terminfo support.
-                while escape_sequence.len() < 256 {
-                    let input = match self.display.readchar_nonblock()? {
-                        Some(c) => c,
-                        None    => break
-                    };
-
-                    if input == '\x1b' {
-                        self.display.unreadchar(input);
-                        break;
-                    }
-
-                    escape_sequence.push(input);
-                }
+            '\t' => {
+                self.cmd_struct_focus(vec![String::from("\t")])
+            },
 
+            '\x1b' => {
+                let escape_sequence = self.read_escape_sequence()?;
                 self.handle_escape_sequence(escape_sequence)
             },
 
@@ -894,59 +1424,332 @@ impl Buffer {
         Ok(())
     }
 
-    fn perform_replacement(&mut self, old: u8, new: u8) -> Result<(), String> {
-        let buf_offset = (self.loc - self.base_offset) as usize;
+    fn read_escape_sequence(&mut self) -> Result<String, String> {
+        let mut escape_sequence = String::with_capacity(256);
 
-        if let Err(e) = self.undo_file.enter(self.loc, old, new) {
-            self.buffer[buf_offset] = old;
-            return Err(format!("Undo log error: {}", e));
-        }
+        // FIXME: This is a very arbitrary max length.
+        //        Also, we need proper terminfo support.
+        while escape_sequence.len() < 256 {
+            let input = match self.display.readchar_nonblock()? {
+                Some(c) => c,
+                None    => break
+            };
 
-        if let Err(e) = self.file.write_u8(self.loc, new) {
-            self.buffer[buf_offset] = old;
-            return Err(format!("Write error: {}", e));
-        }
+            if input == '\x1b' {
+                self.display.unreadchar(input);
+                break;
+            }
 
-        if let Err(e) = self.undo_file.settle() {
-            return Err(format!("Undo log error: {}", e));
+            escape_sequence.push(input);
         }
 
-        Ok(())
+        Ok(escape_sequence)
     }
 
-    fn handle_mouse(&mut self, seq: &String) -> Result<bool, String> {
-        let match_type;
-        let mut button;
-        let mut x;
-        let mut y;
+    // Keyboard input while the struct pane has the focus
+    fn handle_struct_focus_input(&mut self, input: char)
+        -> Result<(), String>
+    {
+        let si = match self.active_struct {
+            Some(si) => si,
+            None     => return Ok(())
+        };
+        let page = self.struct_pane(si).1 as isize;
+
+        let delta = match input {
+            'j' => 1,
+            'k' => -1,
+
+            'w' => {
+                // Move on to the next pane
+                let i = self.struct_panes.iter().position(|&s| s == si)
+                                         .unwrap_or(0);
+                let next = self.struct_panes[(i + 1) %
+                                             self.struct_panes.len()];
+                self.focus_struct_pane(next);
+                return self.update_status();
+            },
 
-        {
-            let regex;
-            if self.mouse_input_regex_1006.is_match(seq.as_str()) {
-                regex = &self.mouse_input_regex_1006;
-                match_type = 1006;
-            } else if self.mouse_input_regex_1015.is_match(seq.as_str()) {
-                regex = &self.mouse_input_regex_1015;
-                match_type = 1015;
-            } else {
-                return Ok(false);
-            }
+            '\t' | 'q' => {
+                self.struct_focus = false;
+                return self.update_status();
+            },
 
-            let m = regex.captures(seq.as_str()).unwrap();
+            '\x1b' => {
+                let seq = self.read_escape_sequence()?;
+                if self.handle_mouse(&seq)? {
+                    return Ok(());
+                }
 
-            button = match m[1].parse::<u32>() {
-                Ok(v)   => v,
-                Err(e)  => return Err(format!("Mouse input: {}: {}: {}",
-                                              seq, &m[1], e))
-            };
+                match seq.as_str() {
+                    "[A"    => -1,
+                    "[B"    => 1,
+                    "[5~"   => -page,
+                    "[6~"   => page,
+                    "[H"    => isize::MIN,
+                    "[F"    => isize::MAX,
+                    ""      => {
+                        self.struct_focus = false;
+                        return self.update_status();
+                    },
+                    _       => 0
+                }
+            },
 
-            x = match m[2].parse::<u32>() {
-                Ok(v)   => v,
-                Err(e)  => return Err(format!("Mouse input: {}: {}: {}",
-                                              seq, &m[2], e))
-            };
+            _ => 0
+        };
 
-            y = match m[3].parse::<u32>() {
+        self.scroll_struct(si, delta)
+    }
+
+    // Keyboard input while an output view is shown
+    fn handle_output_view_input(&mut self, input: char)
+        -> Result<(), String>
+    {
+        let page = match self.output_view {
+            Some(ref view) => view.height(&mut self.display),
+            None           => 0
+        } as isize;
+
+        let delta = match input {
+            'j' => 1,
+            'k' => -1,
+
+            ':' => {
+                self.command_line = Some(String::new());
+                return self.update_status();
+            },
+
+            'q' => {
+                self.output_view = None;
+                return self.update();
+            },
+
+            '\x1b' => {
+                let seq = self.read_escape_sequence()?;
+                if self.handle_mouse(&seq)? {
+                    return Ok(());
+                }
+
+                match seq.as_str() {
+                    "[A"    => -1,
+                    "[B"    => 1,
+                    "[5~"   => -page,
+                    "[6~"   => page,
+                    "[H"    => isize::MIN,
+                    "[F"    => isize::MAX,
+                    ""      => {
+                        self.output_view = None;
+                        return self.update();
+                    },
+                    _       => 0
+                }
+            },
+
+            _ => 0
+        };
+
+        self.scroll_output_view(delta)
+    }
+
+    fn scroll_output_view(&mut self, delta: isize) -> Result<(), String> {
+        if let Some(ref mut view) = self.output_view {
+            view.scroll_by(&mut self.display, delta);
+        }
+        self.update_cursor()
+    }
+
+    // Completes struct names for the commands taking one
+    fn complete_cmdline(&mut self, cmd_line: &mut String) {
+        let (cmd, prefix) = match cmd_line.find(' ') {
+            Some(i) => (String::from(&cmd_line[..i]),
+                        String::from(cmd_line[i + 1..].trim_start())),
+            None    => return
+        };
+
+        let takes_name = ["struct", "struct-debug", "struct-reload",
+                          "struct-rm", "struct-split"].contains(&cmd.as_str());
+        if !takes_name || prefix.contains(' ') {
+            return;
+        }
+
+        let mut names: Vec<String> =
+            (0..self.structs.len()).map(|i| self.structs.get(i).get_name())
+                                   .filter(|n| n.starts_with(&prefix))
+                                   .map(String::from)
+                                   .collect();
+        if cmd == "struct" && "auto".starts_with(&prefix) {
+            names.push(String::from("auto"));
+        }
+
+        let mut common = match names.first() {
+            Some(n) => n.clone(),
+            None    => return
+        };
+        for n in names.iter() {
+            while !n.starts_with(&common) {
+                common.pop();
+            }
+        }
+
+        if common.len() > prefix.len() {
+            cmd_line.push_str(&common[prefix.len()..]);
+        } else if names.len() > 1 {
+            names.sort();
+            self.status_info = Some((names.join("  "), Color::StatusLoc));
+        }
+    }
+
+    fn cmd_struct_overlay(&mut self, args: Vec<String>) -> Result<(), String> {
+        let enable = match args.get(1).map(|a| a.as_str()) {
+            None        => self.struct_overlay.is_none(),
+            Some("on")  => true,
+            Some("off") => false,
+            Some(a)     => return Err(format!("Invalid argument “{}” \
+                                               (expected on or off)", a))
+        };
+
+        if args.len() > 2 {
+            return Err(String::from("Usage: struct-overlay [on|off]"));
+        }
+
+        self.struct_overlay = if enable { Some(Vec::new()) } else { None };
+        // Collects the field ranges
+        self.update_struct()?;
+        self.update()
+    }
+
+    fn cmd_struct_focus(&mut self, _: Vec<String>) -> Result<(), String> {
+        if self.active_struct.is_none() {
+            return Err(String::from("No active struct"));
+        }
+
+        self.struct_focus = true;
+        self.status_info = Some((String::from("Struct pane: ↑/↓/PgUp/PgDn \
+                                               scroll, w next pane, Tab \
+                                               returns"),
+                                 Color::StatusLoc));
+        self.update_status()
+    }
+
+    fn perform_replacement(&mut self, old: u8, new: u8) -> Result<(), String> {
+        let buf_offset = (self.loc - self.base_offset) as usize;
+
+        if let Err(e) = self.undo_file.enter(self.loc, old, new) {
+            self.buffer[buf_offset] = old;
+            return Err(format!("Undo log error: {}", e));
+        }
+
+        if let Err(e) = self.file.write_u8(self.loc, new) {
+            self.buffer[buf_offset] = old;
+            return Err(format!("Write error: {}", e));
+        }
+        self.structs.file_modified(self.loc, 1);
+
+        if let Err(e) = self.undo_file.settle() {
+            return Err(format!("Undo log error: {}", e));
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Writes bytes through the undo log (enter, modify, settle; see
+     * undo_file.rs), so that they are undone together.
+     */
+    fn write_bytes(&mut self, address: u64, new: &[u8])
+        -> Result<(), String>
+    {
+        let mut joined = false;
+        for (i, &byte) in new.iter().enumerate() {
+            if self.write_byte(address + i as u64, byte, joined)? {
+                joined = true;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false if the byte already had the given value
+    fn write_byte(&mut self, address: u64, new: u8, joined: bool)
+        -> Result<bool, String>
+    {
+        let old = self.file.read_u8(address)?;
+        if old == new {
+            return Ok(false);
+        }
+
+        let entered = if joined {
+            self.undo_file.enter_joined(address, old, new)
+        } else {
+            self.undo_file.enter(address, old, new)
+        };
+        if let Err(e) = entered {
+            return Err(format!("Undo log error: {}", e));
+        }
+
+        if let Err(e) = self.file.write_u8(address, new) {
+            return Err(format!("Write error: {}", e));
+        }
+        self.structs.file_modified(address, 1);
+
+        if let Err(e) = self.undo_file.settle() {
+            return Err(format!("Undo log error: {}", e));
+        }
+
+        Ok(true)
+    }
+
+    fn commit_field_edit(&mut self, edit: &FieldEdit) -> Result<(), String> {
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Err(String::from("No active struct"))
+        };
+
+        let (offset, bytes) =
+            self.structs.get(si).encode_field(&mut self.file, edit.line,
+                                              &edit.input)?;
+
+        match offset.checked_add(bytes.len() as u64) {
+            Some(end) if end <= self.file.len()? => (),
+            _ => return Err(String::from("Field exceeds the end of the file"))
+        }
+
+        self.write_bytes(offset, &bytes)
+    }
+
+    fn handle_mouse(&mut self, seq: &String) -> Result<bool, String> {
+        let match_type;
+        let mut button;
+        let mut x;
+        let mut y;
+
+        {
+            let regex;
+            if self.mouse_input_regex_1006.is_match(seq.as_str()) {
+                regex = &self.mouse_input_regex_1006;
+                match_type = 1006;
+            } else if self.mouse_input_regex_1015.is_match(seq.as_str()) {
+                regex = &self.mouse_input_regex_1015;
+                match_type = 1015;
+            } else {
+                return Ok(false);
+            }
+
+            let m = regex.captures(seq.as_str()).unwrap();
+
+            button = match m[1].parse::<u32>() {
+                Ok(v)   => v,
+                Err(e)  => return Err(format!("Mouse input: {}: {}: {}",
+                                              seq, &m[1], e))
+            };
+
+            x = match m[2].parse::<u32>() {
+                Ok(v)   => v,
+                Err(e)  => return Err(format!("Mouse input: {}: {}: {}",
+                                              seq, &m[2], e))
+            };
+
+            y = match m[3].parse::<u32>() {
                 Ok(v)   => v,
                 Err(e)  => return Err(format!("Mouse input: {}: {}: {}",
                                               seq, &m[3], e))
@@ -967,11 +1770,35 @@ impl Buffer {
             }
         }
 
+        if self.output_view.is_some() {
+            match button {
+                64 => self.scroll_output_view(-(STRUCT_SCROLL_LINES as isize))?,
+                65 => self.scroll_output_view(STRUCT_SCROLL_LINES as isize)?,
+                _  => ()
+            }
+            return Ok(true);
+        }
+
+        // FIXME: Hard-coding is bad (see draw_struct())
+        let over_struct = if x > 90 {
+            self.struct_pane_at(y as usize - 1).map(|(si, _)| si)
+        } else {
+            None
+        };
+
         if button == 64 {
-            self.do_scroll_up()?;
+            if let Some(si) = over_struct {
+                self.scroll_struct(si, -(STRUCT_SCROLL_LINES as isize))?;
+            } else {
+                self.do_scroll_up()?;
+            }
             return Ok(true);
         } else if button == 65 {
-            self.do_scroll_down()?;
+            if let Some(si) = over_struct {
+                self.scroll_struct(si, STRUCT_SCROLL_LINES as isize)?;
+            } else {
+                self.do_scroll_down()?;
+            }
             return Ok(true);
         }
 
@@ -993,9 +1820,22 @@ impl Buffer {
         }
 
         if x > 89 {
-            if let Some(si) = self.active_struct {
-                let res = self.structs.get_mut(si).mouse_down(y as usize)?;
-                if let Some((loc, len)) = res.highlight {
+            if let Some((si, row)) = self.struct_pane_at(y as usize) {
+                if self.active_struct != Some(si) {
+                    self.focus_struct_pane(si);
+                    self.update_status()?;
+                }
+
+                // Clicking the title line only focuses the pane
+                let row = match row {
+                    Some(r) => r,
+                    None    => return Ok(true)
+                };
+
+                let res = self.structs.get_mut(si).mouse_down(row)?;
+                if let Some(link) = res.link {
+                    self.follow_link(link)?;
+                } else if let Some((loc, len)) = res.highlight {
                     self.loc = loc;
                     self.cursor_to_bounds(true)?;
                     // Invoke update_cursor() before setting highlight_end, so
@@ -1096,8 +1936,20 @@ impl Buffer {
             "g" | "goto" => self.cmd_goto(args),
             "q" | "quit" => self.cmd_quit(args),
             "struct" => self.cmd_struct(args),
-
-            _ => Err(format!("Unknown command “{}”", args[0]))
+            "struct-add" => self.cmd_struct_add(args),
+            "struct-break" => self.cmd_struct_break(args),
+            "struct-close" => self.cmd_struct_close(args),
+            "struct-debug" => self.cmd_struct_debug(args),
+            "struct-export" => self.cmd_struct_export(args),
+            "struct-find" => self.cmd_struct_find(args),
+            "struct-overlay" => self.cmd_struct_overlay(args),
+            "struct-reload" => self.cmd_struct_reload(args),
+            "struct-rm" => self.cmd_struct_rm(args),
+            "struct-split" => self.cmd_struct_split(args),
+            "structs" => self.cmd_structs(args),
+            "validate" => self.cmd_validate(args),
+
+            _ => self.cmd_script(args)
         }
     }
 
@@ -1124,26 +1976,14 @@ impl Buffer {
             return Err(format!("Usage: {} <address|start|end>", args[0]));
         }
 
-        // Rust is so nice to read
         let position =
-            match if args[1] == "end" {
-                    Ok(0xffffffffffffffffu64)
-                } else if args[1] == "start" || args[1] == "begin" {
-                    Ok(0u64)
-                } else if args[1].starts_with("0x") {
-                    u64::from_str_radix(&args[1][2..], 16)
-                } else if args[1].starts_with("0b") {
-                    // nice gimmmick
-                    u64::from_str_radix(&args[1][2..], 2)
-                } else if args[1].starts_with("0") {
-                    u64::from_str_radix(args[1].as_str(), 8)
-                } else {
-                    args[1].parse::<u64>()
-                }
-        {
-            Ok(v)   => v,
-            Err(e)  => return Err(format!("{}: {}", args[1], e))
-        };
+            if args[1] == "end" {
+                0xffffffffffffffffu64
+            } else if args[1] == "start" || args[1] == "begin" {
+                0u64
+            } else {
+                parse_number(&args[1])?
+            };
 
         self.do_goto(position)
     }
@@ -1185,18 +2025,22 @@ impl Buffer {
             return Err(String::from("Cannot redo in read-only mode"));
         }
 
-        let (address, val) = match self.undo_file.redo()? {
+        let changes = match self.undo_file.redo()? {
             Some(x) => x,
             None    => return Err(String::from("Nothing to redo"))
         };
 
-        if let Err(e) = self.file.write_u8(address, val) {
-            return Err(format!("Write error: {}", e));
+        for &(address, val) in changes.iter() {
+            if let Err(e) = self.file.write_u8(address, val) {
+                return Err(format!("Write error: {}", e));
+            }
+            self.structs.file_modified(address, 1);
         }
 
         self.undo_file.settle()?;
 
-        self.do_goto(address)?; // Performs a screen update
+        // Performs a screen update
+        self.do_goto(changes.iter().map(|c| c.0).min().unwrap())?;
 
         Ok(())
     }
@@ -1215,24 +2059,566 @@ impl Buffer {
     }
 
     fn cmd_struct(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 2 {
+            return Err(format!("Usage: {} <struct name|auto>", args[0]));
+        }
+
+        if args[1] == "auto" {
+            return self.cmd_struct_auto();
+        }
+
+        let a_s = self.find_struct(&args[1])?;
+        self.set_active_struct(a_s);
+        self.update()?;
+
+        Ok(())
+    }
+
+    // Shows a struct in a new pane below the others
+    fn cmd_struct_split(&mut self, args: Vec<String>) -> Result<(), String> {
         if args.len() != 2 {
             return Err(format!("Usage: {} <struct name>", args[0]));
         }
 
-        let mut a_s = 0;
-        while a_s < self.structs.len() {
-            if self.structs.get(a_s).get_name() == args[1] {
-                break;
+        let a_s = self.find_struct(&args[1])?;
+        if !self.struct_panes.contains(&a_s) {
+            self.struct_panes.push(a_s);
+        }
+        self.focus_struct_pane(a_s);
+        self.update()
+    }
+
+    // Closes the active struct's pane
+    fn cmd_struct_close(&mut self, _: Vec<String>) -> Result<(), String> {
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Err(String::from("No active struct"))
+        };
+
+        self.structs.get_mut(si).debug_stop();
+
+        let i = self.struct_panes.iter().position(|&s| s == si).unwrap_or(0);
+        self.struct_panes.retain(|&s| s != si);
+        self.active_struct =
+            self.struct_panes.get(i.saturating_sub(1)).cloned();
+        if self.active_struct.is_none() {
+            self.struct_focus = false;
+        }
+        self.update()
+    }
+
+    // Writes the active struct's full output at the cursor to a JSON file
+    fn cmd_struct_export(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 2 {
+            return Err(format!("Usage: {} <path>", args[0]));
+        }
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Err(String::from("No active struct"))
+        };
+
+        let limits = self.structs.get_limits().clone();
+        let json = self.structs.get_mut(si).export(&mut self.file, self.loc,
+                                                   &limits)?;
+        if let Err(e) = std::fs::write(&args[1], json) {
+            return Err(format!("{}: {}", args[1], e));
+        }
+
+        self.status_info = Some((format!("Exported to {}", args[1]),
+                                 Color::StatusLoc));
+        self.update_status()
+    }
+
+    /*
+     * Jumps to the next offset after the cursor where the active struct has
+     * a field matching the given comparison.  Candidates are the cursor plus
+     * multiples of the stride, which defaults to the struct's size there.
+     */
+    fn cmd_struct_find(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 4 && args.len() != 5 {
+            return Err(format!("Usage: {} <field> <op> <value> [stride]",
+                               args[0]));
+        }
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Err(String::from("No active struct"))
+        };
+        let op = match CompareOp::parse(&args[2]) {
+            Some(op)    => op,
+            None        => return Err(format!("Unknown operator “{}” \
+                                               (use ==, !=, <, <=, >, >= or &)",
+                                              args[2]))
+        };
+        if let CompareOp::And = op {
+            parse_number(&args[3])?;
+        }
+
+        let limits = self.structs.get_limits().clone();
+        let stride = match args.get(4) {
+            Some(s) => parse_number(s)?,
+            None    => self.structs.get_mut(si).extent(&mut self.file,
+                                                       self.loc, &limits)?
+        };
+        if stride == 0 {
+            return Err(String::from("Struct has no size here, give a stride"));
+        }
+
+        let lof = self.file.len()?;
+        let deadline = Instant::now() +
+                       Duration::from_millis(limits.max_search_time_ms);
+        let mut loc = self.loc;
+        // The first error, as long as the struct has failed at every offset
+        let mut error = None;
+        let mut succeeded = false;
+        loop {
+            loc = match loc.checked_add(stride) {
+                Some(l) if l < lof  => l,
+                _ => {
+                    if let (Some(e), false) = (error, succeeded) {
+                        return Err(format!("Struct failed at every offset \
+                                            searched ({})", e));
+                    }
+                    return Err(format!("No match for {} {} {}", args[1],
+                                       args[2], args[3]));
+                }
+            };
+
+            // Resume from here when run again
+            if Instant::now() > deadline {
+                let searched = loc - stride;
+                self.do_goto(searched)?;
+                return Err(format!("Stopped at {:#x} (search time limit), \
+                                    run again to continue", searched));
+            }
+
+            // Offsets the struct fails on just do not match
+            let found = self.structs.get_mut(si)
+                            .find_match(&mut self.file, loc, &limits,
+                                        &args[1], op, &args[3]);
+            match found {
+                Ok(Some((name, value))) => {
+                    self.do_goto(loc)?;
+                    self.status_info = Some((format!("{} = {} at {:#x}",
+                                                     name, value, loc),
+                                             Color::StatusLoc));
+                    return self.update_status();
+                },
+                Ok(None)    => succeeded = true,
+                Err(e)      => if error.is_none() {
+                    error = Some(format!("{:#x}: {}", loc, e));
+                }
+            }
+        }
+    }
+
+    // Activates the best struct for the file and lists all candidates
+    fn cmd_struct_auto(&mut self) -> Result<(), String> {
+        let matches = self.structs.detect(&mut self.file);
+        let best = match matches.first() {
+            Some(m) => m.index,
+            None    => return Err(String::from("No matching struct"))
+        };
+
+        self.set_active_struct(best);
+        self.update()?;
+
+        let candidates: Vec<String> = matches.iter().map(|m| {
+            format!("{} ({})", self.structs.get(m.index).get_name(),
+                    m.reasons.join(", "))
+        }).collect();
+        self.status_info = Some((format!("Candidates: {}",
+                                         candidates.join("; ")),
+                                 Color::StatusLoc));
+        Ok(())
+    }
+
+    fn cmd_struct_add(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 3 {
+            return Err(format!("Usage: {} <name> <path (relative to \
+                                ~/.butterfly)>", args[0]));
+        }
+
+        let entry = CfgEntryStruct {
+            path: args[2].clone(),
+            detect: None,
+        };
+        self.config.add_struct(&args[1], entry.clone())?;
+
+        if let Err(e) = self.structs.add(&args[1], &entry) {
+            return Err(format!("Added {}, but loading failed: {}", args[1],
+                               e));
+        }
+
+        self.status_info = Some((format!("Added {}", args[1]),
+                                 Color::StatusLoc));
+        Ok(())
+    }
+
+    fn cmd_struct_rm(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 2 {
+            return Err(format!("Usage: {} <struct name>", args[0]));
+        }
+
+        self.config.remove_struct(&args[1])?;
+
+        if let Ok(i) = self.find_struct(&args[1]) {
+            if self.active_struct == Some(i) {
+                self.active_struct = None;
             }
+            self.struct_panes.retain(|&s| s != i);
 
-            a_s += 1;
+            for si in self.struct_panes.iter_mut()
+                                       .chain(self.active_struct.iter_mut())
+            {
+                if *si > i {
+                    *si -= 1;
+                }
+            }
+
+            if self.active_struct.is_none() {
+                self.active_struct = self.struct_panes.first().cloned();
+                self.struct_focus = false;
+            }
+            self.structs.remove(i);
+        } else {
+            self.structs.remove_failed(&args[1]);
+        }
+
+        self.update()
+    }
+
+    // Loads structs again (all of them, or just the given one)
+    fn cmd_struct_reload(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() > 2 {
+            return Err(format!("Usage: {} [struct name]", args[0]));
+        }
+
+        let name = args.get(1);
+        let loaded = match name {
+            Some(n) => self.find_struct(n).ok().into_iter().collect(),
+            None    => (0..self.structs.len()).collect::<Vec<usize>>()
+        };
+
+        let mut errors = Vec::new();
+        for &i in loaded.iter() {
+            if let Err(e) = self.structs.reload(i) {
+                errors.push(format!("{}: {}", self.structs.get(i).get_name(),
+                                    e));
+            }
+        }
+
+        if loaded.is_empty() || name.is_none() {
+            self.structs.retry_failed();
+            for e in self.structs.load_errors().iter() {
+                if name.is_none() || name == Some(&e.name) {
+                    errors.push(format!("{}: {}", e.name, e.error));
+                }
+            }
         }
-        if a_s == self.structs.len() {
-            return Err(format!("Unknown struct “{}”", args[1]));
+
+        if let Some(n) = name {
+            if self.find_struct(n).is_err() && errors.is_empty() {
+                return Err(format!("Unknown struct “{}”", n));
+            }
         }
 
-        self.active_struct = Some(a_s);
         self.update()?;
+        if !errors.is_empty() {
+            return Err(errors.join("; "));
+        }
+
+        self.status_info = Some((String::from("Reloaded"), Color::StatusLoc));
+        Ok(())
+    }
+
+    // Lists all structs from the configuration
+    fn cmd_structs(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 1 {
+            return Err(format!("Usage: {}", args[0]));
+        }
+
+        let mut entries = Vec::<(String, Vec<String>)>::new();
+
+        for i in 0..self.structs.len() {
+            let s = self.structs.get(i);
+            let mut lines = Vec::new();
+
+            if self.active_struct == Some(i) {
+                lines.push(format!("{} (active)", s.get_name()));
+            } else {
+                lines.push(String::from(s.get_name()));
+            }
+            if let Some(desc) = s.get_description() {
+                lines.push(format!("  {}", desc));
+            }
+            lines.push(format!("  Path:   {}", s.get_path()));
+            if s.get_path().ends_with(".json") ||
+               s.get_path().ends_with(".toml")
+            {
+                lines.push(format!("  Size:   {} bytes ({} bytes of byte code)",
+                                   s.get_size(), s.get_code_size()));
+            } else {
+                lines.push(format!("  Size:   {} bytes", s.get_size()));
+            }
+            match s.get_reload_error() {
+                Some(e) => lines.push(format!("  Status: loaded, but reloading \
+                                               failed: {}", e)),
+                None    => lines.push(String::from("  Status: loaded"))
+            }
+
+            entries.push((String::from(s.get_name()), lines));
+        }
+
+        for e in self.structs.load_errors().iter() {
+            entries.push((e.name.clone(),
+                          vec![e.name.clone(),
+                               format!("  Path:   {}", e.path),
+                               format!("  Status: failed: {}", e.error)]));
+        }
+
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut lines = Vec::new();
+        for (_, mut e) in entries {
+            lines.append(&mut e);
+            lines.push(String::new());
+        }
+
+        let title = format!("{} structs (q closes)", self.structs.len() +
+                                                     self.structs.load_errors()
+                                                                 .len());
+        self.output_view = Some(OutputView::new(title, lines));
+        self.update()
+    }
+
+    // Lists the checks of the active struct at the cursor that failed
+    fn cmd_validate(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 1 {
+            return Err(format!("Usage: {}", args[0]));
+        }
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Err(String::from("No active struct"))
+        };
+
+        let limits = self.structs.get_limits().clone();
+        let (checks, failures) =
+            self.structs.get_mut(si).validate(&mut self.file, self.loc,
+                                              &limits);
+
+        if failures.is_empty() {
+            self.status_info = Some((format!("All {} checks passed", checks),
+                                     Color::StatusLoc));
+            return self.update_status();
+        }
+
+        let title = format!("{}: {} problems at {:#x} (q closes)",
+                            self.structs.get(si).get_name(), failures.len(),
+                            self.loc);
+        self.output_view = Some(OutputView::new(title, failures));
+        self.update()
+    }
+
+    // Runs a command registered by a script
+    fn cmd_script(&mut self, args: Vec<String>) -> Result<(), String> {
+        let si = match self.scripts.iter()
+                           .position(|s| s.commands().contains(&args[0]))
+        {
+            Some(i) => i,
+            None    => return Err(format!("Unknown command “{}”", args[0]))
+        };
+
+        let limits = self.structs.get_limits().clone();
+        let output = self.scripts[si].run_command(&args[0], &args[1..],
+                                                  &self.file, self.loc,
+                                                  &limits)?;
+
+        if let Some(loc) = output.goto {
+            self.do_goto(loc)?;
+        }
+        if !output.lines.is_empty() {
+            let title = format!("{} (q closes)", args.join(" "));
+            self.output_view = Some(OutputView::new(title, output.lines));
+        }
+        if let Some(status) = output.status {
+            self.status_info = Some((status, Color::StatusLoc));
+        }
+
+        self.update()
+    }
+
+    fn find_struct(&self, name: &str) -> Result<usize, String> {
+        self.structs.lookup(name)
+    }
+
+    fn cmd_struct_debug(&mut self, args: Vec<String>) -> Result<(), String> {
+        if args.len() > 2 {
+            return Err(format!("Usage: {} [struct name|off]", args[0]));
+        }
+
+        // Stop any previous session first
+        if let Some(si) = self.active_struct {
+            if let Some(loc) = self.structs.get_mut(si).debug_stop() {
+                self.loc = loc;
+                self.cursor_to_bounds(true)?;
+            }
+        }
+
+        if args.len() == 2 && args[1] == "off" {
+            self.update()?;
+            return Ok(());
+        }
+
+        let a_s = if args.len() == 2 {
+            self.find_struct(&args[1])?
+        } else {
+            match self.active_struct {
+                Some(i) => i,
+                None    => return Err(String::from("No active struct"))
+            }
+        };
+
+        if self.structs.get(a_s).is_script() {
+            return Err(String::from("Only byte code can be debugged, not \
+                                     scripts"));
+        }
+
+        self.set_active_struct(a_s);
+        self.structs.get_mut(a_s).debug_start(self.loc);
+        self.update()?;
+
+        self.status_info = Some((String::from("Debugging: n = step, \
+                                               c = continue"),
+                                 Color::StatusLoc));
+        Ok(())
+    }
+
+    fn do_struct_debug_step(&mut self, run: bool) -> Result<(), String> {
+        let si = match self.active_struct {
+            Some(i) if self.structs.get(i).is_debugging() => i,
+            _ => return Err(String::from("Not debugging a struct \
+                                          (see :struct-debug)"))
+        };
+
+        let limits = self.structs.get_limits().clone();
+        let res = self.structs.get_mut(si).debug_step(&mut self.file, &limits,
+                                                      run);
+
+        // Follow the loads in the hex view
+        if let Some((ofs, _)) = self.structs.get(si).debug_access() {
+            if ofs < self.file.len()? {
+                self.loc = ofs;
+                self.cursor_to_bounds(true)?;
+            }
+        }
+        self.update()?;
+
+        match res {
+            Ok(DebugStop::Stepped)      => (),
+            Ok(DebugStop::Breakpoint)   => {
+                self.status_info = Some((String::from("Breakpoint"),
+                                         Color::StatusLoc));
+            },
+            Ok(DebugStop::Finished)     => {
+                self.status_info = Some((String::from("Execution finished"),
+                                         Color::StatusLoc));
+            },
+            Err(e) => {
+                self.status_info = Some((format!("struct {}: {}",
+                                                 self.structs.get(si)
+                                                     .get_name(),
+                                                 e),
+                                         Color::ErrorInfo));
+            }
+        }
+        self.update_status()?;
+
+        Ok(())
+    }
+
+    fn cmd_struct_break(&mut self, args: Vec<String>) -> Result<(), String> {
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Err(String::from("No active struct"))
+        };
+
+        if args.len() == 1 {
+            let loc = self.loc;
+            self.structs.get_mut(si).toggle_file_breakpoint(loc);
+        } else if args.len() == 2 && args[1] == "clear" {
+            self.structs.get_mut(si).clear_breakpoints();
+        } else if args.len() == 3 && args[1] == "pc" {
+            let pc = parse_number(&args[2])?;
+            self.structs.get_mut(si).toggle_pc_breakpoint(pc as usize);
+        } else if args.len() == 3 && args[1] == "file" {
+            let offset = parse_number(&args[2])?;
+            self.structs.get_mut(si).toggle_file_breakpoint(offset);
+        } else {
+            return Err(format!("Usage: {} [pc <address>|file <offset>|clear]",
+                               args[0]));
+        }
+
+        self.update_struct()?;
+        self.reset_term_cursor_pos()?;
+        self.display.flush();
+        Ok(())
+    }
+
+    fn follow_link(&mut self, link: Link) -> Result<(), String> {
+        if link.target >= self.file.len()? {
+            return Err(format!("Link target {:#x} is beyond the end of the \
+                                file", link.target));
+        }
+
+        if let Some(ref name) = link.target_struct {
+            let si = self.find_struct(name)?;
+            self.set_active_struct(si);
+        }
+
+        self.do_goto(link.target) // Pushes to the jump stack
+    }
+
+    fn cmd_follow_link(&mut self, _: Vec<String>) -> Result<(), String> {
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Ok(())
+        };
+
+        let link = match self.structs.get(si).field_at(self.loc) {
+            Some(f) => f.link,
+            None    => None
+        };
+
+        match link {
+            Some(l) => self.follow_link(l),
+            None    => Err(String::from("No link here"))
+        }
+    }
+
+    fn cmd_edit_field(&mut self, _: Vec<String>) -> Result<(), String> {
+        if let Mode::Read = self.mode {
+            return Err(String::from("Cannot edit in read-only mode"));
+        }
+
+        let si = match self.active_struct {
+            Some(i) => i,
+            None    => return Err(String::from("No active struct"))
+        };
+
+        let field = match self.structs.get(si).field_at(self.loc) {
+            Some(f) => f,
+            None    => return Err(String::from("No struct field here"))
+        };
+
+        let height = self.struct_pane(si).1;
+        self.structs.get_mut(si).scroll_to(field.line, height);
+
+        self.field_edit = Some(FieldEdit {
+            line: field.line,
+            name: field.name,
+            input: field.value,
+        });
+        self.update_status()?;
 
         Ok(())
     }
@@ -1242,18 +2628,22 @@ impl Buffer {
             return Err(String::from("Cannot undo in read-only mode"));
         }
 
-        let (address, val) = match self.undo_file.undo()? {
+        let changes = match self.undo_file.undo()? {
             Some(x) => x,
             None    => return Err(String::from("Nothing to undo"))
         };
 
-        if let Err(e) = self.file.write_u8(address, val) {
-            return Err(format!("Write error: {}", e));
+        for &(address, val) in changes.iter() {
+            if let Err(e) = self.file.write_u8(address, val) {
+                return Err(format!("Write error: {}", e));
+            }
+            self.structs.file_modified(address, 1);
         }
 
         self.undo_file.settle()?;
 
-        self.do_goto(address)?; // Performs a screen update
+        // Performs a screen update
+        self.do_goto(changes.iter().map(|c| c.0).min().unwrap())?;
 
         Ok(())
     }
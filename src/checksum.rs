@@ -0,0 +1,309 @@
+// Checksums over file ranges, for the struct VM's checksum instructions
+pub enum Hasher {
+    Crc32 { table: Vec<u32>, crc: u32 },
+    Adler32 { a: u32, b: u32 },
+    Sha1 { state: [u32; 5], block: Vec<u8>, length: u64 },
+    Sha256 { state: [u32; 8], block: Vec<u8>, length: u64 },
+}
+
+// Largest chunk read from the file at once while computing a checksum
+pub const CHUNK_SIZE: usize = 65536;
+
+// Reflected polynomials
+const CRC32_POLY: u32 = 0xedb88320;
+const CRC32C_POLY: u32 = 0x82f63b78;
+
+const ADLER32_MOD: u32 = 65521;
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5,
+    0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc,
+    0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+    0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3,
+    0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5,
+    0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+
+impl Hasher {
+    // Algorithms are numbered as the checksum instructions' subfunctions
+    pub fn new(algorithm: u8) -> Option<Self> {
+        Some(match algorithm {
+            0x00 => Self::crc32(CRC32_POLY),
+            0x01 => Self::crc32(CRC32C_POLY),
+            0x02 => Hasher::Adler32 { a: 1, b: 0 },
+            0x03 => Hasher::Sha1 {
+                state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476,
+                        0xc3d2e1f0],
+                block: Vec::new(),
+                length: 0,
+            },
+            0x04 => Hasher::Sha256 {
+                state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                        0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+                block: Vec::new(),
+                length: 0,
+            },
+            _ => return None
+        })
+    }
+
+    fn crc32(poly: u32) -> Self {
+        let table = (0..256u32).map(|i| {
+            (0..8).fold(i, |c, _| if c & 1 != 0 { (c >> 1) ^ poly }
+                                  else { c >> 1 })
+        }).collect();
+
+        Hasher::Crc32 { table, crc: 0xffffffff }
+    }
+
+    // Length of the result in bytes
+    pub fn size(&self) -> usize {
+        match *self {
+            Hasher::Crc32 { .. } | Hasher::Adler32 { .. } => 4,
+            Hasher::Sha1 { .. } => 20,
+            Hasher::Sha256 { .. } => 32,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match *self {
+            Hasher::Crc32 { ref table, ref mut crc } => {
+                for &b in data {
+                    *crc = table[((*crc ^ b as u32) & 0xff) as usize] ^
+                           (*crc >> 8);
+                }
+            },
+
+            Hasher::Adler32 { ref mut a, ref mut b } => {
+                // Reduce only every few KB (this cannot overflow in between)
+                for chunk in data.chunks(4096) {
+                    for &x in chunk {
+                        *a += x as u32;
+                        *b += *a;
+                    }
+                    *a %= ADLER32_MOD;
+                    *b %= ADLER32_MOD;
+                }
+            },
+
+            Hasher::Sha1 { ref mut state, ref mut block, ref mut length } => {
+                *length = length.wrapping_add(data.len() as u64);
+                for &x in data {
+                    block.push(x);
+                    if block.len() == 64 {
+                        sha1_block(state, block);
+                        block.clear();
+                    }
+                }
+            },
+
+            Hasher::Sha256 { ref mut state, ref mut block, ref mut length } => {
+                *length = length.wrapping_add(data.len() as u64);
+                for &x in data {
+                    block.push(x);
+                    if block.len() == 64 {
+                        sha256_block(state, block);
+                        block.clear();
+                    }
+                }
+            },
+        }
+    }
+
+    // Returns the result as big-endian bytes
+    pub fn finish(mut self) -> Vec<u8> {
+        let (block_len, length) = match self {
+            Hasher::Crc32 { crc, .. } => return to_be_bytes(&[!crc]),
+            Hasher::Adler32 { a, b } => return to_be_bytes(&[(b << 16) | a]),
+
+            Hasher::Sha1 { ref block, length, .. } |
+            Hasher::Sha256 { ref block, length, .. } => (block.len(), length)
+        };
+
+        let mut padding = vec![0x80u8];
+        while (block_len + padding.len()) % 64 != 56 {
+            padding.push(0);
+        }
+        let bits = length.wrapping_mul(8);
+        padding.extend((0..8).rev().map(|i| (bits >> (i * 8)) as u8));
+        self.update(&padding);
+
+        match self {
+            Hasher::Sha1 { state, .. } => to_be_bytes(&state),
+            Hasher::Sha256 { state, .. } => to_be_bytes(&state),
+            _ => Vec::new()
+        }
+    }
+}
+
+
+fn to_be_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter()
+         .flat_map(|w| (0..4).rev().map(move |i| (w >> (i * 8)) as u8))
+         .collect()
+}
+
+fn sha1_block(state: &mut [u32; 5], block: &[u8]) {
+    let mut w = [0u32; 80];
+    for (x, bytes) in w.iter_mut().zip(block.chunks(4)) {
+        *x = bytes.iter().fold(0, |w, &b| (w << 8) | b as u32);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let (mut a, mut b, mut c, mut d, mut e) =
+        (state[0], state[1], state[2], state[3], state[4]);
+
+    for (i, &wi) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19  => ((b & c) | (!b & d), 0x5a827999),
+            20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _       => (b ^ c ^ d, 0xca62c1d6),
+        };
+
+        let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e)
+                 .wrapping_add(k).wrapping_add(wi);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = t;
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e].iter()) {
+        *s = s.wrapping_add(*v);
+    }
+}
+
+fn sha256_block(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (x, bytes) in w.iter_mut().zip(block.chunks(4)) {
+        *x = bytes.iter().fold(0, |w, &b| (w << 8) | b as u32);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^
+                 (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^
+                 (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7])
+                        .wrapping_add(s1);
+    }
+
+    let mut v = *state;
+
+    for i in 0..64 {
+        let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^
+                 v[4].rotate_right(25);
+        let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+        let t1 = v[7].wrapping_add(s1).wrapping_add(ch)
+                     .wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+        let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^
+                 v[0].rotate_right(22);
+        let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+        let t2 = s0.wrapping_add(maj);
+
+        v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1),
+             v[4], v[5], v[6]];
+    }
+
+    for (s, x) in state.iter_mut().zip(v.iter()) {
+        *s = s.wrapping_add(*x);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sum(algorithm: u8, data: &[u8]) -> String {
+        let mut h = Hasher::new(algorithm).unwrap();
+        h.update(data);
+        h.finish().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(sum(0x00, b""), "00000000");
+        assert_eq!(sum(0x00, b"123456789"), "cbf43926");
+        assert_eq!(sum(0x00, &[0xff; 100000]), "68c6cec4");
+    }
+
+    #[test]
+    fn crc32c() {
+        assert_eq!(sum(0x01, b""), "00000000");
+        assert_eq!(sum(0x01, b"123456789"), "e3069283");
+    }
+
+    #[test]
+    fn adler32() {
+        assert_eq!(sum(0x02, b""), "00000001");
+        assert_eq!(sum(0x02, b"Wikipedia"), "11e60398");
+        // Long enough for the deferred modulo to matter
+        assert_eq!(sum(0x02, &[0xff; 100000]), "149a302c");
+    }
+
+    #[test]
+    fn sha1() {
+        assert_eq!(sum(0x03, b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sum(0x03, b"abc"),
+                   "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sum(0x03, b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmn\
+                               lmnomnopnopq"),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(sum(0x03, &[b'a'; 1000]),
+                   "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+    }
+
+    #[test]
+    fn sha256() {
+        assert_eq!(sum(0x04, b""),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b\
+                    7852b855");
+        assert_eq!(sum(0x04, b"abc"),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61\
+                    f20015ad");
+        assert_eq!(sum(0x04, b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmn\
+                               lmnomnopnopq"),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd4\
+                    19db06c1");
+        assert_eq!(sum(0x04, &[b'a'; 1000]),
+                   "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1\
+                    b9737ea3");
+    }
+
+    #[test]
+    fn split_updates() {
+        for algorithm in 0x00..0x05 {
+            let data = [0x5au8; 200];
+            let mut h = Hasher::new(algorithm).unwrap();
+            for chunk in data.chunks(7) {
+                h.update(chunk);
+            }
+            let split: String =
+                h.finish().iter().map(|b| format!("{:02x}", b)).collect();
+            assert_eq!(split, sum(algorithm, &data));
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        let sizes: Vec<usize> =
+            (0x00..0x05).map(|a| Hasher::new(a).unwrap().size()).collect();
+        assert_eq!(sizes, vec![4, 4, 4, 20, 32]);
+        assert!(Hasher::new(0x05).is_none());
+    }
+}
@@ -8,16 +8,73 @@ use std::io::Seek;
 struct Config {
     files: HashMap<String, CfgEntryFile>,
     structs: HashMap<String, CfgEntryStruct>,
+    #[serde(default)]
+    struct_limits: CfgStructLimits,
+    // Command scripts run on startup (relative to the base directory)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scripts: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct CfgEntryFile {
     undo_file_name: String,
+    #[serde(default)]
+    struct_view: CfgStructView,
 }
 
-#[derive(Serialize, Deserialize)]
+// How the structs were shown for a file when it was last closed
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CfgStructView {
+    // Names of the structs shown, from top to bottom
+    pub panes: Vec<String>,
+    pub active_struct: Option<String>,
+    pub structs: HashMap<String, CfgStructState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CfgStructState {
+    // Paths of the folded headers
+    pub folded: Vec<Vec<String>>,
+    pub scroll: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CfgEntryStruct {
     pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detect: Option<CfgStructDetect>,
+}
+
+// Rules for picking a struct automatically when opening a file; all given
+// rules must match
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CfgStructDetect {
+    // Hex string, e.g. "514649fb"
+    pub magic: Option<String>,
+    pub magic_offset: u64,
+    // Glob patterns (with * and ?) matched against the file name, or against
+    // the full path if they contain a slash
+    pub names: Vec<String>,
+    pub min_size: Option<u64>,
+}
+
+// Limits for executing struct byte code, so a broken struct cannot hang the
+// editor
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CfgStructLimits {
+    pub max_instructions: u64,
+    pub max_time_ms: u64,
+    pub max_stack: usize,
+    pub max_wram: usize,
+    pub max_string_length: usize,
+    pub max_output_lines: usize,
+    pub max_checksum_length: u64,
+    // For all runs of one :struct-find together
+    pub max_search_time_ms: u64,
 }
 
 
@@ -34,6 +91,24 @@ impl Config {
         Config {
             files: HashMap::<String, CfgEntryFile>::new(),
             structs: HashMap::<String, CfgEntryStruct>::new(),
+            struct_limits: CfgStructLimits::default(),
+            scripts: Vec::new(),
+        }
+    }
+}
+
+
+impl Default for CfgStructLimits {
+    fn default() -> Self {
+        CfgStructLimits {
+            max_instructions: 10000000,
+            max_time_ms: 1000,
+            max_stack: 4096,
+            max_wram: 65536,
+            max_string_length: 4096,
+            max_output_lines: 100000,
+            max_checksum_length: 16777216,
+            max_search_time_ms: 5000,
         }
     }
 }
@@ -73,6 +148,7 @@ impl CfgEntryFile {
 
         Ok(CfgEntryFile {
             undo_file_name: format!("undo-{}", i),
+            struct_view: CfgStructView::default(),
         })
     }
 }
@@ -161,7 +237,52 @@ impl ConfigFile {
         Ok(base_path.as_path().to_string_lossy().into_owned())
     }
 
+    pub fn get_struct_view(&self, for_filename: &str)
+        -> Option<&CfgStructView>
+    {
+        self.config.files.get(for_filename).map(|f| &f.struct_view)
+    }
+
+    pub fn set_struct_view(&mut self, for_filename: &str, view: CfgStructView)
+        -> Result<(), String>
+    {
+        match self.config.files.get_mut(for_filename) {
+            Some(f) => f.struct_view = view,
+            None    => return Err(format!("No config entry for {}",
+                                          for_filename))
+        }
+
+        self.update()
+    }
+
+    pub fn get_scripts(&self) -> &Vec<String> {
+        &self.config.scripts
+    }
+
     pub fn get_structs(&self) -> &HashMap<String, CfgEntryStruct> {
         &self.config.structs
     }
+
+    pub fn add_struct(&mut self, name: &String, entry: CfgEntryStruct)
+        -> Result<(), String>
+    {
+        if self.config.structs.contains_key(name) {
+            return Err(format!("Struct “{}” already exists", name));
+        }
+
+        self.config.structs.insert(name.clone(), entry);
+        self.update()
+    }
+
+    pub fn remove_struct(&mut self, name: &String) -> Result<(), String> {
+        if self.config.structs.remove(name).is_none() {
+            return Err(format!("Unknown struct “{}”", name));
+        }
+
+        self.update()
+    }
+
+    pub fn get_struct_limits(&self) -> &CfgStructLimits {
+        &self.config.struct_limits
+    }
 }
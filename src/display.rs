@@ -39,6 +39,7 @@ bitmask! {
         StructH2            = (1u64 << 10),
         StructH3P           = (1u64 << 11),
         Highlight           = (1u64 << 12),
+        StructFieldAlt      = (1u64 << 13),
     }
 }
 
@@ -188,6 +189,11 @@ impl Display {
         }
     }
 
+    pub fn w(&mut self) -> u32 {
+        let (width, _) = Self::dim();
+        width
+    }
+
     pub fn h(&mut self) -> u32 {
         let (_, height) = Self::dim();
 
@@ -218,6 +224,7 @@ impl Display {
         // TODO: Make these customizable
 
         let mut bold = false;
+        let mut bright = false;
         let mut underline = false;
         let mut swap = false;
         let mut fg_color = 9;
@@ -259,6 +266,16 @@ impl Display {
         if self.mode.contains(Color::StructH1) {
             fg_color = 4; // blue
         }
+        if self.mode.contains(Color::StructH2) {
+            fg_color = 5; // magenta
+        }
+        if self.mode.contains(Color::StructH3P) {
+            fg_color = 3; // yellow
+        }
+        if self.mode.contains(Color::StructFieldAlt) {
+            // Distinguishes adjacent fields in the struct overlay
+            bright = true;
+        }
         if self.mode.contains(Color::Highlight) {
             bold = true;
             underline = true;
@@ -273,7 +290,8 @@ impl Display {
             sgr_string.push_str(";4");
         }
         if fg_color != 9 {
-            sgr_string.push_str(&format!(";{}", fg_color + 30));
+            sgr_string.push_str(&format!(";{}", fg_color +
+                                                if bright { 90 } else { 30 }));
         }
         if bg_color != 9 {
             sgr_string.push_str(&format!(";{}", bg_color + 40));
@@ -0,0 +1,290 @@
+use std;
+
+
+// Text encodings of strings in files, for the struct VM's string instructions
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Utf32Le,
+    Utf32Be,
+    Latin1,
+    // Code page 037 (US/Canada)
+    Ebcdic,
+}
+
+/*
+ * Builds a string from the code units of a string in any encoding but UTF-8,
+ * combining UTF-16 surrogate pairs.  Invalid code units (including unpaired
+ * surrogates) are replaced by U+FFFD.
+ */
+pub struct Decoder {
+    encoding: Encoding,
+    string: String,
+    pending_surrogate: Option<u32>,
+}
+
+// Code points of the EBCDIC characters (all of which are in Latin-1)
+const EBCDIC: [u8; 256] = [
+    0x00, 0x01, 0x02, 0x03, 0x9c, 0x09, 0x86, 0x7f, 0x97, 0x8d, 0x8e, 0x0b,
+    0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x9d, 0x85, 0x08, 0x87,
+    0x18, 0x19, 0x92, 0x8f, 0x1c, 0x1d, 0x1e, 0x1f, 0x80, 0x81, 0x82, 0x83,
+    0x84, 0x0a, 0x17, 0x1b, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x05, 0x06, 0x07,
+    0x90, 0x91, 0x16, 0x93, 0x94, 0x95, 0x96, 0x04, 0x98, 0x99, 0x9a, 0x9b,
+    0x14, 0x15, 0x9e, 0x1a, 0x20, 0xa0, 0xe2, 0xe4, 0xe0, 0xe1, 0xe3, 0xe5,
+    0xe7, 0xf1, 0xa2, 0x2e, 0x3c, 0x28, 0x2b, 0x7c, 0x26, 0xe9, 0xea, 0xeb,
+    0xe8, 0xed, 0xee, 0xef, 0xec, 0xdf, 0x21, 0x24, 0x2a, 0x29, 0x3b, 0xac,
+    0x2d, 0x2f, 0xc2, 0xc4, 0xc0, 0xc1, 0xc3, 0xc5, 0xc7, 0xd1, 0xa6, 0x2c,
+    0x25, 0x5f, 0x3e, 0x3f, 0xf8, 0xc9, 0xca, 0xcb, 0xc8, 0xcd, 0xce, 0xcf,
+    0xcc, 0x60, 0x3a, 0x23, 0x40, 0x27, 0x3d, 0x22, 0xd8, 0x61, 0x62, 0x63,
+    0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0xab, 0xbb, 0xf0, 0xfd, 0xfe, 0xb1,
+    0xb0, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0xaa, 0xba,
+    0xe6, 0xb8, 0xc6, 0xa4, 0xb5, 0x7e, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78,
+    0x79, 0x7a, 0xa1, 0xbf, 0xd0, 0xdd, 0xde, 0xae, 0x5e, 0xa3, 0xa5, 0xb7,
+    0xa9, 0xa7, 0xb6, 0xbc, 0xbd, 0xbe, 0x5b, 0x5d, 0xaf, 0xa8, 0xb4, 0xd7,
+    0x7b, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0xad, 0xf4,
+    0xf6, 0xf2, 0xf3, 0xf5, 0x7d, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f, 0x50,
+    0x51, 0x52, 0xb9, 0xfb, 0xfc, 0xf9, 0xfa, 0xff, 0x5c, 0xf7, 0x53, 0x54,
+    0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0xb2, 0xd4, 0xd6, 0xd2, 0xd3, 0xd5,
+    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0xb3, 0xdb,
+    0xdc, 0xd9, 0xda, 0x9f,
+];
+
+
+impl Encoding {
+    /*
+     * Encodings are numbered as the string output instruction's pairs of
+     * subfunctions (i.e. the subfunction shifted right by one)
+     */
+    pub fn new(n: u8) -> Option<Self> {
+        Some(match n {
+            0x00 => Encoding::Utf8,
+            0x01 => Encoding::Utf16Le,
+            0x02 => Encoding::Utf16Be,
+            0x03 => Encoding::Utf32Le,
+            0x04 => Encoding::Utf32Be,
+            0x05 => Encoding::Latin1,
+            0x06 => Encoding::Ebcdic,
+            _ => return None
+        })
+    }
+
+    // Size of a code unit in bytes
+    pub fn unit_size(&self) -> usize {
+        match *self {
+            Encoding::Utf16Le | Encoding::Utf16Be => 2,
+            Encoding::Utf32Le | Encoding::Utf32Be => 4,
+            _ => 1
+        }
+    }
+
+    // Assembles a code unit from its bytes
+    pub fn unit(&self, bytes: &[u8]) -> u32 {
+        match *self {
+            Encoding::Utf16Be | Encoding::Utf32Be =>
+                bytes.iter().fold(0, |u, &b| (u << 8) | b as u32),
+            _ => bytes.iter().rev().fold(0, |u, &b| (u << 8) | b as u32)
+        }
+    }
+
+    /*
+     * Returns the character of a single code unit, or None if it is invalid
+     * on its own (which includes UTF-16 surrogates).  UTF-8 is not decoded
+     * unit by unit.
+     */
+    pub fn char_of(&self, unit: u32) -> Option<char> {
+        match *self {
+            Encoding::Utf8 => None,
+            Encoding::Ebcdic => Some(EBCDIC[unit as usize & 0xff] as char),
+            _ => std::char::from_u32(unit)
+        }
+    }
+
+    // Encodes @s, failing for characters that cannot be represented
+    pub fn encode(&self, s: &str) -> Result<Vec<u8>, String> {
+        let units: Vec<u32> = match *self {
+            Encoding::Utf8 => return Ok(s.as_bytes().to_vec()),
+
+            Encoding::Utf16Le | Encoding::Utf16Be =>
+                s.encode_utf16().map(|u| u as u32).collect(),
+
+            Encoding::Utf32Le | Encoding::Utf32Be =>
+                s.chars().map(|c| c as u32).collect(),
+
+            Encoding::Latin1 | Encoding::Ebcdic => {
+                let mut units = Vec::new();
+                for c in s.chars() {
+                    let unit = if *self == Encoding::Latin1 {
+                        if (c as u32) < 0x100 { Some(c as u32) } else { None }
+                    } else {
+                        EBCDIC.iter().position(|&x| x as u32 == c as u32)
+                              .map(|p| p as u32)
+                    };
+                    match unit {
+                        Some(u) => units.push(u),
+                        None    => return Err(format!("“{}” cannot be \
+                                                       encoded", c))
+                    }
+                }
+                units
+            }
+        };
+
+        let size = self.unit_size();
+        let big_endian = *self == Encoding::Utf16Be ||
+                         *self == Encoding::Utf32Be;
+        let mut bytes = Vec::new();
+        for u in units {
+            for i in 0..size {
+                let shift = if big_endian { size - 1 - i } else { i } * 8;
+                bytes.push((u >> shift) as u8);
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Decoder {
+            encoding,
+            string: String::new(),
+            pending_surrogate: None,
+        }
+    }
+
+    pub fn push(&mut self, unit: u32) {
+        let high = self.pending_surrogate.take();
+
+        if self.encoding.unit_size() == 2 && (0xd800..0xe000).contains(&unit) {
+            match high {
+                Some(h) if unit >= 0xdc00 => {
+                    let c = 0x10000 + ((h - 0xd800) << 10) + (unit - 0xdc00);
+                    self.string.push(std::char::from_u32(c).unwrap());
+                },
+                _ => {
+                    if high.is_some() {
+                        self.push_invalid();
+                    }
+                    if unit < 0xdc00 {
+                        self.pending_surrogate = Some(unit);
+                    } else {
+                        self.push_invalid();
+                    }
+                }
+            }
+            return;
+        }
+
+        if high.is_some() {
+            self.push_invalid();
+        }
+        let c = self.encoding.char_of(unit)
+                    .unwrap_or(std::char::REPLACEMENT_CHARACTER);
+        self.string.push(c);
+    }
+
+    // For a partial code unit
+    pub fn push_invalid(&mut self) {
+        if self.pending_surrogate.take().is_some() {
+            self.string.push(std::char::REPLACEMENT_CHARACTER);
+        }
+        self.string.push(std::char::REPLACEMENT_CHARACTER);
+    }
+
+    pub fn finish(mut self) -> String {
+        if self.pending_surrogate.is_some() {
+            self.string.push(std::char::REPLACEMENT_CHARACTER);
+        }
+        self.string
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoder, Encoding};
+
+    fn decode(encoding: Encoding, bytes: &[u8]) -> String {
+        let mut decoder = Decoder::new(encoding);
+        for unit in bytes.chunks(encoding.unit_size()) {
+            decoder.push(encoding.unit(unit));
+        }
+        decoder.finish()
+    }
+
+    #[test]
+    fn utf16_surrogate_pairs() {
+        // U+1F600, U+10000 and U+10FFFF
+        let le = [0x3d, 0xd8, 0x00, 0xde, 0x00, 0xd8, 0x00, 0xdc,
+                  0xff, 0xdb, 0xff, 0xdf];
+        let be = [0xd8, 0x3d, 0xde, 0x00];
+        assert_eq!(decode(Encoding::Utf16Le, &le),
+                   "\u{1f600}\u{10000}\u{10ffff}");
+        assert_eq!(decode(Encoding::Utf16Be, &be), "\u{1f600}");
+
+        assert_eq!(Encoding::Utf16Le.encode("\u{1f600}").unwrap(),
+                   vec![0x3d, 0xd8, 0x00, 0xde]);
+        assert_eq!(Encoding::Utf16Be.encode("a\u{10ffff}").unwrap(),
+                   vec![0x00, 0x61, 0xdb, 0xff, 0xdf, 0xff]);
+    }
+
+    #[test]
+    fn utf16_unpaired_surrogates() {
+        // Lone low surrogate
+        assert_eq!(decode(Encoding::Utf16Be, &[0xdc, 0x00, 0x00, 0x61]),
+                   "\u{fffd}a");
+        // High surrogate followed by a non-surrogate
+        assert_eq!(decode(Encoding::Utf16Be, &[0xd8, 0x00, 0x00, 0x61]),
+                   "\u{fffd}a");
+        // Two high surrogates, the second one completed
+        assert_eq!(decode(Encoding::Utf16Be,
+                          &[0xd8, 0x00, 0xd8, 0x3d, 0xde, 0x00]),
+                   "\u{fffd}\u{1f600}");
+        // High surrogate at the end
+        assert_eq!(decode(Encoding::Utf16Le, &[0x61, 0x00, 0x00, 0xd8]),
+                   "a\u{fffd}");
+
+        // Partial code unit after a high surrogate
+        let mut decoder = Decoder::new(Encoding::Utf16Le);
+        decoder.push(0xd800);
+        decoder.push_invalid();
+        assert_eq!(decoder.finish(), "\u{fffd}\u{fffd}");
+
+        // UTF-32 has no surrogates
+        assert_eq!(decode(Encoding::Utf32Le, &[0x00, 0xd8, 0x00, 0x00]),
+                   "\u{fffd}");
+        assert_eq!(decode(Encoding::Utf32Be, &[0x00, 0x01, 0xf6, 0x00]),
+                   "\u{1f600}");
+    }
+
+    #[test]
+    fn ebcdic() {
+        // "Hello, World!" in code page 037
+        let hello = [0xc8, 0x85, 0x93, 0x93, 0x96, 0x6b, 0x40, 0xe6, 0x96,
+                     0x99, 0x93, 0x84, 0x5a];
+        assert_eq!(decode(Encoding::Ebcdic, &hello), "Hello, World!");
+        assert_eq!(decode(Encoding::Ebcdic, &[0xf0, 0xf9, 0x4a, 0x9f, 0xff]),
+                   "09¢¤\u{9f}");
+        assert_eq!(Encoding::Ebcdic.encode("Hello, World!").unwrap(),
+                   hello.to_vec());
+
+        // Every byte maps to a different character
+        let all: Vec<u8> = (0..=255).collect();
+        let string = decode(Encoding::Ebcdic, &all);
+        assert_eq!(string.chars().count(), 256);
+        assert_eq!(Encoding::Ebcdic.encode(&string).unwrap(), all);
+
+        assert!(Encoding::Ebcdic.encode("€").is_err());
+    }
+
+    #[test]
+    fn latin1() {
+        assert_eq!(decode(Encoding::Latin1, &[0x61, 0xe9, 0xff]), "aéÿ");
+        assert_eq!(Encoding::Latin1.encode("aéÿ").unwrap(),
+                   vec![0x61, 0xe9, 0xff]);
+        assert!(Encoding::Latin1.encode("\u{100}").is_err());
+    }
+}
@@ -24,6 +24,10 @@ impl File {
         })
     }
 
+    pub fn get_filename(&self) -> &str {
+        self.filename.as_ref()
+    }
+
     pub fn read(&mut self, position: u64, buffer: &mut Vec<u8>)
         -> Result<(), String>
     {
@@ -100,4 +104,17 @@ impl File {
             Err(e)  => Err(format!("Failed to inquire file length: {}", e))
         }
     }
+
+    // Another handle to the same (read-only) file, for scripts
+    #[cfg(feature = "scripting")]
+    pub fn duplicate(&self) -> Result<Self, String> {
+        match self.file.try_clone() {
+            Ok(f)   => Ok(File {
+                file: f,
+                filename: self.filename.clone(),
+                writable: false,
+            }),
+            Err(e)  => Err(format!("Failed to duplicate file handle: {}", e))
+        }
+    }
 }
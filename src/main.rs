@@ -1,8 +1,11 @@
 #[macro_use] extern crate bitmask;
              extern crate regex;
+#[cfg(feature = "scripting")]
+             extern crate rhai;
              extern crate serde;
 #[macro_use] extern crate serde_derive;
              extern crate serde_json;
+             extern crate toml;
 
 use std::env;
 use std::process::exit;
@@ -10,16 +13,29 @@ use std::process::exit;
 mod buffer;
 use buffer::Buffer;
 
+mod checksum;
+
 mod config;
 use config::ConfigFile;
 
 mod display;
 use display::Display;
 
+mod encoding;
+
 mod file;
 use file::File;
 
+mod number;
+
+mod output_view;
+
+mod script;
+
 mod structs;
+mod struct_def;
+mod struct_export;
+mod struct_test;
 
 mod undo_file;
 use undo_file::UndoFile;
@@ -27,8 +43,28 @@ use undo_file::UndoFile;
 fn main() {
     let argv: Vec<String> = env::args().collect();
 
+    if argv.len() > 1 && argv[1] == "export-struct" {
+        if let Err(e) = struct_export::run(&argv[2..]) {
+            eprintln!("{}", e);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    if argv.len() > 1 && argv[1] == "test-struct" {
+        match struct_test::run(&argv[2..]) {
+            Ok(true)    => exit(0),
+            Ok(false)   => exit(1),
+            Err(e)      => { eprintln!("{}", e); exit(2) }
+        }
+    }
+
     if argv.len() != 2 {
         eprintln!("Usage: {} <file>", argv[0]);
+        eprintln!("       {} test-struct [--update] <struct> <file> <loc> \
+                   <snapshot>", argv[0]);
+        eprintln!("       {} export-struct <struct> <file> <loc> [output]",
+                  argv[0]);
         exit(1);
     }
 
@@ -61,7 +97,7 @@ fn main() {
         Err(e)  => { eprintln!("Failed to open display: {}", e); exit(1) }
     };
 
-    let mut buffer = match Buffer::new(display, file, undo_file, &mut config) {
+    let mut buffer = match Buffer::new(display, file, undo_file, config) {
         Ok(b)   => b,
         Err(e)  => { eprintln!("Failed to initialize buffer: {}", e); exit(1) }
     };
@@ -75,4 +111,8 @@ fn main() {
     }
 
     buffer.restore_display();
+
+    if let Err(e) = buffer.save_struct_view() {
+        eprintln!("Failed to save struct view: {}", e);
+    }
 }
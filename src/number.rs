@@ -0,0 +1,21 @@
+/*
+ * Parses a number given on the command line or in a command, in hex (0x),
+ * binary (0b), octal (leading 0) or decimal
+ */
+pub fn parse_number(string: &str) -> Result<u64, String> {
+    // Rust is so nice to read
+    match if let Some(hex) = string.strip_prefix("0x") {
+              u64::from_str_radix(hex, 16)
+          } else if let Some(bin) = string.strip_prefix("0b") {
+              // nice gimmmick
+              u64::from_str_radix(bin, 2)
+          } else if string.starts_with('0') {
+              u64::from_str_radix(string, 8)
+          } else {
+              string.parse::<u64>()
+          }
+    {
+        Ok(v)   => Ok(v),
+        Err(e)  => Err(format!("{}: {}", string, e))
+    }
+}
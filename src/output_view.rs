@@ -0,0 +1,58 @@
+use display::{Color, Display};
+use std;
+
+
+// Scrollable full-screen text for commands with lengthy output, shown instead
+// of the hex view until closed
+pub struct OutputView {
+    title: String,
+    lines: Vec<String>,
+    scroll: usize,
+}
+
+
+impl OutputView {
+    pub fn new(title: String, lines: Vec<String>) -> Self {
+        OutputView {
+            title,
+            lines,
+            scroll: 0,
+        }
+    }
+
+    // Number of text lines shown (below the title, above the status line)
+    pub fn height(&self, display: &mut Display) -> usize {
+        (display.h() as usize).saturating_sub(3)
+    }
+
+    pub fn scroll_by(&mut self, display: &mut Display, delta: isize) {
+        let height = self.height(display);
+        let max_scroll = self.lines.len().saturating_sub(height);
+
+        let scroll = if delta < 0 {
+            self.scroll.saturating_sub(delta.unsigned_abs())
+        } else {
+            self.scroll.saturating_add(delta as usize)
+        };
+        self.scroll = std::cmp::min(scroll, max_scroll);
+    }
+
+    pub fn draw(&self, display: &mut Display) {
+        let width = display.w() as usize;
+        let height = self.height(display);
+
+        display.set_cursor_pos(0, 0);
+        display.clear_line();
+        display.color_on(Color::StructH0);
+        display.write(self.title.chars().take(width).collect());
+        display.color_off(Color::StructH0);
+
+        for y in 0..height {
+            display.set_cursor_pos(0, y + 1);
+            display.clear_line();
+            if let Some(line) = self.lines.get(self.scroll + y) {
+                display.write(line.chars().take(width).collect());
+            }
+        }
+    }
+}
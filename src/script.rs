@@ -0,0 +1,521 @@
+#[cfg(feature = "scripting")]
+use config::CfgStructLimits;
+#[cfg(feature = "scripting")]
+use file::File;
+#[cfg(feature = "scripting")]
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, FnPtr, Scope, AST};
+#[cfg(feature = "scripting")]
+use rhai::module_resolvers::DummyModuleResolver;
+#[cfg(feature = "scripting")]
+use std;
+#[cfg(feature = "scripting")]
+use std::cell::RefCell;
+#[cfg(feature = "scripting")]
+use std::rc::Rc;
+#[cfg(feature = "scripting")]
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "scripting"))]
+use config::CfgStructLimits;
+#[cfg(not(feature = "scripting"))]
+use file::File;
+
+/*
+ * Structs and commands written in Rhai (see doc/scripting), as an alternative
+ * to struct byte code.
+ *
+ * A struct script is run from the top whenever the struct is evaluated, and
+ * describes its output by calling header(), field_*() and check(); the struct
+ * turns that into output lines just like those of byte code.  Command scripts
+ * are run once when butterfly starts and register commands, i.e. script
+ * functions that are called by “:name args...”.
+ */
+
+
+// An output line of a struct script
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub enum ScriptLine {
+    Header { level: u8, title: String },
+    Int { name: String, loc: u64, length: u64, value: u64, signed: bool,
+          base: usize, big_endian: bool },
+    Str { name: String, loc: u64, length: u64, value: String },
+    // Shown as a hex dump, unless there is a text
+    Bytes { name: String, loc: u64, bytes: Vec<u8>, text: Option<String> },
+    Check { name: String, loc: u64, length: u64, passed: bool },
+}
+
+pub struct StructOutput {
+    pub lines: Vec<ScriptLine>,
+    // Whether the output depends on the location the struct is run at
+    pub uses_loc: bool,
+    // File ranges read
+    pub reads: Vec<(u64, u64)>,
+}
+
+pub struct CommandOutput {
+    pub goto: Option<u64>,
+    pub status: Option<String>,
+    // Printed text, shown in an output view
+    pub lines: Vec<String>,
+}
+
+
+#[cfg(feature = "scripting")]
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Rc<RefCell<State>>,
+}
+
+// Scripts cannot be loaded at all without the scripting feature
+#[cfg(not(feature = "scripting"))]
+pub enum Script {}
+
+// What the functions called by a script act on
+#[cfg(feature = "scripting")]
+#[derive(Default)]
+struct State {
+    file: Option<File>,
+    loc: u64,
+    big_endian: bool,
+    deadline: Option<Instant>,
+    // Bytes per read
+    max_read: u64,
+
+    lines: Vec<ScriptLine>,
+    uses_loc: bool,
+    reads: Vec<(u64, u64)>,
+
+    goto: Option<u64>,
+    status: Option<String>,
+    printed: Vec<String>,
+
+    commands: Vec<(String, FnPtr)>,
+}
+
+#[cfg(feature = "scripting")]
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+
+// Describes an error that stopped a script
+#[cfg(feature = "scripting")]
+fn error_text(e: &EvalAltResult) -> String {
+    match *e {
+        // Stopped by the progress callback, which gives the reason
+        EvalAltResult::ErrorTerminated(ref reason, pos) =>
+            format!("{} ({})", reason, pos),
+        ref e => format!("{}", e),
+    }
+}
+
+
+#[cfg(feature = "scripting")]
+impl State {
+    fn read(&mut self, offset: i64, length: i64) -> ScriptResult<Vec<u8>> {
+        if offset < 0 || length < 0 {
+            return Err(format!("Invalid file range {}+{}", offset,
+                               length).into());
+        }
+
+        let (offset, length) = (offset as u64, length as u64);
+        if length > self.max_read {
+            return Err(format!("Cannot read {} bytes at once (limit: {})",
+                               length, self.max_read).into());
+        }
+
+        let f = match self.file {
+            Some(ref mut f) => f,
+            None => return Err(String::from("No file to read from").into())
+        };
+        let len = f.len()?;
+        if offset > len || length > len - offset {
+            return Err(format!("File range {:#x}+{:#x} is beyond the end of \
+                                the file", offset, length).into());
+        }
+
+        let mut buffer = vec![0u8; length as usize];
+        f.read(offset, &mut buffer)?;
+        self.reads.push((offset, length));
+        Ok(buffer)
+    }
+
+    // Reads an integer of @size bytes (in the current endianness)
+    fn read_int(&mut self, offset: i64, size: i64, signed: bool)
+        -> ScriptResult<u64>
+    {
+        if ![1, 2, 4, 8].contains(&size) {
+            return Err(format!("Invalid integer size {}", size).into());
+        }
+
+        let mut bytes = self.read(offset, size)?;
+        if !self.big_endian {
+            bytes.reverse();
+        }
+        let val = bytes.iter().fold(0u64, |v, &b| (v << 8) | b as u64);
+
+        Ok(if signed && size < 8 {
+            let shift = 64 - size * 8;
+            (((val << shift) as i64) >> shift) as u64
+        } else {
+            val
+        })
+    }
+
+    fn output_int(&mut self, name: &str, offset: i64, size: i64, signed: bool,
+                  base: usize)
+        -> ScriptResult<i64>
+    {
+        let value = self.read_int(offset, size, signed)?;
+        self.lines.push(ScriptLine::Int {
+            name: String::from(name),
+            loc: offset as u64,
+            length: size as u64,
+            value,
+            signed,
+            base,
+            big_endian: self.big_endian,
+        });
+        Ok(value as i64)
+    }
+}
+
+
+#[cfg(feature = "scripting")]
+impl Script {
+    pub fn compile(source: &[u8]) -> Result<Self, String> {
+        let source = match std::str::from_utf8(source) {
+            Ok(s)   => s,
+            Err(e)  => return Err(format!("Script is not UTF-8: {}", e))
+        };
+
+        let state = Rc::new(RefCell::new(State::default()));
+        let mut engine = Engine::new();
+        // Scripts only get to the file through the API, so no imports
+        engine.set_module_resolver(DummyModuleResolver::new());
+        Self::register_api(&mut engine, &state);
+
+        let ast = match engine.compile(source) {
+            Ok(ast) => ast,
+            Err(e)  => return Err(format!("{}", e))
+        };
+
+        Ok(Script {
+            engine,
+            ast,
+            state,
+        })
+    }
+
+    fn register_api(engine: &mut Engine, state: &Rc<RefCell<State>>) {
+        let s = state.clone();
+        engine.on_print(move |text| s.borrow_mut().printed.push(text.into()));
+
+        let s = state.clone();
+        engine.on_progress(move |_| {
+            let deadline = s.try_borrow().ok().and_then(|st| st.deadline);
+            match deadline {
+                Some(d) if Instant::now() > d =>
+                    Some(Dynamic::from("Time limit exceeded")),
+                _ => None
+            }
+        });
+
+        let s = state.clone();
+        engine.register_fn("loc", move || -> i64 {
+            let mut st = s.borrow_mut();
+            st.uses_loc = true;
+            st.loc as i64
+        });
+
+        let s = state.clone();
+        engine.register_fn("file_len", move || -> ScriptResult<i64> {
+            match s.borrow_mut().file {
+                Some(ref mut f) => Ok(f.len()? as i64),
+                None => Err(String::from("No file").into())
+            }
+        });
+
+        let s = state.clone();
+        engine.register_fn("big_endian", move |be: bool| {
+            s.borrow_mut().big_endian = be;
+        });
+
+        for &(name, size, signed) in [("read_u8", 1, false),
+                                      ("read_u16", 2, false),
+                                      ("read_u32", 4, false),
+                                      ("read_u64", 8, false),
+                                      ("read_i8", 1, true),
+                                      ("read_i16", 2, true),
+                                      ("read_i32", 4, true),
+                                      ("read_i64", 8, true)].iter()
+        {
+            let s = state.clone();
+            engine.register_fn(name, move |offset: i64| -> ScriptResult<i64> {
+                Ok(s.borrow_mut().read_int(offset, size, signed)? as i64)
+            });
+        }
+
+        let s = state.clone();
+        engine.register_fn("read_bytes",
+                           move |offset: i64, length: i64|
+                               -> ScriptResult<Blob>
+        {
+            s.borrow_mut().read(offset, length)
+        });
+
+        let s = state.clone();
+        engine.register_fn("read_string",
+                           move |offset: i64, length: i64|
+                               -> ScriptResult<String>
+        {
+            let bytes = s.borrow_mut().read(offset, length)?;
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        });
+
+        // Struct output
+
+        let s = state.clone();
+        engine.register_fn("header", move |level: i64, title: &str|
+                                         -> ScriptResult<()>
+        {
+            if !(0..=255).contains(&level) {
+                return Err(format!("Invalid header level {}", level).into());
+            }
+            s.borrow_mut().lines.push(ScriptLine::Header {
+                level: level as u8,
+                title: String::from(title),
+            });
+            Ok(())
+        });
+
+        for &(name, signed, base) in [("field_uint", false, 10),
+                                      ("field_int", true, 10),
+                                      ("field_hex", false, 16)].iter()
+        {
+            let s = state.clone();
+            engine.register_fn(name, move |name: &str, offset: i64, size: i64|
+                                          -> ScriptResult<i64>
+            {
+                s.borrow_mut().output_int(name, offset, size, signed, base)
+            });
+        }
+
+        let s = state.clone();
+        engine.register_fn("field_string",
+                           move |name: &str, offset: i64, length: i64|
+                               -> ScriptResult<String>
+        {
+            let mut st = s.borrow_mut();
+            let bytes = st.read(offset, length)?;
+            let value = String::from_utf8_lossy(&bytes).into_owned();
+            st.lines.push(ScriptLine::Str {
+                name: String::from(name),
+                loc: offset as u64,
+                length: length as u64,
+                value: value.clone(),
+            });
+            Ok(value)
+        });
+
+        let s = state.clone();
+        engine.register_fn("field_bytes",
+                           move |name: &str, offset: i64, length: i64|
+                               -> ScriptResult<Blob>
+        {
+            let mut st = s.borrow_mut();
+            let bytes = st.read(offset, length)?;
+            st.lines.push(ScriptLine::Bytes {
+                name: String::from(name),
+                loc: offset as u64,
+                bytes: bytes.clone(),
+                text: None,
+            });
+            Ok(bytes)
+        });
+
+        let s = state.clone();
+        engine.register_fn("field_text",
+                           move |name: &str, offset: i64, length: i64,
+                                 text: &str|
+                               -> ScriptResult<()>
+        {
+            let mut st = s.borrow_mut();
+            let bytes = st.read(offset, length)?;
+            st.lines.push(ScriptLine::Bytes {
+                name: String::from(name),
+                loc: offset as u64,
+                bytes,
+                text: Some(String::from(text)),
+            });
+            Ok(())
+        });
+
+        let s = state.clone();
+        engine.register_fn("check",
+                           move |name: &str, offset: i64, length: i64,
+                                 passed: bool|
+                               -> ScriptResult<()>
+        {
+            if offset < 0 || length < 0 {
+                return Err(format!("Invalid file range {}+{}", offset,
+                                   length).into());
+            }
+            s.borrow_mut().lines.push(ScriptLine::Check {
+                name: String::from(name),
+                loc: offset as u64,
+                length: length as u64,
+                passed,
+            });
+            Ok(())
+        });
+
+        // Commands
+
+        let s = state.clone();
+        engine.register_fn("register_command",
+                           move |name: &str, function: FnPtr| {
+            let mut st = s.borrow_mut();
+            st.commands.retain(|(n, _)| n != name);
+            st.commands.push((String::from(name), function));
+        });
+
+        let s = state.clone();
+        engine.register_fn("set_loc", move |offset: i64| -> ScriptResult<()> {
+            if offset < 0 {
+                return Err(format!("Invalid offset {}", offset).into());
+            }
+            s.borrow_mut().goto = Some(offset as u64);
+            Ok(())
+        });
+
+        let s = state.clone();
+        engine.register_fn("status", move |text: &str| {
+            s.borrow_mut().status = Some(String::from(text));
+        });
+    }
+
+    // Prepares the state for a run on @file at @loc
+    fn start(&mut self, file: &File, loc: u64, limits: &CfgStructLimits)
+        -> Result<(), String>
+    {
+        self.engine.set_max_operations(limits.max_instructions);
+        self.engine.set_max_string_size(limits.max_string_length);
+
+        let mut st = self.state.borrow_mut();
+        let commands = std::mem::take(&mut st.commands);
+        *st = State::default();
+        st.commands = commands;
+
+        st.file = Some(file.duplicate()?);
+        st.loc = loc;
+        st.max_read = limits.max_string_length as u64;
+        st.deadline =
+            Some(Instant::now() + Duration::from_millis(limits.max_time_ms));
+        Ok(())
+    }
+
+    /*
+     * Runs the script as a struct at @loc.  The output up to an error is
+     * returned along with it.
+     */
+    pub fn run_struct(&mut self, file: &File, loc: u64,
+                      limits: &CfgStructLimits)
+        -> (StructOutput, Result<(), String>)
+    {
+        let res = self.start(file, loc, limits).and_then(|_| {
+            self.engine.run_ast_with_scope(&mut Scope::new(), &self.ast)
+                       .map_err(|e| error_text(&e))
+        });
+
+        let mut st = self.state.borrow_mut();
+        st.file = None;
+        (StructOutput {
+            lines: std::mem::take(&mut st.lines),
+            uses_loc: st.uses_loc,
+            reads: std::mem::take(&mut st.reads),
+        }, res)
+    }
+
+    // Runs the script's top level, which registers its commands
+    pub fn init(&mut self, file: &File, loc: u64, limits: &CfgStructLimits)
+        -> Result<(), String>
+    {
+        self.start(file, loc, limits)?;
+        let res = self.engine.run_ast_with_scope(&mut Scope::new(), &self.ast)
+                             .map_err(|e| error_text(&e));
+        self.state.borrow_mut().file = None;
+        res
+    }
+
+    // Names of the commands the script has registered
+    pub fn commands(&self) -> Vec<String> {
+        self.state.borrow().commands.iter().map(|(n, _)| n.clone())
+                                    .collect()
+    }
+
+    /*
+     * Calls the function registered for command @name with @args (an array
+     * of strings), with the cursor at @loc
+     */
+    pub fn run_command(&mut self, name: &str, args: &[String], file: &File,
+                       loc: u64, limits: &CfgStructLimits)
+        -> Result<CommandOutput, String>
+    {
+        let function = match self.state.borrow().commands.iter()
+                                 .find(|(n, _)| n == name)
+        {
+            Some((_, f))        => f.clone(),
+            None                => return Err(format!("Unknown command “{}”",
+                                                      name))
+        };
+
+        self.start(file, loc, limits)?;
+        let args: Array = args.iter().map(|a| Dynamic::from(a.clone()))
+                              .collect();
+        let res = function.call::<Dynamic>(&self.engine, &self.ast, (args,))
+                          .map(|_| ())
+                          .map_err(|e| format!("{}: {}", name,
+                                               error_text(&e)));
+
+        let mut st = self.state.borrow_mut();
+        st.file = None;
+        res?;
+
+        Ok(CommandOutput {
+            goto: st.goto.take(),
+            status: st.status.take(),
+            lines: std::mem::take(&mut st.printed),
+        })
+    }
+}
+
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    pub fn compile(_: &[u8]) -> Result<Self, String> {
+        Err(String::from("butterfly was built without scripting support"))
+    }
+
+    pub fn run_struct(&mut self, _: &File, _: u64, _: &CfgStructLimits)
+        -> (StructOutput, Result<(), String>)
+    {
+        match *self {}
+    }
+
+    pub fn init(&mut self, _: &File, _: u64, _: &CfgStructLimits)
+        -> Result<(), String>
+    {
+        match *self {}
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        match *self {}
+    }
+
+    pub fn run_command(&mut self, _: &str, _: &[String], _: &File, _: u64,
+                       _: &CfgStructLimits)
+        -> Result<CommandOutput, String>
+    {
+        match *self {}
+    }
+}
@@ -0,0 +1,1717 @@
+use serde_json::{self, Value};
+use std;
+use std::collections::{BTreeMap, HashMap};
+use toml;
+
+/*
+ * Compiles declarative struct definitions (JSON or TOML, see doc/struct-json)
+ * into struct byte code (see doc/struct-opcodes).
+ *
+ * All integer field values are kept in WRAM so later fields can refer to them
+ * by name.  The first few WRAM slots are reserved for the compiler's own
+ * bookkeeping.
+ */
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructDef {
+    description: Option<String>,
+    header: Option<String>,
+    endianness: Option<String>,
+    base: Option<Value>,
+    fields: Vec<ItemDef>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ItemDef {
+    name: Option<String>,
+    #[serde(rename = "type")]
+    field_type: Option<String>,
+    header: Option<String>,
+    #[serde(rename = "if")]
+    condition: Option<Value>,
+    #[serde(rename = "else")]
+    else_fields: Option<Vec<ItemDef>>,
+    fields: Option<Vec<ItemDef>>,
+    offset: Option<Value>,
+    base: Option<Value>,
+    count: Option<Value>,
+    until: Option<Value>,
+    stride: Option<Value>,
+    length: Option<Value>,
+    endianness: Option<String>,
+    display: Option<Value>,
+    #[serde(rename = "enum")]
+    enumeration: Option<BTreeMap<String, String>>,
+    flags: Option<BTreeMap<String, String>>,
+    time: Option<String>,
+    fraction_bits: Option<u8>,
+    bits: Option<Vec<BitDef>>,
+    link: Option<LinkDef>,
+    range: Option<RangeDef>,
+    check: Option<Value>,
+    #[serde(default)]
+    hidden: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BitDef {
+    name: String,
+    first: u64,
+    width: Option<u64>,
+    #[serde(default)]
+    signed: bool,
+    display: Option<Value>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct RangeDef {
+    start: Option<Value>,
+    length: Value,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct LinkDef {
+    base: Option<Value>,
+    #[serde(rename = "struct")]
+    target_struct: Option<String>,
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+enum BinOp {
+    Add, Sub, Mul, Div, Mod,
+    And, Or, Xor, Shl, Shr,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    LogAnd, LogOr,
+}
+
+#[derive(Clone, Copy)]
+enum UnOp {
+    Neg, Not, LogNot,
+}
+
+enum Expr {
+    Const(u64),
+    Field(String),
+    Loc,
+    Base,
+    Offset,
+    Index,
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Num(u64),
+    Ident(String),
+    Op(&'static str),
+}
+
+enum FieldType {
+    Int { size: u64, signed: bool, subfunc: u8 },
+    Float { size: u64, subfunc: u8 },
+    // Load subfunctions for null-terminated and sized strings, and the output
+    // subfunction
+    Str { null: u8, sized: u8, encoding: u8 },
+    Guid { mixed_endian: bool },
+    // Byte ranges; the size is None for hex dumps of arbitrary length
+    Bytes { size: Option<u64>, subfunc: u8 },
+    // Checksums over another range
+    Checksum { size: u64, subfunc: u8 },
+    // Variable-length integers; BCD numbers have their length given instead
+    Varint { sized: bool, subfunc: u8 },
+}
+
+enum Name {
+    Static(String),
+    // Name with the array index from the given slot appended (“name[i]”)
+    Indexed(String, u64),
+}
+
+#[derive(Clone)]
+struct Scope {
+    base_slot: u64,
+    vis_slot: Option<u64>,
+    index_slot: Option<u64>,
+    level: u8,
+    big_endian: bool,
+}
+
+struct Compiler {
+    code: Vec<u8>,
+    slots: HashMap<String, u64>,
+    next_slot: u64,
+}
+
+
+// Current absolute file offset (where the next field without an explicit
+// offset is placed)
+const SLOT_CUR: u64 = 0;
+// Offset of the field currently being processed
+const SLOT_FOFS: u64 = 1;
+// Length of the string field currently being processed
+const SLOT_FLEN: u64 = 2;
+const FIRST_FREE_SLOT: u64 = 3;
+
+
+pub fn compile_json(source: &[u8]) -> Result<Vec<u8>, String> {
+    match serde_json::from_slice(source) {
+        Ok(d)   => compile(&d),
+        Err(e)  => Err(format!("Invalid struct definition: {}", e))
+    }
+}
+
+// The same structure as JSON, just written as TOML
+pub fn compile_toml(source: &[u8]) -> Result<Vec<u8>, String> {
+    let source = match std::str::from_utf8(source) {
+        Ok(s)   => s,
+        Err(e)  => return Err(format!("Invalid struct definition: {}", e))
+    };
+    match toml::from_str(source) {
+        Ok(d)   => compile(&d),
+        Err(e)  => Err(format!("Invalid struct definition: {}", e))
+    }
+}
+
+fn compile(def: &StructDef) -> Result<Vec<u8>, String> {
+    let mut c = Compiler {
+        code: Vec::new(),
+        slots: HashMap::new(),
+        next_slot: FIRST_FREE_SLOT,
+    };
+
+    let mut scope = Scope {
+        base_slot: 0,
+        vis_slot: None,
+        index_slot: None,
+        level: 0,
+        big_endian: parse_endianness(&def.endianness, false)?,
+    };
+
+    // Must be the first instruction to be found by :structs
+    if let Some(ref desc) = def.description {
+        c.op(0x35);
+        c.emit_u64(desc.chars().count() as u64);
+        c.code.extend_from_slice(desc.as_bytes());
+    }
+
+    // Allocate all of WRAM up front, so fields skipped by a condition read as
+    // 0 instead of failing (the size is patched in at the end)
+    c.lic(0);
+    let wram_size_pos = c.here();
+    c.lic(0);
+    c.op(0x2c); // ssi
+
+    scope.base_slot = c.alloc_slot();
+    match def.base {
+        Some(ref b) => c.expr(&parse_value(b)?, &scope)?,
+        None        => c.op(0x14), // lic $LOC
+    }
+    c.op(0x81); // idup
+    c.store_slot(scope.base_slot);
+    c.store_slot(SLOT_CUR);
+
+    if let Some(ref title) = def.header {
+        let vis = c.header(&Name::Static(title.clone()), &scope);
+        scope.vis_slot = Some(vis);
+        scope.level += 1;
+    }
+
+    c.items(&def.fields, &scope)?;
+    c.op(0x00); // stop
+
+    let wram_top = c.next_slot - 1;
+    c.patch_u64(wram_size_pos + 1, wram_top);
+
+    Ok(c.code)
+}
+
+
+fn parse_endianness(e: &Option<String>, default: bool)
+    -> Result<bool, String>
+{
+    match *e {
+        None    => Ok(default),
+        Some(ref s) => match s.as_str() {
+            "le" | "little" => Ok(false),
+            "be" | "big"    => Ok(true),
+            _ => Err(format!("Invalid endianness “{}”", s))
+        }
+    }
+}
+
+fn parse_display(d: &Option<Value>) -> Result<u8, String> {
+    let base = match *d {
+        None => 10,
+        Some(Value::String(ref s)) => match s.as_str() {
+            "bin" => 2,
+            "oct" => 8,
+            "dec" => 10,
+            "hex" => 16,
+            _ => return Err(format!("Invalid display mode “{}”", s))
+        },
+        Some(Value::Number(ref n)) => match n.as_u64() {
+            Some(b) => b,
+            None    => return Err(format!("Invalid display base {}", n))
+        },
+        Some(ref v) => return Err(format!("Invalid display mode {}", v))
+    };
+
+    if !(2..=36).contains(&base) {
+        return Err(format!("Display base must be in [2, 36], but is {}",
+                           base));
+    }
+    Ok(base as u8)
+}
+
+fn parse_type(t: &str) -> Result<FieldType, String> {
+    Ok(match t {
+        "u64"   => FieldType::Int { size: 8, signed: false, subfunc: 0x00 },
+        "i64"   => FieldType::Int { size: 8, signed: true,  subfunc: 0x01 },
+        "u32"   => FieldType::Int { size: 4, signed: false, subfunc: 0x02 },
+        "i32"   => FieldType::Int { size: 4, signed: true,  subfunc: 0x03 },
+        "u16"   => FieldType::Int { size: 2, signed: false, subfunc: 0x04 },
+        "i16"   => FieldType::Int { size: 2, signed: true,  subfunc: 0x05 },
+        "u8"    => FieldType::Int { size: 1, signed: false, subfunc: 0x06 },
+        "i8"    => FieldType::Int { size: 1, signed: true,  subfunc: 0x07 },
+        "f64"   => FieldType::Float { size: 8, subfunc: 0x00 },
+        "f32"   => FieldType::Float { size: 4, subfunc: 0x01 },
+        "utf8"  => FieldType::Str { null: 0x04, sized: 0x12, encoding: 0 },
+        "ascii" => FieldType::Str { null: 0x05, sized: 0x03, encoding: 0 },
+        "utf16le"   => FieldType::Str { null: 0x06, sized: 0x07, encoding: 1 },
+        "utf16be"   => FieldType::Str { null: 0x08, sized: 0x09, encoding: 2 },
+        "utf32le"   => FieldType::Str { null: 0x0a, sized: 0x0b, encoding: 3 },
+        "utf32be"   => FieldType::Str { null: 0x0c, sized: 0x0d, encoding: 4 },
+        "latin1"    => FieldType::Str { null: 0x0e, sized: 0x0f, encoding: 5 },
+        "ebcdic"    => FieldType::Str { null: 0x10, sized: 0x11, encoding: 6 },
+        "guid"  => FieldType::Guid { mixed_endian: true },
+        "uuid"  => FieldType::Guid { mixed_endian: false },
+        "ipv4"  => FieldType::Bytes { size: Some(4),  subfunc: 0x00 },
+        "ipv6"  => FieldType::Bytes { size: Some(16), subfunc: 0x01 },
+        "mac"   => FieldType::Bytes { size: Some(6),  subfunc: 0x02 },
+        "bytes" => FieldType::Bytes { size: None,     subfunc: 0x03 },
+        "crc32"     => FieldType::Checksum { size: 4,  subfunc: 0x00 },
+        "crc32c"    => FieldType::Checksum { size: 4,  subfunc: 0x01 },
+        "adler32"   => FieldType::Checksum { size: 4,  subfunc: 0x02 },
+        "sha1"      => FieldType::Checksum { size: 20, subfunc: 0x03 },
+        "sha256"    => FieldType::Checksum { size: 32, subfunc: 0x04 },
+        "uleb128"   => FieldType::Varint { sized: false, subfunc: 0x00 },
+        "sleb128"   => FieldType::Varint { sized: false, subfunc: 0x01 },
+        "zigzag"    => FieldType::Varint { sized: false, subfunc: 0x02 },
+        "vlq"       => FieldType::Varint { sized: false, subfunc: 0x03 },
+        "bcd"       => FieldType::Varint { sized: true,  subfunc: 0x04 },
+        _ => return Err(format!("Unknown type “{}”", t))
+    })
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(rest)  => (true, rest),
+        None        => (false, s)
+    };
+
+    let val = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(oct) = s.strip_prefix("0o") {
+        u64::from_str_radix(oct, 8)
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2)
+    } else {
+        s.parse::<u64>()
+    };
+
+    match val {
+        Ok(v) if neg    => Some(v.wrapping_neg()),
+        Ok(v)           => Some(v),
+        Err(_)          => None
+    }
+}
+
+// Parses a GUID in its usual textual form into its on-disk form
+fn parse_guid(s: &str, mixed_endian: bool) -> Option<Vec<u8>> {
+    let digits: String = s.chars().filter(|c| *c != '-').collect();
+    if digits.len() != 32 || !digits.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    for i in 0..16 {
+        match u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16) {
+            Ok(b)   => bytes.push(b),
+            Err(_)  => return None
+        }
+    }
+    if mixed_endian {
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+    }
+    Some(bytes)
+}
+
+// Text of a check's condition, for naming it
+fn check_text(v: &Value) -> String {
+    match *v {
+        Value::String(ref s) => s.clone(),
+        ref v => v.to_string(),
+    }
+}
+
+fn parse_value(v: &Value) -> Result<Expr, String> {
+    match *v {
+        Value::Number(ref n) => match n.as_u64() {
+            Some(x) => Ok(Expr::Const(x)),
+            None    => match n.as_i64() {
+                Some(x) => Ok(Expr::Const(x as u64)),
+                None    => Err(format!("Invalid integer {}", n))
+            }
+        },
+        Value::String(ref s) => parse_expr(s),
+        _ => Err(format!("Invalid expression {}", v))
+    }
+}
+
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    const OPS: [&str; 23] = [
+        "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+        "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">",
+        "(", ")", ",",
+    ];
+
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    'outer: while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_alphanumeric() || c == '_' || c == '$' || c == '.' {
+            let start = i;
+            while i < chars.len() &&
+                  (chars[i].is_alphanumeric() || chars[i] == '_' ||
+                   chars[i] == '$' || chars[i] == '.')
+            {
+                i += 1;
+            }
+
+            let word: String = chars[start..i].iter().collect();
+            if c.is_ascii_digit() {
+                match parse_number(&word) {
+                    Some(n) => tokens.push(Token::Num(n)),
+                    None    => return Err(format!("Invalid number “{}”", word))
+                }
+            } else {
+                tokens.push(Token::Ident(word));
+            }
+            continue;
+        }
+
+        for op in OPS.iter() {
+            let op_chars: Vec<char> = op.chars().collect();
+            if chars[i..].starts_with(&op_chars) {
+                tokens.push(Token::Op(op));
+                i += op_chars.len();
+                continue 'outer;
+            }
+        }
+
+        return Err(format!("Unexpected character “{}” in “{}”", c, s));
+    }
+
+    Ok(tokens)
+}
+
+fn binop_info(op: &str) -> Option<(BinOp, u32)> {
+    Some(match op {
+        "||"    => (BinOp::LogOr,  1),
+        "&&"    => (BinOp::LogAnd, 2),
+        "|"     => (BinOp::Or,     3),
+        "^"     => (BinOp::Xor,    4),
+        "&"     => (BinOp::And,    5),
+        "=="    => (BinOp::Eq,     6),
+        "!="    => (BinOp::Ne,     6),
+        "<"     => (BinOp::Lt,     7),
+        "<="    => (BinOp::Le,     7),
+        ">"     => (BinOp::Gt,     7),
+        ">="    => (BinOp::Ge,     7),
+        "<<"    => (BinOp::Shl,    8),
+        ">>"    => (BinOp::Shr,    8),
+        "+"     => (BinOp::Add,    9),
+        "-"     => (BinOp::Sub,    9),
+        "*"     => (BinOp::Mul,   10),
+        "/"     => (BinOp::Div,   10),
+        "%"     => (BinOp::Mod,   10),
+        _ => return None
+    })
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+fn parse_expr(s: &str) -> Result<Expr, String> {
+    let mut p = Parser {
+        source: s,
+        tokens: tokenize(s)?,
+        pos: 0,
+    };
+
+    let e = p.binary(1)?;
+    if p.pos < p.tokens.len() {
+        return Err(format!("Trailing garbage in expression “{}”", s));
+    }
+    Ok(e)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    // Precedence climbing; all binary operators are left-associative
+    fn binary(&mut self, min_prec: u32) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+
+        while let Some(Token::Op(o)) = self.peek() {
+            let (op, prec) = match binop_info(o) {
+                Some(x) => x,
+                None    => break
+            };
+            if prec < min_prec {
+                break;
+            }
+
+            self.pos += 1;
+            let rhs = self.binary(prec + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let tok = match self.peek() {
+            Some(t) => t,
+            None    =>
+                return Err(format!("Unexpected end of expression “{}”",
+                                   self.source))
+        };
+        self.pos += 1;
+
+        match tok {
+            Token::Num(n) => Ok(Expr::Const(n)),
+
+            Token::Ident(name) => Ok(match name.as_str() {
+                "$LOC"      => Expr::Loc,
+                "$BASE"     => Expr::Base,
+                "$OFFSET"   => Expr::Offset,
+                "$INDEX"    => Expr::Index,
+                _           => Expr::Field(name)
+            }),
+
+            Token::Op("-") => Ok(Expr::Unary(UnOp::Neg, Box::new(self.unary()?))),
+            Token::Op("~") => Ok(Expr::Unary(UnOp::Not, Box::new(self.unary()?))),
+            Token::Op("!") =>
+                Ok(Expr::Unary(UnOp::LogNot, Box::new(self.unary()?))),
+
+            Token::Op("(") => {
+                let e = self.binary(1)?;
+                if self.peek() != Some(Token::Op(")")) {
+                    return Err(format!("Missing “)” in expression “{}”",
+                                       self.source));
+                }
+                self.pos += 1;
+                Ok(e)
+            },
+
+            _ => Err(format!("Unexpected token in expression “{}”",
+                             self.source))
+        }
+    }
+}
+
+
+impl Compiler {
+    fn here(&self) -> usize {
+        self.code.len()
+    }
+
+    fn alloc_slot(&mut self) -> u64 {
+        self.next_slot += 1;
+        self.next_slot - 1
+    }
+
+    fn field_slot(&mut self, name: &str) -> u64 {
+        if let Some(s) = self.slots.get(name) {
+            return *s;
+        }
+
+        let s = self.alloc_slot();
+        self.slots.insert(String::from(name), s);
+        s
+    }
+
+    fn op(&mut self, opcode: u8) {
+        self.code.push(opcode);
+    }
+
+    fn op2(&mut self, opcode: u8, subfunc: u8) {
+        self.code.push(opcode);
+        self.code.push(subfunc);
+    }
+
+    fn emit_u64(&mut self, val: u64) {
+        for i in 0..8 {
+            self.code.push((val >> (i * 8)) as u8);
+        }
+    }
+
+    fn patch_u64(&mut self, pos: usize, val: u64) {
+        for i in 0..8 {
+            self.code[pos + i] = (val >> (i * 8)) as u8;
+        }
+    }
+
+    fn lic(&mut self, val: u64) {
+        self.op(0x10);
+        self.emit_u64(val);
+    }
+
+    fn lsc(&mut self, s: &str) {
+        self.op(0x12);
+        self.emit_u64(s.chars().count() as u64);
+        self.code.extend_from_slice(s.as_bytes());
+    }
+
+    // Emits a table for the symbolic output instructions
+    fn symbol_table(&mut self, entries: &[(Vec<u8>, String)]) {
+        self.emit_u64(entries.len() as u64);
+        for (key, name) in entries {
+            self.code.extend_from_slice(key);
+            self.emit_u64(name.chars().count() as u64);
+            self.code.extend_from_slice(name.as_bytes());
+        }
+    }
+
+    fn load_slot(&mut self, slot: u64) {
+        self.lic(slot);
+        self.op(0x1c); // sli
+    }
+
+    // Stores the topmost value of the integer stack
+    fn store_slot(&mut self, slot: u64) {
+        self.lic(slot);
+        self.op(0x2c); // ssi
+    }
+
+    // Emits a jump with a yet unknown target, to be set with patch_jump()
+    fn jump(&mut self, opcode: u8) -> usize {
+        let pos = self.here();
+        self.op(opcode);
+        self.emit_u64(0);
+        pos
+    }
+
+    fn patch_jump(&mut self, insn: usize, target: usize) {
+        let rel = (target as u64).wrapping_sub(insn as u64);
+        self.patch_u64(insn + 1, rel);
+    }
+
+    fn jump_to(&mut self, opcode: u8, target: usize) {
+        let pos = self.jump(opcode);
+        self.patch_jump(pos, target);
+    }
+
+    fn name(&mut self, name: &Name) {
+        match *name {
+            Name::Static(ref s) => self.lsc(s),
+
+            Name::Indexed(ref s, index_slot) => {
+                // scat puts the topmost string first, so build it back to
+                // front
+                self.lsc("]");
+                self.load_slot(index_slot);
+                self.op2(0xc8, 0x00); // sfu
+                self.code.push(10);
+                self.op(0xc4); // scat
+                self.lsc(&format!("{}[", s));
+                self.op(0xc4); // scat
+            }
+        }
+    }
+
+    // Skips the following code if the current scope is folded; the return
+    // value must be passed to guard_end()
+    fn guard_begin(&mut self, scope: &Scope) -> Option<usize> {
+        match scope.vis_slot {
+            Some(vis) => {
+                self.load_slot(vis);
+                Some(self.jump(0xe1)) // jz
+            },
+            None => None
+        }
+    }
+
+    fn guard_end(&mut self, guard: Option<usize>) {
+        if let Some(j) = guard {
+            let target = self.here();
+            self.patch_jump(j, target);
+        }
+    }
+
+    fn expr(&mut self, e: &Expr, scope: &Scope) -> Result<(), String> {
+        match *e {
+            Expr::Const(c) => self.lic(c),
+
+            Expr::Field(ref name) => {
+                let slot = match self.slots.get(name) {
+                    Some(s) => *s,
+                    None    => return Err(format!("Unknown field “{}”", name))
+                };
+                self.load_slot(slot);
+            },
+
+            Expr::Loc => self.op(0x14),
+
+            Expr::Base => self.load_slot(scope.base_slot),
+
+            Expr::Offset => {
+                self.load_slot(SLOT_CUR);
+                self.load_slot(scope.base_slot);
+                self.op(0x89); // isub
+            },
+
+            Expr::Index => match scope.index_slot {
+                Some(s) => self.load_slot(s),
+                None    => return Err(String::from("$INDEX used outside of an \
+                                                    array"))
+            },
+
+            Expr::Unary(op, ref x) => {
+                self.expr(x, scope)?;
+                self.op(match op {
+                    UnOp::Neg       => 0x83, // ineg
+                    UnOp::Not       => 0x88, // inot
+                    UnOp::LogNot    => 0x93, // lnot
+                });
+            },
+
+            Expr::Binary(op, ref x, ref y) => {
+                self.expr(x, scope)?;
+                if op == BinOp::LogAnd || op == BinOp::LogOr {
+                    self.op(0x93); // lnot
+                    self.op(0x93); // lnot
+                }
+                self.expr(y, scope)?;
+
+                match op {
+                    BinOp::Add      => self.op(0x84), // iadd
+                    BinOp::Sub      => self.op(0x89), // isub
+                    BinOp::Mul      => self.op(0x8a), // imul
+                    BinOp::Div      => self.op(0x8b), // idivu
+                    BinOp::Mod      => self.op(0x8c), // imodu
+                    BinOp::And      => self.op(0x85), // iand
+                    BinOp::Or       => self.op(0x86), // ior
+                    BinOp::Xor      => self.op(0x87), // ixor
+                    BinOp::Shl      => self.op(0x8d), // ishl
+                    BinOp::Shr      => self.op(0x8e), // ishr
+                    BinOp::Eq       => self.op(0x90), // ieq
+                    BinOp::Ne       => { self.op(0x90); self.op(0x93) },
+                    BinOp::Lt       => self.op(0x91), // iltu
+                    BinOp::Ge       => { self.op(0x91); self.op(0x93) },
+                    BinOp::Gt       => { self.op(0x80); self.op(0x91) },
+                    BinOp::Le       => {
+                        self.op(0x80);
+                        self.op(0x91);
+                        self.op(0x93);
+                    },
+                    BinOp::LogAnd   => {
+                        self.op(0x93);
+                        self.op(0x93);
+                        self.op(0x85);
+                    },
+                    BinOp::LogOr    => {
+                        self.op(0x86);
+                        self.op(0x93);
+                        self.op(0x93);
+                    },
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    // Pushes the absolute offset of an item onto the stack
+    fn item_offset(&mut self, item: &ItemDef, scope: &Scope)
+        -> Result<(), String>
+    {
+        match item.offset {
+            Some(ref o) => {
+                self.load_slot(scope.base_slot);
+                self.expr(&parse_value(o)?, scope)?;
+                self.op(0x84); // iadd
+            },
+            None => self.load_slot(SLOT_CUR),
+        }
+        Ok(())
+    }
+
+    // Emits an OUTPUT_HEADER for the given title and returns the slot that
+    // stores whether its contents are visible
+    fn header(&mut self, title: &Name, scope: &Scope) -> u64 {
+        let vis = self.alloc_slot();
+
+        let guard = self.guard_begin(scope);
+        self.name(title);
+        self.op2(0x2b, scope.level); // oh<level>
+        self.store_slot(vis);
+
+        if guard.is_some() {
+            let skip = self.jump(0xe0); // jmp
+            self.guard_end(guard);
+            self.lic(0);
+            self.store_slot(vis);
+            let target = self.here();
+            self.patch_jump(skip, target);
+        }
+
+        vis
+    }
+
+    fn items(&mut self, items: &Vec<ItemDef>, scope: &Scope)
+        -> Result<(), String>
+    {
+        for item in items {
+            let res = if item.check.is_some() && item.field_type.is_none() &&
+                         (item.fields.is_some() || item.condition.is_some())
+            {
+                Err(String::from("Only fields can have checks (use a \
+                                  separate check item)"))
+            } else if item.count.is_some() || item.until.is_some() {
+                self.array(item, scope)
+            } else if item.condition.is_some() {
+                self.conditional(item, scope)
+            } else if item.fields.is_some() {
+                self.group(item, scope)
+            } else if item.field_type.is_some() {
+                let name = match item.name {
+                    Some(ref n) => Name::Static(n.clone()),
+                    None        => return Err(String::from("Field without \
+                                                            a name"))
+                };
+                self.field(item, &name, scope)
+            } else if item.check.is_some() {
+                self.standalone_check(item, scope)
+            } else {
+                Err(String::from("Item is neither a field, a group, an array, \
+                                  a condition, nor a check"))
+            };
+
+            if let Err(e) = res {
+                return Err(match item.name.as_ref().or(item.header.as_ref()) {
+                    Some(n) => format!("{}: {}", n, e),
+                    None    => e
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn field(&mut self, item: &ItemDef, name: &Name, scope: &Scope)
+        -> Result<(), String>
+    {
+        let plain_name = match *name {
+            Name::Static(ref s) | Name::Indexed(ref s, _) => s.clone()
+        };
+        let field_type = match item.field_type {
+            Some(ref t) => parse_type(t)?,
+            None        => return Err(String::from("Field without a type"))
+        };
+        let big_endian = parse_endianness(&item.endianness, scope.big_endian)?;
+
+        self.item_offset(item, scope)?;
+        self.store_slot(SLOT_FOFS);
+
+        match field_type {
+            FieldType::Int { size, signed, subfunc } => {
+                let slot = self.field_slot(&plain_name);
+
+                self.op2(0x01, big_endian as u8); // f2le/f2be
+                self.load_slot(SLOT_FOFS);
+                self.op2(0x18, subfunc);
+                self.store_slot(slot);
+
+                self.advance(size);
+
+                if !item.hidden {
+                    let guard = self.guard_begin(scope);
+                    self.int_output(item, name, slot, size, signed, scope)?;
+                    self.guard_end(guard);
+                }
+
+                if let Some(ref bits) = item.bits {
+                    for bit in bits {
+                        self.bitfield(bit, &plain_name, slot, size, item.hidden,
+                                      scope)?;
+                    }
+                }
+            },
+
+            FieldType::Float { size, subfunc } => {
+                self.advance(size);
+
+                if !item.hidden {
+                    let guard = self.guard_begin(scope);
+                    self.op2(0x01, big_endian as u8); // f2le/f2be
+                    self.load_slot(SLOT_FOFS);
+                    self.lic(size);
+                    self.load_slot(SLOT_FOFS);
+                    self.op2(0x19, subfunc);
+                    self.name(name);
+                    self.op2(0x29, 0x00); // osf
+                    self.guard_end(guard);
+                }
+            },
+
+            FieldType::Guid { mixed_endian } => {
+                let mut table = Vec::new();
+                if let Some(ref enumeration) = item.enumeration {
+                    for (key, text) in enumeration.iter() {
+                        match parse_guid(key, mixed_endian) {
+                            Some(g) => table.push((g, text.clone())),
+                            None    => return Err(format!("Invalid GUID “{}”",
+                                                          key))
+                        }
+                    }
+                }
+
+                self.advance(16);
+
+                if !item.hidden {
+                    let guard = self.guard_begin(scope);
+                    self.load_slot(SLOT_FOFS);
+                    self.name(name);
+                    self.op2(0x31, !mixed_endian as u8); // oguid/ouuid
+                    self.symbol_table(&table);
+                    self.guard_end(guard);
+                }
+            },
+
+            FieldType::Bytes { size, subfunc } => {
+                match (size, &item.length) {
+                    (Some(s), _) => self.lic(s),
+                    (None, Some(l)) => self.expr(&parse_value(l)?, scope)?,
+                    (None, None) =>
+                        return Err(String::from("Byte ranges need a length")),
+                }
+                self.store_slot(SLOT_FLEN);
+
+                self.load_slot(SLOT_FOFS);
+                self.load_slot(SLOT_FLEN);
+                self.op(0x84); // iadd
+                self.store_slot(SLOT_CUR);
+
+                if !item.hidden {
+                    let guard = self.guard_begin(scope);
+                    self.load_slot(SLOT_FOFS);
+                    self.load_slot(SLOT_FLEN);
+                    self.name(name);
+                    self.op2(0x33, subfunc); // oipv4/oipv6/omac/ohex
+                    self.guard_end(guard);
+                }
+            },
+
+            FieldType::Str { null, sized, encoding } => {
+                self.load_slot(SLOT_FOFS);
+                match item.length {
+                    Some(ref l) => {
+                        self.expr(&parse_value(l)?, scope)?;
+                        self.op(0x81); // idup
+                        self.store_slot(SLOT_FLEN);
+                        self.op2(0x1a, sized); // flsutf8bsized/...
+                    },
+                    None => {
+                        self.op2(0x1a, null); // flsutf8nulll/...
+                        self.store_slot(SLOT_FLEN);
+                    }
+                }
+
+                self.load_slot(SLOT_FOFS);
+                self.load_slot(SLOT_FLEN);
+                self.op(0x84); // iadd
+                self.store_slot(SLOT_CUR);
+
+                if item.hidden {
+                    self.op(0xc2); // sdrop
+                } else {
+                    let guard = self.guard_begin(scope);
+                    self.load_slot(SLOT_FOFS);
+                    self.load_slot(SLOT_FLEN);
+                    self.name(name);
+                    // oss/ossnull/ossutf16le/...
+                    self.op2(0x2a, encoding << 1 | item.length.is_none() as u8);
+
+                    if guard.is_some() {
+                        let skip = self.jump(0xe0); // jmp
+                        self.guard_end(guard);
+                        self.op(0xc2); // sdrop
+                        let target = self.here();
+                        self.patch_jump(skip, target);
+                    }
+                }
+            },
+
+            FieldType::Varint { sized, subfunc } => {
+                let slot = self.field_slot(&plain_name);
+
+                self.load_slot(SLOT_FOFS);
+                if sized {
+                    match item.length {
+                        Some(ref l) => self.expr(&parse_value(l)?, scope)?,
+                        None => return Err(String::from("BCD numbers need a \
+                                                         length")),
+                    }
+                }
+                self.op2(0x1b, subfunc); // flleb128u/.../flbcd
+                self.store_slot(SLOT_FLEN);
+                self.store_slot(slot);
+
+                self.load_slot(SLOT_FOFS);
+                self.load_slot(SLOT_FLEN);
+                self.op(0x84); // iadd
+                self.store_slot(SLOT_CUR);
+
+                if !item.hidden {
+                    let base = parse_display(&item.display)?;
+                    let guard = self.guard_begin(scope);
+                    self.load_slot(SLOT_FOFS);
+                    self.load_slot(SLOT_FLEN);
+                    self.load_slot(slot);
+                    self.name(name);
+                    self.op2(0x39, subfunc); // ovleb128u/.../ovbcd
+                    self.code.push(base);
+                    self.guard_end(guard);
+                }
+            },
+
+            FieldType::Checksum { size, subfunc } => {
+                let range = match item.range {
+                    Some(ref r) => r,
+                    None        => return Err(String::from("Checksums need a \
+                                                            range"))
+                };
+
+                self.advance(size);
+
+                if !item.hidden {
+                    let guard = self.guard_begin(scope);
+                    self.op2(0x01, big_endian as u8); // f2le/f2be
+                    match range.start {
+                        Some(ref s) => self.expr(&parse_value(s)?, scope)?,
+                        None        => self.load_slot(scope.base_slot),
+                    }
+                    self.expr(&parse_value(&range.length)?, scope)?;
+                    self.load_slot(SLOT_FOFS);
+                    self.name(name);
+                    self.op2(0x37, subfunc); // ocrc32/.../osha256
+                    self.guard_end(guard);
+                }
+            },
+        }
+
+        if let Some(ref c) = item.check {
+            // The field covers FOFS..CUR now
+            let guard = self.guard_begin(scope);
+            self.load_slot(SLOT_FOFS);
+            self.load_slot(SLOT_CUR);
+            self.load_slot(SLOT_FOFS);
+            self.op(0x89); // isub
+            self.expr(&parse_value(c)?, scope)?;
+            self.lsc(&format!(": {}", check_text(c)));
+            self.name(name);
+            self.op(0xc4); // scat
+            self.op(0x3a); // ochk
+            self.guard_end(guard);
+        }
+
+        Ok(())
+    }
+
+    // A check on its own, at the current offset (or the given one)
+    fn standalone_check(&mut self, item: &ItemDef, scope: &Scope)
+        -> Result<(), String>
+    {
+        let cond = parse_value(item.check.as_ref().unwrap())?;
+        let name = match item.name {
+            Some(ref n) => n.clone(),
+            None        => check_text(item.check.as_ref().unwrap()),
+        };
+
+        let guard = self.guard_begin(scope);
+        self.item_offset(item, scope)?;
+        match item.length {
+            Some(ref l) => self.expr(&parse_value(l)?, scope)?,
+            None        => self.lic(0),
+        }
+        self.expr(&cond, scope)?;
+        self.lsc(&name);
+        self.op(0x3a); // ochk
+        self.guard_end(guard);
+
+        Ok(())
+    }
+
+    // CUR = FOFS + size
+    fn advance(&mut self, size: u64) {
+        self.load_slot(SLOT_FOFS);
+        self.lic(size);
+        self.op(0x84); // iadd
+        self.store_slot(SLOT_CUR);
+    }
+
+    fn int_output(&mut self, item: &ItemDef, name: &Name, slot: u64, size: u64,
+                  signed: bool, scope: &Scope)
+        -> Result<(), String>
+    {
+        let base = parse_display(&item.display)?;
+        let symbols = match (&item.enumeration, &item.flags) {
+            (Some(_), Some(_)) =>
+                return Err(String::from("A field cannot have both an enum \
+                                         and flags")),
+            (Some(e), None) => Some((e, signed as u8)),    // oeu/oei
+            (None, Some(f)) => Some((f, 0x02)),            // ofl
+            (None, None)    => None
+        };
+
+
+        let formatted = match (&item.time, item.fraction_bits) {
+            (Some(_), Some(_)) =>
+                return Err(String::from("A field cannot be both a time and a \
+                                         fixed-point number")),
+            (Some(t), None) => Some(match t.as_str() {
+                "unix"  => 0x00, // otunix
+                "ntfs"  => 0x01, // otntfs
+                "fat"   => 0x02, // otfat
+                _ => return Err(format!("Unknown time format “{}”", t))
+            }),
+            (None, Some(_)) => Some(0x03 + signed as u8), // ofxu/ofxi
+            (None, None) => None
+        };
+
+        let special = symbols.is_some() as u32 + formatted.is_some() as u32 +
+                      item.link.is_some() as u32;
+        if special > 1 {
+            return Err(String::from("Only one of enum, flags, link, time and \
+                                     fraction_bits may be given"));
+        }
+
+        self.load_slot(SLOT_FOFS);
+        self.lic(size);
+
+        if let Some(subfunc) = formatted {
+            self.load_slot(slot);
+            self.name(name);
+            self.op2(0x32, subfunc);
+            if let Some(bits) = item.fraction_bits {
+                if bits as u64 > size * 8 {
+                    return Err(format!("More fraction bits ({}) than the \
+                                        field has", bits));
+                }
+                self.code.push(bits);
+            }
+            return Ok(());
+        }
+
+        if let Some((map, subfunc)) = symbols {
+            let mut table = Vec::new();
+            for (key, text) in map.iter() {
+                let val = match parse_number(key) {
+                    Some(v) => v,
+                    None    => return Err(format!("Invalid value “{}”", key))
+                };
+                table.push(((0..8).map(|i| (val >> (i * 8)) as u8).collect(),
+                            text.clone()));
+            }
+
+            self.load_slot(slot);
+            self.name(name);
+            self.op2(0x30, subfunc); // oeu/oei/ofl
+            self.code.push(base);
+            self.symbol_table(&table);
+            return Ok(());
+        }
+
+        match item.link {
+            Some(ref link) => {
+                match link.base {
+                    Some(ref b) => self.expr(&parse_value(b)?, scope)?,
+                    None        => self.lic(0),
+                }
+                self.load_slot(slot);
+                self.lsc(link.target_struct.as_deref().unwrap_or(""));
+                self.name(name);
+                self.op2(0x28, 0x02 | signed as u8); // olu/oli
+            },
+            None => {
+                self.load_slot(slot);
+                self.name(name);
+                self.op2(0x28, signed as u8); // osu/osi
+            }
+        }
+        self.code.push(base);
+
+        Ok(())
+    }
+
+    fn bitfield(&mut self, bit: &BitDef, field_name: &str, field_slot: u64,
+                size: u64, hidden: bool, scope: &Scope)
+        -> Result<(), String>
+    {
+        let width = bit.width.unwrap_or(1);
+        if width == 0 || bit.first + width > size * 8 {
+            return Err(format!("Bit range of “{}” exceeds the field",
+                               bit.name));
+        }
+
+        let full_name = format!("{}.{}", field_name, bit.name);
+        let slot = self.field_slot(&full_name);
+        let signed = bit.signed as u8;
+
+        self.load_slot(field_slot);
+        self.op2(0x8f, signed); // ibextu/ibexts
+        self.code.push(bit.first as u8);
+        self.code.push(width as u8);
+        self.store_slot(slot);
+
+        if !hidden {
+            let base = parse_display(&bit.display)?;
+            let guard = self.guard_begin(scope);
+            self.load_slot(SLOT_FOFS);
+            self.lic(size);
+            self.load_slot(field_slot);
+            self.lsc(&full_name);
+            self.op2(0x38, signed); // obfu/obfi
+            self.code.push(base);
+            self.code.push(bit.first as u8);
+            self.code.push(width as u8);
+            self.guard_end(guard);
+        }
+
+        Ok(())
+    }
+
+    fn conditional(&mut self, item: &ItemDef, scope: &Scope)
+        -> Result<(), String>
+    {
+        let cond = parse_value(item.condition.as_ref().unwrap())?;
+        let fields = match item.fields {
+            Some(ref f) => f,
+            None        => return Err(String::from("Condition without fields"))
+        };
+
+        self.expr(&cond, scope)?;
+        let to_else = self.jump(0xe1); // jz
+        self.items(fields, scope)?;
+
+        match item.else_fields {
+            Some(ref else_fields) => {
+                let to_end = self.jump(0xe0); // jmp
+                let target = self.here();
+                self.patch_jump(to_else, target);
+                self.items(else_fields, scope)?;
+                let target = self.here();
+                self.patch_jump(to_end, target);
+            },
+            None => {
+                let target = self.here();
+                self.patch_jump(to_else, target);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn group(&mut self, item: &ItemDef, scope: &Scope) -> Result<(), String> {
+        let mut inner = scope.clone();
+        inner.big_endian = parse_endianness(&item.endianness,
+                                            scope.big_endian)?;
+
+        let saved_cur = match item.base {
+            Some(ref b) => {
+                // Groups with an explicit base do not affect the enclosing
+                // layout
+                let save = self.alloc_slot();
+                self.load_slot(SLOT_CUR);
+                self.store_slot(save);
+
+                inner.base_slot = self.alloc_slot();
+                self.expr(&parse_value(b)?, scope)?;
+                self.op(0x81); // idup
+                self.store_slot(inner.base_slot);
+                self.store_slot(SLOT_CUR);
+
+                Some(save)
+            },
+            None => None
+        };
+
+        if let Some(ref title) = item.header {
+            let vis = self.header(&Name::Static(title.clone()), scope);
+            inner.vis_slot = Some(vis);
+            inner.level += 1;
+        }
+
+        self.items(item.fields.as_ref().unwrap(), &inner)?;
+
+        if let Some(save) = saved_cur {
+            self.load_slot(save);
+            self.store_slot(SLOT_CUR);
+        }
+
+        Ok(())
+    }
+
+    fn array(&mut self, item: &ItemDef, scope: &Scope) -> Result<(), String> {
+        let name = match item.name {
+            Some(ref n) => n.clone(),
+            None        => return Err(String::from("Array without a name"))
+        };
+        let count = match item.count {
+            Some(ref c) => Some(parse_value(c)?),
+            None        => None
+        };
+        let until = match item.until {
+            Some(ref u) => Some(parse_value(u)?),
+            None        => None
+        };
+
+        // Arrays of scalars have a fixed element size; arrays of groups only
+        // if a stride is given
+        let elem_size = match item.field_type {
+            Some(ref t) => match parse_type(t)? {
+                FieldType::Int { size, .. } | FieldType::Float { size, .. } =>
+                    Some(size),
+                FieldType::Guid { .. } => Some(16),
+                FieldType::Bytes { size, .. } => size,
+                FieldType::Checksum { size, .. } => Some(size),
+                FieldType::Str { .. } | FieldType::Varint { .. } => None
+            },
+            None => None
+        };
+        if item.field_type.is_none() && item.fields.is_none() {
+            return Err(String::from("Array without an element type or \
+                                     fields"));
+        }
+
+        let start = self.alloc_slot();
+        let cnt = self.alloc_slot();
+        let idx = self.alloc_slot();
+        let first = self.alloc_slot();
+        let end = self.alloc_slot();
+        let in_window = self.alloc_slot();
+        let stride = match item.stride {
+            Some(ref s) => {
+                let slot = self.alloc_slot();
+                self.expr(&parse_value(s)?, scope)?;
+                self.store_slot(slot);
+                Some(slot)
+            },
+            None => match elem_size {
+                Some(size) => {
+                    let slot = self.alloc_slot();
+                    self.lic(size);
+                    self.store_slot(slot);
+                    Some(slot)
+                },
+                None => None
+            }
+        };
+
+        self.item_offset(item, scope)?;
+        self.op(0x81); // idup
+        self.store_slot(start);
+        self.store_slot(SLOT_CUR);
+        match count {
+            Some(ref c) => self.expr(c, scope)?,
+            None        => self.lic(!0u64), // Unknown (until the terminator)
+        }
+        self.store_slot(cnt);
+
+        // The array header yields the range of elements to show
+        let guard = self.guard_begin(scope);
+        self.load_slot(cnt);
+        self.lsc(&name);
+        self.op2(0x34, 0x00); // oab<level>
+        self.code.push(scope.level);
+        self.store_slot(end);
+        self.store_slot(first);
+        if guard.is_some() {
+            let skip = self.jump(0xe0); // jmp
+            self.guard_end(guard);
+            self.lic(0);
+            self.op(0x81); // idup
+            self.store_slot(end);
+            self.store_slot(first);
+            let target = self.here();
+            self.patch_jump(skip, target);
+        }
+
+        let mut inner = scope.clone();
+        inner.vis_slot = Some(in_window);
+        inner.index_slot = Some(idx);
+        inner.level += 1;
+        inner.big_endian = parse_endianness(&item.endianness,
+                                            scope.big_endian)?;
+
+        // With a fixed element size and a known count, only the elements
+        // shown need to be visited; otherwise, all elements must be walked
+        // (invisibly) to find the end of the array
+        let direct = stride.is_some() && until.is_none();
+
+        if direct {
+            self.load_slot(first);
+            self.lic(1);
+            self.store_slot(in_window);
+        } else {
+            self.lic(0);
+        }
+        self.store_slot(idx);
+
+        let loop_start = self.here();
+        self.load_slot(idx);
+        self.load_slot(if direct { end } else { cnt });
+        self.op(0x91); // iltu
+        let loop_exit = self.jump(0xe1); // jz
+
+        if !direct {
+            // in_window = first <= idx && idx < end
+            self.load_slot(idx);
+            self.load_slot(first);
+            self.op(0x91); // iltu
+            self.op(0x93); // lnot
+            self.load_slot(idx);
+            self.load_slot(end);
+            self.op(0x91); // iltu
+            self.op(0x85); // iand
+            self.store_slot(in_window);
+        }
+
+        if let Some(st) = stride {
+            self.load_slot(start);
+            self.load_slot(idx);
+            self.load_slot(st);
+            self.op(0x8a); // imul
+            self.op(0x84); // iadd
+            self.store_slot(SLOT_CUR);
+        }
+
+        let element = ItemDef {
+            name: None,
+            field_type: item.field_type.clone(),
+            header: None,
+            condition: None,
+            else_fields: None,
+            fields: None,
+            offset: None,
+            base: None,
+            count: None,
+            until: None,
+            stride: None,
+            length: item.length.clone(),
+            endianness: None,
+            display: item.display.clone(),
+            enumeration: item.enumeration.clone(),
+            flags: item.flags.clone(),
+            time: item.time.clone(),
+            fraction_bits: item.fraction_bits,
+            bits: None,
+            link: item.link.clone(),
+            range: item.range.clone(),
+            check: item.check.clone(),
+            hidden: item.hidden,
+        };
+
+        let elem_base = self.alloc_slot();
+        let mut elem_scope = inner.clone();
+        if item.field_type.is_some() {
+            self.field(&element, &Name::Indexed(name.clone(), idx), &inner)?;
+        } else {
+            elem_scope.base_slot = elem_base;
+            self.load_slot(SLOT_CUR);
+            self.store_slot(elem_scope.base_slot);
+
+            let elem_vis = self.header(&Name::Indexed(name.clone(), idx),
+                                       &inner);
+            elem_scope.vis_slot = Some(elem_vis);
+            elem_scope.level += 1;
+
+            self.items(item.fields.as_ref().unwrap(), &elem_scope)?;
+        }
+
+        // The terminating element is part of the array
+        let done = match until {
+            Some(ref u) => {
+                self.expr(u, &elem_scope)?;
+                let slot = self.alloc_slot();
+                self.store_slot(slot);
+                Some(slot)
+            },
+            None => None
+        };
+
+        self.load_slot(idx);
+        self.lic(1);
+        self.op(0x84); // iadd
+        self.store_slot(idx);
+        if let Some(d) = done {
+            self.load_slot(d);
+            let exit = self.jump(0xe2); // jnz
+            self.jump_to(0xe0, loop_start); // jmp
+            let target = self.here();
+            self.patch_jump(exit, target);
+        } else {
+            self.jump_to(0xe0, loop_start); // jmp
+        }
+
+        let target = self.here();
+        self.patch_jump(loop_exit, target);
+
+        if direct {
+            self.load_slot(start);
+            self.load_slot(cnt);
+            self.load_slot(stride.unwrap());
+            self.op(0x8a); // imul
+            self.op(0x84); // iadd
+            self.store_slot(SLOT_CUR);
+        } else {
+            // The actual number of elements
+            self.load_slot(idx);
+            self.store_slot(cnt);
+        }
+
+        let guard = self.guard_begin(scope);
+        self.load_slot(cnt);
+        self.op2(0x34, 0x01); // oaend
+        self.guard_end(guard);
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Evaluates a constant expression the way the compiled byte code would
+    fn eval(e: &Expr) -> u64 {
+        match *e {
+            Expr::Const(n) => n,
+            Expr::Unary(op, ref e) => {
+                let v = eval(e);
+                match op {
+                    UnOp::Neg       => v.wrapping_neg(),
+                    UnOp::Not       => !v,
+                    UnOp::LogNot    => (v == 0) as u64,
+                }
+            },
+            Expr::Binary(op, ref l, ref r) => {
+                let (l, r) = (eval(l), eval(r));
+                match op {
+                    BinOp::Add      => l.wrapping_add(r),
+                    BinOp::Sub      => l.wrapping_sub(r),
+                    BinOp::Mul      => l.wrapping_mul(r),
+                    BinOp::Div      => l / r,
+                    BinOp::Mod      => l % r,
+                    BinOp::And      => l & r,
+                    BinOp::Or       => l | r,
+                    BinOp::Xor      => l ^ r,
+                    BinOp::Shl      => l << r,
+                    BinOp::Shr      => l >> r,
+                    BinOp::Eq       => (l == r) as u64,
+                    BinOp::Ne       => (l != r) as u64,
+                    BinOp::Lt       => (l < r) as u64,
+                    BinOp::Le       => (l <= r) as u64,
+                    BinOp::Gt       => (l > r) as u64,
+                    BinOp::Ge       => (l >= r) as u64,
+                    BinOp::LogAnd   => (l != 0 && r != 0) as u64,
+                    BinOp::LogOr    => (l != 0 || r != 0) as u64,
+                }
+            },
+            _ => panic!("Expression is not constant"),
+        }
+    }
+
+    fn value(s: &str) -> u64 {
+        eval(&parse_expr(s).unwrap())
+    }
+
+    fn error(s: &str) -> String {
+        match parse_expr(s) {
+            Ok(_)   => panic!("“{}” parsed", s),
+            Err(e)  => e,
+        }
+    }
+
+    fn json_error(source: &str) -> String {
+        compile_json(source.as_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(value("123"), 123);
+        assert_eq!(value("0x7b + 0o173 + 0b1111011"), 3 * 123);
+        assert_eq!(value("-1"), u64::MAX);
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("1 + 2 * 3"), 7);
+        assert_eq!(value("(1 + 2) * 3"), 9);
+        assert_eq!(value("7 % 4 * 2"), 6);
+        assert_eq!(value("1 << 2 + 1"), 8);
+        assert_eq!(value("2 + 3 < 6"), 1);
+        assert_eq!(value("1 | 2 & 3 == 3"), 1);
+        assert_eq!(value("6 ^ 3 & 1"), 7);
+        assert_eq!(value("0 || 1 && 0"), 0);
+        assert_eq!(value("-2 * 3"), (-6i64) as u64);
+        assert_eq!(value("~0 >> 60"), 0xf);
+        assert_eq!(value("!0 + 1"), 2);
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_eq!(value("10 - 4 - 3"), 3);
+        assert_eq!(value("64 / 4 / 2"), 8);
+        assert_eq!(value("1 << 2 << 3"), 32);
+    }
+
+    #[test]
+    fn names() {
+        match parse_expr("$LOC + size").unwrap() {
+            Expr::Binary(BinOp::Add, ref l, ref r) => {
+                assert!(matches!(**l, Expr::Loc));
+                assert!(matches!(**r, Expr::Field(ref n) if n == "size"));
+            },
+            _ => panic!("Wrong expression tree"),
+        }
+    }
+
+    #[test]
+    fn expression_errors() {
+        assert_eq!(error("1 +"), "Unexpected end of expression “1 +”");
+        assert_eq!(error("(1 + 2"), "Missing “)” in expression “(1 + 2”");
+        assert_eq!(error("1 2"), "Trailing garbage in expression “1 2”");
+        assert_eq!(error("0x1g"), "Invalid number “0x1g”");
+        assert_eq!(error("1 # 2"), "Unexpected character “#” in “1 # 2”");
+        assert_eq!(error("* 2"), "Unexpected token in expression “* 2”");
+    }
+
+    #[test]
+    fn json_and_toml_agree() {
+        let json = r#"{
+            "header": "rec",
+            "endianness": "be",
+            "fields": [
+                { "name": "count", "type": "u8" },
+                { "name": "kind", "type": "u8", "display": "hex" },
+                {
+                    "if": "kind == 1 && count > 0",
+                    "fields": [ { "name": "a", "type": "u16" } ],
+                    "else": [ { "name": "b", "type": "u32" } ]
+                },
+                {
+                    "name": "items",
+                    "count": "count * 2",
+                    "fields": [ { "name": "v", "type": "i16" } ]
+                },
+                {
+                    "name": "names",
+                    "until": "len == 0",
+                    "fields": [
+                        { "name": "len", "type": "u8" },
+                        { "name": "text", "type": "ascii", "length": "len" }
+                    ]
+                }
+            ]
+        }"#;
+        let toml = r#"
+            header = "rec"
+            endianness = "be"
+
+            [[fields]]
+            name = "count"
+            type = "u8"
+
+            [[fields]]
+            name = "kind"
+            type = "u8"
+            display = "hex"
+
+            [[fields]]
+            if = "kind == 1 && count > 0"
+            fields = [ { name = "a", type = "u16" } ]
+            else = [ { name = "b", type = "u32" } ]
+
+            [[fields]]
+            name = "items"
+            count = "count * 2"
+            fields = [ { name = "v", type = "i16" } ]
+
+            [[fields]]
+            name = "names"
+            until = "len == 0"
+            fields = [
+                { name = "len", type = "u8" },
+                { name = "text", type = "ascii", length = "len" },
+            ]
+        "#;
+
+        let code = compile_json(json.as_bytes()).unwrap();
+        assert!(!code.is_empty());
+        assert_eq!(compile_toml(toml.as_bytes()).unwrap(), code);
+    }
+
+    #[test]
+    fn definition_errors() {
+        assert!(json_error("{").starts_with("Invalid struct definition: "));
+        assert!(json_error(r#"{ "fields": [], "foo": 1 }"#)
+                .starts_with("Invalid struct definition: "));
+        assert!(compile_toml(b"fields = ").unwrap_err()
+                .starts_with("Invalid struct definition: "));
+
+        assert_eq!(json_error(r#"{ "fields": [
+                                   { "name": "x", "type": "u24" } ] }"#),
+                   "x: Unknown type “u24”");
+        assert_eq!(json_error(r#"{ "fields": [
+                                   { "name": "x", "type": "u8",
+                                     "offset": "y" } ] }"#),
+                   "x: Unknown field “y”");
+        assert_eq!(json_error(r#"{ "fields": [
+                                   { "count": 2, "type": "u8" } ] }"#),
+                   "Array without a name");
+        assert_eq!(json_error(r#"{ "endianness": "middle", "fields": [] }"#),
+                   "Invalid endianness “middle”");
+        assert_eq!(json_error(r#"{ "fields": [
+                                   { "name": "x", "type": "u8",
+                                     "display": 37 } ] }"#),
+                   "x: Display base must be in [2, 36], but is 37");
+    }
+}
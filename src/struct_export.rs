@@ -0,0 +1,37 @@
+use config::ConfigFile;
+use file::File;
+use number::parse_number;
+use std;
+use structs::Structs;
+
+
+/*
+ * Non-interactive export ("butterfly export-struct"): runs a struct on a file
+ * and writes its full output as JSON to the given path, or to stdout.
+ */
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.len() != 3 && args.len() != 4 {
+        return Err(String::from("Usage: butterfly export-struct <struct> \
+                                 <file> <loc> [output]"));
+    }
+    let loc = parse_number(&args[2])?;
+
+    let config = ConfigFile::new()?;
+    let mut structs = Structs::load(&config);
+    let si = structs.lookup(&args[0])?;
+
+    let mut file = File::new(args[1].clone())?;
+    let limits = config.get_struct_limits().clone();
+    let json = structs.get_mut(si).export(&mut file, loc, &limits)?;
+
+    match args.get(3) {
+        Some(path) => match std::fs::write(path, json + "\n") {
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(format!("{}: {}", path, e))
+        },
+        None => {
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
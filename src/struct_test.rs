@@ -0,0 +1,244 @@
+use config::ConfigFile;
+use file::File;
+use number::parse_number;
+use std;
+use structs::Structs;
+
+
+// Lines of unchanged output shown around each change in a diff
+const DIFF_CONTEXT: usize = 2;
+// Largest table for finding the longest common subsequence of two outputs
+const DIFF_MAX_CELLS: usize = 16 << 20;
+
+enum DiffOp<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+
+/*
+ * Headless struct runner for regression tests ("butterfly test-struct"):
+ * runs a struct on a sample file and compares its output with a snapshot
+ * file, which is (only) written instead if --update is given.  Returns
+ * whether the output matched.
+ */
+pub fn run(args: &[String]) -> Result<bool, String> {
+    let update = args.first().map(|a| a.as_str()) == Some("--update");
+    let args = if update { &args[1..] } else { args };
+
+    if args.len() != 4 {
+        return Err(String::from("Usage: butterfly test-struct [--update] \
+                                 <struct> <file> <loc> <snapshot>"));
+    }
+    let name = &args[0];
+    let loc = parse_number(&args[2])?;
+    let snapshot_path = &args[3];
+
+    let config = ConfigFile::new()?;
+    let mut structs = Structs::load(&config);
+    let si = structs.lookup(name)?;
+
+    let mut file = File::new(args[1].clone())?;
+    let limits = config.get_struct_limits().clone();
+    structs.get_mut(si).update(&mut file, loc, &limits)?;
+
+    let mut actual = structs.get(si).snapshot().join("\n");
+    actual.push('\n');
+
+    if update {
+        if let Err(e) = std::fs::write(snapshot_path, &actual) {
+            return Err(format!("{}: {}", snapshot_path, e));
+        }
+        println!("{}: wrote {}", name, snapshot_path);
+        return Ok(true);
+    }
+
+    let expected = match std::fs::read_to_string(snapshot_path) {
+        Ok(s)   => s,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!("{}: Snapshot does not exist (use --update \
+                                to create it)", snapshot_path));
+        },
+        Err(e)  => return Err(format!("{}: {}", snapshot_path, e))
+    };
+
+    if expected == actual {
+        println!("{}: ok", name);
+        return Ok(true);
+    }
+
+    println!("{}: output differs from {}", name, snapshot_path);
+    println!("--- {}", snapshot_path);
+    println!("+++ {} @ {:#x}", args[1], loc);
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    for line in diff(&expected_lines, &actual_lines) {
+        println!("{}", line);
+    }
+    Ok(false)
+}
+
+// Line diff of @old and @new, with some context around every change
+fn diff(old: &[&str], new: &[&str]) -> Vec<String> {
+    let prefix = old.iter().zip(new.iter()).take_while(|&(a, b)| a == b)
+                    .count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+                              .take_while(|&(a, b)| a == b).count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut ops = Vec::new();
+    for (i, l) in old[..prefix].iter().enumerate() {
+        ops.push((i, DiffOp::Same(l)));
+    }
+
+    if old_mid.len().saturating_mul(new_mid.len()) > DIFF_MAX_CELLS {
+        // Too large for a proper diff, just replace it all
+        for (i, l) in old_mid.iter().enumerate() {
+            ops.push((prefix + i, DiffOp::Removed(l)));
+        }
+        for l in new_mid {
+            ops.push((prefix + old_mid.len(), DiffOp::Added(l)));
+        }
+    } else {
+        lcs_diff(old_mid, new_mid, prefix, &mut ops);
+    }
+
+    for (i, l) in old[old.len() - suffix..].iter().enumerate() {
+        ops.push((old.len() - suffix + i, DiffOp::Same(l)));
+    }
+
+    let changed: Vec<bool> =
+        ops.iter().map(|(_, op)| !matches!(*op, DiffOp::Same(_))).collect();
+    let near_change = |k: usize| {
+        let start = k.saturating_sub(DIFF_CONTEXT);
+        let end = std::cmp::min(k + DIFF_CONTEXT + 1, ops.len());
+        changed[start..end].iter().any(|&c| c)
+    };
+
+    let mut lines = Vec::new();
+    let mut skipped = true;
+    for (k, &(old_line, ref op)) in ops.iter().enumerate() {
+        if !near_change(k) {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            lines.push(format!("@@ line {} @@", old_line + 1));
+            skipped = false;
+        }
+
+        lines.push(match *op {
+            DiffOp::Same(l)     => format!(" {}", l),
+            DiffOp::Removed(l)  => format!("-{}", l),
+            DiffOp::Added(l)    => format!("+{}", l),
+        });
+    }
+    lines
+}
+
+/*
+ * Appends the operations turning @old into @new to @ops, using their longest
+ * common subsequence.  @first is the line number of @old[0].
+ */
+fn lcs_diff<'a>(old: &[&'a str], new: &[&'a str], first: usize,
+                ops: &mut Vec<(usize, DiffOp<'a>)>)
+{
+    // Lengths of the longest common subsequences of all suffixes
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((first + i, DiffOp::Same(old[i])));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() ||
+                                    lcs[i + 1][j] >= lcs[i][j + 1])
+        {
+            ops.push((first + i, DiffOp::Removed(old[i])));
+            i += 1;
+        } else {
+            ops.push((first + i, DiffOp::Added(new[j])));
+            j += 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, DIFF_MAX_CELLS};
+
+    fn lines(s: &str) -> Vec<&str> {
+        s.split(' ').collect()
+    }
+
+    #[test]
+    fn identical() {
+        let old = lines("a b c d e f g");
+        assert!(diff(&old, &old).is_empty());
+        assert!(diff(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn pure_insert() {
+        assert_eq!(diff(&lines("a b c d e f g"), &lines("a b c x d e f g")),
+                   ["@@ line 2 @@", " b", " c", "+x", " d", " e"]);
+        assert_eq!(diff(&[], &lines("a b")), ["@@ line 1 @@", "+a", "+b"]);
+    }
+
+    #[test]
+    fn pure_delete() {
+        assert_eq!(diff(&lines("a b c d e f g"), &lines("a b d e f g")),
+                   ["@@ line 1 @@", " a", " b", "-c", " d", " e"]);
+        assert_eq!(diff(&lines("a b"), &[]), ["@@ line 1 @@", "-a", "-b"]);
+    }
+
+    #[test]
+    fn common_subsequence() {
+        assert_eq!(diff(&lines("a b c"), &lines("b c d")),
+                   ["@@ line 1 @@", "-a", " b", " c", "+d"]);
+
+        // Two changes far enough apart for separate hunks
+        assert_eq!(diff(&lines("a b c d e f g h i"),
+                        &lines("x b c d e f g h y")),
+                   ["@@ line 1 @@", "-a", "+x", " b", " c",
+                    "@@ line 7 @@", " g", " h", "-i", "+y"]);
+    }
+
+    #[test]
+    fn too_large() {
+        let n = 4097;
+        assert!(n * n > DIFF_MAX_CELLS);
+
+        // Only the first and last lines differ, but everything in between is
+        // replaced
+        let old: Vec<String> = (0..n).map(|i| format!("{}", i)).collect();
+        let mut new = old.clone();
+        new[0] = String::from("first");
+        new[n - 1] = String::from("last");
+        let old: Vec<&str> = old.iter().map(|s| s.as_str()).collect();
+        let new: Vec<&str> = new.iter().map(|s| s.as_str()).collect();
+
+        let result = diff(&old, &new);
+        assert_eq!(result.len(), 1 + 2 * n);
+        assert_eq!(result[0], "@@ line 1 @@");
+        assert_eq!(result[1], "-0");
+        assert_eq!(result[2], "-1");
+        assert_eq!(result[n], format!("-{}", n - 1));
+        assert_eq!(result[n + 1], "+first");
+        assert_eq!(result[n + 2], "+1");
+        assert_eq!(result[2 * n], "+last");
+    }
+}
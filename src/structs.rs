@@ -1,496 +1,3071 @@
-use config::{self, ConfigFile};
+use checksum::{self, Hasher};
+use config::{self, CfgEntryStruct, CfgStructDetect, CfgStructLimits,
+             ConfigFile};
 use display::{Color, Display};
+use encoding::{Decoder, Encoding};
 use file::File;
+use script::{Script, ScriptLine};
+use serde_json;
 use std;
 use std::collections::HashMap;
 use std::num::Wrapping;
+use std::time::{Duration, Instant, SystemTime};
+use struct_def;
 
 
 pub struct StructCode {
     buffer: Vec<u8>,
 }
 
+// (key, name) pairs for the symbolic output instructions
+type SymbolTable = Vec<(Vec<u8>, String)>;
+
 struct Header {
     folded: bool,
+    // Index of the first array element shown (for array headers)
+    first: u64,
+}
+
+// Node of the output tree written by export()
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ExportNode {
+    Header {
+        title: String,
+        text: String,
+        children: Vec<ExportNode>,
+    },
+    Field {
+        name: String,
+        value: String,
+        text: String,
+        offset: u64,
+        length: u64,
+        // Hex dump of the field's bytes (left out if there are too many, or
+        // if they are not in the file)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bits: Option<ExportBits>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        link: Option<u64>,
+    },
+    Check {
+        name: String,
+        text: String,
+        offset: u64,
+        length: u64,
+        passed: bool,
+    },
+}
+
+#[derive(Serialize)]
+struct ExportBits {
+    first: u64,
+    width: u64,
+}
+
+// A field's value pointing somewhere in the file
+#[derive(Clone)]
+pub struct Link {
+    pub target: u64,
+    pub target_struct: Option<String>,
+}
+
+#[derive(Clone)]
+enum FieldKind {
+    Int { signed: bool, base: usize },
+    Float,
+    Str { encoding: Encoding, null_terminated: bool },
+    Guid { mixed_endian: bool },
+    Bytes,
+    // Encoded as by the variable-length integer loads (their subfunction)
+    Varint { encoding: u8, base: usize },
+}
+
+#[derive(Clone)]
+enum LineContent {
+    Nothing,
+    Header { path: Vec<String> },
+    // Shows the previous or next page of an array's elements when clicked
+    Page { path: Vec<String>, forward: bool },
+    Data {
+        loc: u64,
+        length: u64,
+        name: String,
+        value: String,
+        kind: FieldKind,
+        big_endian: bool,
+        link: Option<Link>,
+        // First bit and width of a bit field within the integer at @loc
+        bits: Option<(u64, u64)>,
+    },
+    // Result of a check on the given file range
+    Check { loc: u64, length: u64, name: String, passed: bool },
+}
+
+struct OutputLine {
+    text: String,
+    color: Option<Color>,
+    content: LineContent,
+    // Whether the check (or checksum) shown passed, for validation
+    check: Option<bool>,
+}
+
+// Execution state of a struct's byte code
+struct Machine {
+    loc: u64,
+    pc: usize,
+    executed: u64,
+    file_be: bool,
+
+    stack: Vec<u64>,
+    fstack: Vec<f64>,
+    sstack: Vec<String>,
+
+    wram: Vec<u64>,
+
+    current_header_path: Vec<String>,
+    last_output_was_not_header: bool,
+
+    // Header path and shown element range of the arrays being output
+    arrays: Vec<(Vec<String>, u64, u64)>,
+
+    // File range accessed by the last instruction
+    last_access: Option<(u64, u64)>,
+
+    // Start of the current run, for the time limit (None for no limit)
+    start_time: Option<Instant>,
+
+    // Whether $LOC has been used, and all file ranges accessed so far
+    uses_loc: bool,
+    reads: Vec<(u64, u64)>,
+
+    // Ignore folded headers and array pages, for exporting everything
+    unfold: bool,
+}
+
+// What the current output depends on; as long as none of that changes, the
+// struct does not need to be executed again
+struct OutputDeps {
+    // None if the output does not depend on $LOC
+    loc: Option<u64>,
+    reads: Vec<(u64, u64)>,
+}
+
+struct DebugSession {
+    machine: Machine,
+    finished: bool,
+}
+
+pub enum DebugStop {
+    Stepped,
+    Breakpoint,
+    Finished,
+}
+
+// Parsed detection rules (see CfgStructDetect)
+struct DetectRules {
+    magic: Option<(u64, Vec<u8>)>,
+    names: Vec<String>,
+    min_size: Option<u64>,
+}
+
+// A struct whose detection rules match a file
+pub struct DetectMatch {
+    pub index: usize,
+    pub score: u64,
+    // Which rules matched
+    pub reasons: Vec<&'static str>,
+}
+
+// A struct from the configuration that could not be loaded
+pub struct LoadError {
+    pub name: String,
+    pub path: String,
+    pub error: String,
+    cfg: CfgEntryStruct,
+}
+
+impl LoadError {
+    fn new(name: &str, cs: &CfgEntryStruct, error: String) -> Self {
+        LoadError {
+            name: name.to_string(),
+            path: Struct::full_path(cs).unwrap_or_else(|_| cs.path.clone()),
+            error,
+            cfg: cs.clone(),
+        }
+    }
+}
+
+pub struct Struct {
+    name: String,
+    cfg: CfgEntryStruct,
+    // Full path of the definition, its size in bytes, and its modification
+    // time when it was loaded
+    path: String,
+    size: u64,
+    mtime: Option<SystemTime>,
+    // When mtime was last compared against the file's
+    mtime_checked: Instant,
+    // Set when reloading the definition failed (and this is still the old
+    // version)
+    reload_error: Option<String>,
+    code: StructCode,
+    // Set for structs written as scripts (whose byte code is empty)
+    script: Option<Script>,
+    detect: Option<DetectRules>,
+    headers: HashMap<Vec<String>, Header>,
+    lines: Vec<OutputLine>,
+    // Index of the first output line shown in the struct pane
+    scroll: usize,
+    cache: Option<OutputDeps>,
+
+    debug: Option<DebugSession>,
+    pc_breakpoints: Vec<usize>,
+    file_breakpoints: Vec<u64>,
+}
+
+pub struct Structs {
+    list: Vec<Struct>,
+    load_errors: Vec<LoadError>,
+    limits: CfgStructLimits,
+}
+
+// Number of lines at the bottom of the struct pane used for showing the VM
+// state while debugging
+const DEBUG_PANEL_HEIGHT: usize = 8;
+
+// Number of array elements shown at once
+const ARRAY_PAGE_SIZE: u64 = 32;
+
+// Maximum number of bytes shown by ohex (the rest is elided)
+const HEXDUMP_LENGTH: usize = 16;
+
+// A field shown in the struct pane
+pub struct FieldInfo {
+    pub line: usize,
+    pub name: String,
+    pub value: String,
+    pub link: Option<Link>,
+    // First and last bit of a bit field
+    pub bits: Option<(u64, u64)>,
+}
+
+// File range of a field in the output, with the level of its header
+#[derive(PartialEq)]
+pub struct FieldRange {
+    pub start: u64,
+    pub length: u64,
+    pub level: usize,
+}
+
+pub struct MouseDownResult {
+    pub need_update: bool,
+    pub highlight: Option<(u64, u64)>,
+    pub link: Option<Link>,
+}
+
+// Comparison of a field's value for searches
+#[derive(Clone, Copy)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // Any of the given bits set
+    And,
+}
+
+
+impl Structs {
+    /*
+     * Loads all structs from the configuration.  Structs that fail to load are
+     * remembered (see load_errors()) instead of failing as a whole.
+     */
+    pub fn load(cfg: &ConfigFile) -> Self {
+        let mut structs = Vec::<Struct>::new();
+        let mut errors = Vec::<LoadError>::new();
+
+        for (name, cs) in cfg.get_structs().iter() {
+            match Struct::load(name, cs) {
+                Ok(s)   => structs.push(s),
+                Err(e)  => errors.push(LoadError::new(name, cs, e))
+            }
+        }
+
+        structs.sort_by(|a, b| a.name.cmp(&b.name));
+        errors.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Structs {
+            list: structs,
+            load_errors: errors,
+            limits: cfg.get_struct_limits().clone(),
+        }
+    }
+
+    /*
+     * Adds a struct, returning its index.  If it cannot be loaded, it is
+     * recorded in load_errors() and the error is returned.
+     */
+    pub fn add(&mut self, name: &str, cs: &CfgEntryStruct)
+        -> Result<usize, String>
+    {
+        match Struct::load(name, cs) {
+            Ok(s)   => {
+                self.list.push(s);
+                Ok(self.list.len() - 1)
+            },
+            Err(e)  => {
+                self.load_errors.push(LoadError::new(name, cs, e.clone()));
+                Err(e)
+            }
+        }
+    }
+
+    // Removes a struct; indices of the structs after it shift down by one
+    pub fn remove(&mut self, i: usize) {
+        self.list.remove(i);
+    }
+
+    // Removes a struct that could not be loaded
+    pub fn remove_failed(&mut self, name: &str) -> bool {
+        let len = self.load_errors.len();
+        self.load_errors.retain(|e| e.name != name);
+        self.load_errors.len() != len
+    }
+
+    /*
+     * Loads a struct's definition again.  If that fails, the old version is
+     * kept.  Fold state, scroll position and breakpoints are kept either way;
+     * a debug session is restarted (at the same $LOC) with the new code.
+     */
+    pub fn reload(&mut self, i: usize) -> Result<(), String> {
+        let old = &mut self.list[i];
+
+        let mut new = match Struct::load(&old.name, &old.cfg) {
+            Ok(s)   => s,
+            Err(e)  => {
+                // Do not try again until the file is modified again
+                old.mtime = Struct::file_mtime(&old.path);
+                old.reload_error = Some(e.clone());
+                return Err(e);
+            }
+        };
+
+        new.headers = std::mem::take(&mut old.headers);
+        new.pc_breakpoints = std::mem::take(&mut old.pc_breakpoints);
+        new.file_breakpoints = std::mem::take(&mut old.file_breakpoints);
+        new.scroll = old.scroll;
+        if let Some(loc) = old.debug_stop() {
+            new.debug_start(loc);
+        }
+        *old = new;
+        Ok(())
+    }
+
+    /*
+     * Reloads a struct if its file has been modified since it was loaded.
+     * To keep this cheap enough to call on every update, the file is checked
+     * at most once per second.
+     */
+    pub fn reload_if_modified(&mut self, i: usize) -> Result<bool, String> {
+        let s = &mut self.list[i];
+        if s.mtime_checked.elapsed() < Duration::from_secs(1) {
+            return Ok(false);
+        }
+        s.mtime_checked = Instant::now();
+        if Struct::file_mtime(&s.path) == s.mtime {
+            return Ok(false);
+        }
+
+        self.reload(i)?;
+        Ok(true)
+    }
+
+    /*
+     * Tries to load the structs in load_errors() again.  Returns the indices
+     * of the structs that could be loaded now.
+     */
+    pub fn retry_failed(&mut self) -> Vec<usize> {
+        let failed = std::mem::take(&mut self.load_errors);
+        let mut loaded = Vec::new();
+
+        for f in failed {
+            if let Ok(i) = self.add(&f.name, &f.cfg) {
+                loaded.push(i);
+            }
+        }
+        loaded
+    }
+
+    pub fn load_errors(&self) -> &Vec<LoadError> {
+        &self.load_errors
+    }
+
+    pub fn get_limits(&self) -> &CfgStructLimits {
+        &self.limits
+    }
+
+    // Must be called whenever the file is modified
+    pub fn file_modified(&mut self, offset: u64, length: u64) {
+        for s in self.list.iter_mut() {
+            s.file_modified(offset, length);
+        }
+    }
+
+    /*
+     * Returns all structs whose detection rules match the given file, best
+     * match first.  Magic bytes weigh most (the longer, the better), then file
+     * names, then the minimum size.
+     */
+    pub fn detect(&self, file: &mut File) -> Vec<DetectMatch> {
+        let mut matches = Vec::new();
+
+        for (i, s) in self.list.iter().enumerate() {
+            if let Some(ref rules) = s.detect {
+                if let Some((score, reasons)) = rules.check(file) {
+                    matches.push(DetectMatch {
+                        index: i,
+                        score,
+                        reasons,
+                    });
+                }
+            }
+        }
+
+        matches.sort_by(|a, b| {
+            b.score.cmp(&a.score)
+                   .then_with(|| self.list[a.index].name
+                                     .cmp(&self.list[b.index].name))
+        });
+        matches
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.list.iter().position(|s| s.name == name)
+    }
+
+    // Like find(), but with an error saying why a struct is not there
+    pub fn lookup(&self, name: &str) -> Result<usize, String> {
+        if let Some(i) = self.find(name) {
+            return Ok(i);
+        }
+
+        match self.load_errors.iter().find(|e| e.name == name) {
+            Some(e) => Err(format!("{}: {}", e.path, e.error)),
+            None    => Err(format!("Unknown struct “{}”", name))
+        }
+    }
+
+    pub fn get(&self, i: usize) -> &Struct {
+        &self.list[i]
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> &mut Struct {
+        &mut self.list[i]
+    }
+}
+
+
+impl DetectRules {
+    fn new(cfg: &CfgStructDetect) -> Result<Self, String> {
+        let magic = match cfg.magic {
+            Some(ref hex) => {
+                let digits: Vec<char> =
+                    hex.chars().filter(|c| !c.is_whitespace()).collect();
+                if digits.is_empty() || !digits.len().is_multiple_of(2) {
+                    return Err(format!("Invalid magic “{}”", hex));
+                }
+
+                let mut bytes = Vec::new();
+                for pair in digits.chunks(2) {
+                    let byte: String = pair.iter().collect();
+                    match u8::from_str_radix(&byte, 16) {
+                        Ok(b)   => bytes.push(b),
+                        Err(_)  => return Err(format!("Invalid magic “{}”",
+                                                      hex))
+                    }
+                }
+                Some((cfg.magic_offset, bytes))
+            },
+            None => None
+        };
+
+        if magic.is_none() && cfg.names.is_empty() && cfg.min_size.is_none() {
+            return Err(String::from("Detection rules are empty"));
+        }
+
+        Ok(DetectRules {
+            magic,
+            names: cfg.names.clone(),
+            min_size: cfg.min_size,
+        })
+    }
+
+    // Returns the score and the matched rules if all rules match
+    fn check(&self, file: &mut File) -> Option<(u64, Vec<&'static str>)> {
+        let mut score = 0;
+        let mut reasons = Vec::new();
+
+        if let Some(min_size) = self.min_size {
+            match file.len() {
+                Ok(len) if len >= min_size => (),
+                _ => return None
+            }
+            score += 1;
+            reasons.push("size");
+        }
+
+        if !self.names.is_empty() {
+            let path = file.get_filename().to_string();
+            let basename = match path.rfind('/') {
+                Some(i) => String::from(&path[i + 1..]),
+                None    => path.clone()
+            };
+
+            let matched = self.names.iter().any(|pattern| {
+                let subject = if pattern.contains('/') { &path }
+                              else { &basename };
+                glob_match(&pattern.chars().collect::<Vec<char>>(),
+                           &subject.chars().collect::<Vec<char>>())
+            });
+            if !matched {
+                return None;
+            }
+            score += 8;
+            reasons.push("name");
+        }
+
+        if let Some((offset, ref magic)) = self.magic {
+            let mut buf = vec![0; magic.len()];
+
+            // Files too short for the magic simply do not match
+            if file.read(offset, &mut buf).is_err() || buf != *magic {
+                return None;
+            }
+            score += 16 * magic.len() as u64;
+            reasons.push("magic");
+        }
+
+        Some((score, reasons))
+    }
+}
+
+
+// Matches a glob pattern with * (any sequence) and ? (any character)
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None        => text.is_empty(),
+        Some(&'*')  => (0..text.len() + 1).any(|i| glob_match(&pattern[1..],
+                                                              &text[i..])),
+        Some(&'?')  => !text.is_empty() && glob_match(&pattern[1..],
+                                                      &text[1..]),
+        Some(c)     => text.first() == Some(c) &&
+                       glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+// Mask of @width bits starting at bit @first of a 64-bit integer
+fn bit_mask(first: u64, width: u64) -> Result<u64, String> {
+    if width == 0 || first + width > 64 {
+        return Err(format!("Invalid bit range {}+{}", first, width));
+    }
+
+    let ones = if width == 64 { !0u64 } else { (1u64 << width) - 1 };
+    Ok(ones << first)
+}
+
+/*
+ * Returns the offset and length of the bytes of a @length-byte integer at
+ * @loc that hold its bits @first to @first + @width - 1, and the bit within
+ * the first of those bytes (in value order) where the range starts.
+ */
+fn bit_span(loc: u64, length: u64, big_endian: bool, first: u64, width: u64)
+    -> (u64, u64, u64)
+{
+    let first_byte = first / 8;
+    let last_byte = (first + width - 1) / 8;
+    let span = last_byte - first_byte + 1;
+
+    if big_endian {
+        (loc + length - 1 - last_byte, span, first % 8)
+    } else {
+        (loc + first_byte, span, first % 8)
+    }
+}
+
+/*
+ * Encodes @val as a variable-length integer (in the given encoding, as
+ * numbered by the load instructions) of exactly @length bytes, padding it as
+ * necessary.  Returns None if it does not fit.
+ */
+fn encode_varint(encoding: u8, val: u64, length: u64) -> Option<Vec<u8>> {
+    // 7-bit groups, least significant first
+    let groups = |mut x: u64, signed: bool| -> Option<Vec<u8>> {
+        let negative = signed && (x as i64) < 0;
+        let mut bytes = Vec::new();
+        for _ in 0..length {
+            bytes.push((x & 0x7f) as u8);
+            x = if negative { ((x as i64) >> 7) as u64 } else { x >> 7 };
+        }
+
+        let rest = if negative { !0u64 } else { 0 };
+        let sign_ok = match bytes.last() {
+            Some(b) => !signed || (b & 0x40 != 0) == negative,
+            None    => false
+        };
+        if x != rest || !sign_ok {
+            return None;
+        }
+        Some(bytes)
+    };
+
+    let mut bytes = match encoding {
+        0x00 => groups(val, false)?,
+        0x01 => groups(val, true)?,
+        0x02 => groups((val << 1) ^ (((val as i64) >> 63) as u64), false)?,
+
+        0x03 => {
+            let mut bytes = groups(val, false)?;
+            bytes.reverse();
+            let last = bytes.len() - 1;
+            for b in &mut bytes[..last] {
+                *b |= 0x80;
+            }
+            return Some(bytes);
+        },
+
+        0x04 => {
+            let mut x = val;
+            let mut bytes = Vec::new();
+            for _ in 0..length {
+                bytes.push(((((x / 10) % 10) << 4) | (x % 10)) as u8);
+                x /= 100;
+            }
+            if x != 0 {
+                return None;
+            }
+            bytes.reverse();
+            return Some(bytes);
+        },
+
+        _ => return None
+    };
+
+    // LEB128: all but the last byte have the continuation bit set
+    let last = bytes.len() - 1;
+    for b in &mut bytes[..last] {
+        *b |= 0x80;
+    }
+    Some(bytes)
+}
+
+/*
+ * Decodes the variable-length integer (LEB128, zigzag or VLQ, numbered as by
+ * the load instructions) at @offset, reading its bytes through @read.
+ * Returns its value and its length in bytes.
+ */
+fn decode_varint<R>(encoding: u8, mut read: R, offset: u64)
+    -> Result<(u64, u64), String>
+    where R: FnMut(u64) -> Result<u8, String>
+{
+    let signed = encoding == 0x01;
+    let what = if encoding == 0x03 { "VLQ" } else { "LEB128 number" };
+    let mut val = 0u64;
+    let mut len = 0u64;
+
+    loop {
+        if len == 10 {
+            return Err(format!("{} at {:#x} is too long", what, offset));
+        }
+        let b = read(offset + len)?;
+
+        if encoding == 0x03 {
+            if val >> 57 != 0 {
+                return Err(format!("{} at {:#x} exceeds 64 bits", what,
+                                   offset));
+            }
+            val = (val << 7) | (b & 0x7f) as u64;
+        } else {
+            // Only bit 63 is left for the tenth byte; for signed numbers, the
+            // bits beyond it must repeat the sign
+            if len == 9 {
+                let fits = if signed {
+                    b & 0x7f == 0x00 || b & 0x7f == 0x7f
+                } else {
+                    b & 0x7e == 0
+                };
+                if !fits {
+                    return Err(format!("{} at {:#x} exceeds 64 bits", what,
+                                       offset));
+                }
+            }
+            val |= ((b & 0x7f) as u64) << (len * 7);
+        }
+        len += 1;
+
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let val = match encoding {
+        0x01 if len * 7 < 64 => {
+            let shift = 64 - len * 7;
+            (((val << shift) as i64) >> shift) as u64
+        },
+        0x02 => (val >> 1) ^ (val & 1).wrapping_neg(),
+        _ => val
+    };
+    Ok((val, len))
+}
+
+/*
+ * Decodes the @length-byte packed BCD number at @offset, reading its bytes
+ * through @read.
+ */
+fn decode_bcd<R>(mut read: R, offset: u64, length: u64) -> Result<u64, String>
+    where R: FnMut(u64) -> Result<u8, String>
+{
+    if length > 10 {
+        return Err(format!("BCD numbers can have at most 10 bytes, not {}",
+                           length));
+    }
+
+    let mut val = 0u64;
+    for i in 0..length {
+        let b = read(offset + i)?;
+        for &digit in [b >> 4, b & 0xf].iter() {
+            if digit > 9 {
+                return Err(format!("Invalid BCD digit at {:#x}", offset + i));
+            }
+            val = match val.checked_mul(10)
+                           .and_then(|v| v.checked_add(digit as u64))
+            {
+                Some(v) => v,
+                None    => return Err(format!("BCD number at {:#x} exceeds \
+                                               64 bits", offset))
+            };
+        }
+    }
+    Ok(val)
+}
+
+// Moves all headers of @level or deeper into their parents (or @top)
+fn close_export_headers(open: &mut Vec<(usize, ExportNode)>,
+                        top: &mut Vec<ExportNode>, level: usize)
+{
+    while matches!(open.last(), Some(&(l, _)) if l >= level) {
+        let (_, node) = open.pop().unwrap();
+        match open.last_mut() {
+            Some(&mut (_, ExportNode::Header { ref mut children, .. })) =>
+                children.push(node),
+            _ => top.push(node)
+        }
+    }
+}
+
+// Parses a number as output for integer fields (with an optional base prefix)
+fn parse_output_int(string: &str) -> Option<i128> {
+    let string = string.trim();
+    let (neg, digits) = match string.strip_prefix('-') {
+        Some(rest)  => (true, rest),
+        None        => (false, string)
+    };
+
+    let (base, digits) = if let Some(rest) = digits.strip_prefix("0x") {
+        (16, rest)
+    } else if let Some(rest) = digits.strip_prefix("0o") {
+        (8, rest)
+    } else if let Some(rest) = digits.strip_prefix("0b") {
+        (2, rest)
+    } else if let Some(rest) = digits.strip_prefix("0[") {
+        let end = rest.find(']')?;
+        (rest[..end].parse::<u32>().ok()?, &rest[end + 1..])
+    } else {
+        (10, digits)
+    };
+    if !(2..=36).contains(&base) {
+        return None;
+    }
+
+    let val = u64::from_str_radix(digits, base).ok()? as i128;
+    Some(if neg { -val } else { val })
+}
+
+
+impl CompareOp {
+    pub fn parse(op: &str) -> Option<Self> {
+        Some(match op {
+            "==" | "="  => CompareOp::Eq,
+            "!="        => CompareOp::Ne,
+            "<"         => CompareOp::Lt,
+            "<="        => CompareOp::Le,
+            ">"         => CompareOp::Gt,
+            ">="        => CompareOp::Ge,
+            "&"         => CompareOp::And,
+            _ => return None
+        })
+    }
+
+    /*
+     * Compares a field's @value to @operand: as integers if both are, else as
+     * floats if both are, else as strings (equality ignoring case).
+     */
+    pub fn test(self, value: &str, operand: &str) -> Result<bool, String> {
+        let ord = match (parse_output_int(value), parse_output_int(operand)) {
+            (Some(a), Some(b)) => {
+                if let CompareOp::And = self {
+                    return Ok(a & b != 0);
+                }
+                a.cmp(&b)
+            },
+
+            _ => {
+                if let CompareOp::And = self {
+                    return Err(format!("{} is not an integer", operand));
+                }
+
+                match (value.trim().parse::<f64>(), operand.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => match a.partial_cmp(&b) {
+                        Some(ord)   => ord,
+                        None        => return Ok(false)
+                    },
+
+                    _ => match self {
+                        CompareOp::Eq =>
+                            return Ok(value.eq_ignore_ascii_case(operand)),
+                        CompareOp::Ne =>
+                            return Ok(!value.eq_ignore_ascii_case(operand)),
+                        _ => value.cmp(operand)
+                    }
+                }
+            }
+        };
+
+        Ok(match self {
+            CompareOp::Eq   => ord == std::cmp::Ordering::Equal,
+            CompareOp::Ne   => ord != std::cmp::Ordering::Equal,
+            CompareOp::Lt   => ord == std::cmp::Ordering::Less,
+            CompareOp::Le   => ord != std::cmp::Ordering::Greater,
+            CompareOp::Gt   => ord == std::cmp::Ordering::Greater,
+            CompareOp::Ge   => ord != std::cmp::Ordering::Less,
+            CompareOp::And  => false
+        })
+    }
 }
 
-#[derive(Clone)]
-enum LineContent {
-    Nothing,
-    Header { path: Vec<String> },
-    Data { loc: u64, length: u64 },
-}
 
-pub struct Struct {
-    name: String,
-    code: StructCode,
-    headers: HashMap<Vec<String>, Header>,
-    lines: Vec<LineContent>,
-}
+impl Machine {
+    fn new(loc: u64) -> Self {
+        Machine {
+            loc,
+            pc: 0,
+            executed: 0,
+            file_be: false,
+
+            stack: Vec::new(),
+            fstack: Vec::new(),
+            sstack: Vec::new(),
+
+            wram: Vec::new(),
+
+            current_header_path: Vec::new(),
+            last_output_was_not_header: false,
+            arrays: Vec::new(),
+
+            last_access: None,
+            start_time: None,
+
+            uses_loc: false,
+            reads: Vec::new(),
+
+            unfold: false,
+        }
+    }
+
+    fn add_read(&mut self, offset: u64, length: u64) {
+        let end = offset.saturating_add(length);
+
+        // Most reads are sequential, so this keeps the list short
+        if let Some(last) = self.reads.last_mut() {
+            let last_end = last.0.saturating_add(last.1);
+            if offset <= last_end && end >= last.0 {
+                let start = std::cmp::min(last.0, offset);
+                *last = (start, std::cmp::max(last_end, end) - start);
+                return;
+            }
+        }
+        self.reads.push((offset, length));
+    }
+}
+
+
+impl Struct {
+    fn load(name: &str, cs: &CfgEntryStruct) -> Result<Self, String> {
+        Self::load_from(name, cs, &Self::full_path(cs)?)
+    }
+
+    // The definition's path is relative to the configuration directory
+    fn full_path(cs: &CfgEntryStruct) -> Result<String, String> {
+        let mut path = config::base_dir()?;
+        path.push(cs.path.clone());
+        Ok(path.as_path().to_string_lossy().into_owned())
+    }
+
+    fn load_from(name: &str, cs: &CfgEntryStruct, path: &str)
+        -> Result<Self, String>
+    {
+        // Taken before reading, so a concurrent modification is not missed
+        let mtime = Self::file_mtime(path);
+
+        let mut file = File::new(path.to_string())?;
+        let len = file.len()?;
+
+        let mut buffer = vec![0; len as usize];
+
+        file.read(0, &mut buffer)?;
+
+        // Declarative definitions are compiled to byte code right here, so
+        // both kinds look the same from here on
+        if cs.path.ends_with(".json") {
+            buffer = struct_def::compile_json(&buffer)?;
+        } else if cs.path.ends_with(".toml") {
+            buffer = struct_def::compile_toml(&buffer)?;
+        }
+
+        let script = if cs.path.ends_with(".rhai") {
+            let script = Script::compile(&buffer)?;
+            buffer = Vec::new();
+            Some(script)
+        } else {
+            None
+        };
+
+        let detect = match cs.detect {
+            Some(ref d) => Some(DetectRules::new(d)?),
+            None        => None
+        };
+
+        Ok(Struct {
+            name: name.to_string(),
+            cfg: cs.clone(),
+            path: path.to_string(),
+            size: len,
+            mtime,
+            mtime_checked: Instant::now(),
+            reload_error: None,
+            code: StructCode {
+                buffer,
+            },
+            script,
+            detect,
+            headers: HashMap::new(),
+            lines: Vec::new(),
+            scroll: 0,
+            cache: None,
+
+            debug: None,
+            pc_breakpoints: Vec::new(),
+            file_breakpoints: Vec::new(),
+        })
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.name.as_ref()
+    }
+
+    pub fn get_path(&self) -> &str {
+        self.path.as_ref()
+    }
+
+    pub fn is_script(&self) -> bool {
+        self.script.is_some()
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    // Paths of all folded headers
+    pub fn get_folded(&self) -> Vec<Vec<String>> {
+        let mut folded: Vec<Vec<String>> =
+            self.headers.iter().filter(|&(_, h)| h.folded)
+                        .map(|(p, _)| p.clone()).collect();
+        folded.sort();
+        folded
+    }
+
+    pub fn get_scroll(&self) -> usize {
+        self.scroll
+    }
+
+    // Restores what get_folded() and get_scroll() returned earlier
+    pub fn restore_view(&mut self, folded: &[Vec<String>], scroll: usize) {
+        for path in folded.iter() {
+            self.lookup_header_mut(path).folded = true;
+        }
+        self.scroll = scroll;
+        self.cache = None;
+    }
+
+    pub fn get_reload_error(&self) -> Option<&String> {
+        self.reload_error.as_ref()
+    }
+
+    fn file_mtime(path: &str) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn get_code_size(&self) -> usize {
+        self.code.buffer.len()
+    }
+
+    // The description embedded with desc as the first instruction, if any
+    pub fn get_description(&self) -> Option<String> {
+        if self.code.buffer.first() != Some(&0x35) {
+            return None;
+        }
+
+        let len = self.load_constant_u64(1).ok()?;
+        self.load_constant_utf8_string(9, Some(len)).ok().map(|(s, _)| s)
+    }
+
+    /*
+     * Executes the struct for the given $LOC; the output can then be shown
+     * with draw().  Does nothing if the last output is still valid.
+     */
+    pub fn update(&mut self, file: &mut File, loc: u64,
+                  limits: &CfgStructLimits)
+        -> Result<(), String>
+    {
+        if let Some(ref deps) = self.cache {
+            if deps.loc.is_none() || deps.loc == Some(loc) {
+                return Ok(());
+            }
+        }
+
+        let mut m = Machine::new(loc);
+        m.start_time = Some(Instant::now());
+
+        self.lines.clear();
+        self.cache = None;
+
+        self.run(&mut m, file, limits)?;
+
+        self.cache = Some(OutputDeps {
+            loc: if m.uses_loc { Some(loc) } else { None },
+            reads: m.reads,
+        });
+
+        Ok(())
+    }
+
+    /*
+     * Executes the struct (its byte code, or its script) until it is done or
+     * the output is full.  Byte code has a time limit only if m.start_time is
+     * set.
+     */
+    fn run(&mut self, m: &mut Machine, file: &mut File,
+           limits: &CfgStructLimits)
+        -> Result<(), String>
+    {
+        let (output, res) = match self.script {
+            Some(ref mut script) => script.run_struct(file, m.loc, limits),
+            None => {
+                while self.checked_step(m, file, limits.max_output_lines,
+                                        limits)?
+                {
+                }
+                return Ok(());
+            }
+        };
+
+        m.uses_loc = output.uses_loc;
+        for (offset, length) in output.reads {
+            m.add_read(offset, length);
+        }
+        self.output_script_lines(m, limits.max_output_lines, output.lines)?;
+        res
+    }
+
+    // Outputs what a script has described, like the byte code would
+    fn output_script_lines(&mut self, m: &mut Machine, max_lines: usize,
+                           lines: Vec<ScriptLine>)
+        -> Result<(), String>
+    {
+        // Level of the folded header whose contents are being skipped
+        let mut folded_level = None;
+
+        for line in lines {
+            if let ScriptLine::Header { level, title } = line {
+                match folded_level {
+                    Some(l) if level > l => continue,
+                    _ => folded_level = None
+                }
+
+                m.current_header_path.truncate(level as usize);
+                m.current_header_path.push(title.clone());
+
+                match self.output_header(m, max_lines, level, title) {
+                    Some(true)  => folded_level = Some(level),
+                    Some(false) => (),
+                    None        => return Ok(())
+                }
+                continue;
+            }
+            if folded_level.is_some() {
+                continue;
+            }
+
+            let more = match line {
+                ScriptLine::Header { .. } => true,
+
+                ScriptLine::Int { name, loc, length, value, signed, base,
+                                  big_endian } =>
+                {
+                    let string = self.format_int(value, signed, base)?;
+                    self.output_line(max_lines,
+                                     format!("{}: {}", name, string),
+                                     LineContent::Data {
+                                         loc,
+                                         length,
+                                         name,
+                                         value: string,
+                                         kind: FieldKind::Int {
+                                             signed,
+                                             base,
+                                         },
+                                         big_endian,
+                                         link: None,
+                                         bits: None,
+                                     })
+                },
+
+                ScriptLine::Str { name, loc, length, value } =>
+                    self.output_line(max_lines,
+                                     format!("{}: {}", name, value),
+                                     LineContent::Data {
+                                         loc,
+                                         length,
+                                         name,
+                                         value,
+                                         kind: FieldKind::Str {
+                                             encoding: Encoding::Utf8,
+                                             null_terminated: false,
+                                         },
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     }),
+
+                ScriptLine::Bytes { name, loc, bytes, text } => {
+                    let text = text.unwrap_or_else(|| {
+                        let mut t = bytes.iter().take(HEXDUMP_LENGTH)
+                                         .map(|b| format!("{:02x}", b))
+                                         .collect::<Vec<String>>().join(" ");
+                        if bytes.len() > HEXDUMP_LENGTH {
+                            t.push_str(" …");
+                        }
+                        t
+                    });
+                    let hex = bytes.iter().map(|b| format!("{:02x}", b))
+                                   .collect::<Vec<String>>().join(" ");
+
+                    self.output_line(max_lines, format!("{}: {}", name, text),
+                                     LineContent::Data {
+                                         loc,
+                                         length: bytes.len() as u64,
+                                         name,
+                                         value: hex,
+                                         kind: FieldKind::Bytes,
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                },
+
+                ScriptLine::Check { name, loc, length, passed } => {
+                    let text = format!("{}: {}", name,
+                                       if passed { "ok" } else { "failed" });
+                    self.output_check_line(max_lines, text, passed,
+                                           LineContent::Check {
+                                               loc,
+                                               length,
+                                               name,
+                                               passed,
+                                           })
+                },
+            };
+
+            if !more {
+                return Ok(());
+            }
+            m.last_output_was_not_header = true;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Executes the struct with all headers unfolded and all array elements
+     * shown, and returns its output lines, along with the error that stopped
+     * it, if any.  The output shown otherwise is not affected.
+     */
+    fn evaluate(&mut self, file: &mut File, loc: u64,
+                limits: &CfgStructLimits)
+        -> (Vec<OutputLine>, Result<(), String>)
+    {
+        let shown = std::mem::take(&mut self.lines);
+
+        let mut m = Machine::new(loc);
+        m.unfold = true;
+        m.start_time = Some(Instant::now());
+        let res = self.run(&mut m, file, limits);
+
+        let lines = std::mem::replace(&mut self.lines, shown);
+        (lines, res)
+    }
+
+    /*
+     * Executes the struct like evaluate(), and returns the resulting tree of
+     * headers and fields as JSON.
+     */
+    pub fn export(&mut self, file: &mut File, loc: u64,
+                  limits: &CfgStructLimits)
+        -> Result<String, String>
+    {
+        let (lines, res) = self.evaluate(file, loc, limits);
+        res?;
+        let file_len = file.len()?;
+
+        // Headers whose children are still being collected, with their level
+        let mut open: Vec<(usize, ExportNode)> = Vec::new();
+        let mut top = Vec::new();
+
+        for line in lines {
+            let node = match line.content {
+                LineContent::Header { path } => {
+                    let level = path.len();
+                    close_export_headers(&mut open, &mut top, level);
+                    open.push((level, ExportNode::Header {
+                        title: path.last().cloned().unwrap_or_default(),
+                        text: line.text,
+                        children: Vec::new(),
+                    }));
+                    continue;
+                },
+
+                LineContent::Data { loc, length, name, value, bits, link,
+                                    .. } =>
+                {
+                    let in_file = length <= file_len &&
+                                  loc <= file_len - length;
+                    let bytes = if in_file &&
+                                   length <= limits.max_string_length as u64
+                    {
+                        let mut bytes = vec![0u8; length as usize];
+                        file.read(loc, &mut bytes)?;
+                        Some(bytes.iter().map(|b| format!("{:02x}", b))
+                                  .collect())
+                    } else {
+                        None
+                    };
+
+                    ExportNode::Field {
+                        name,
+                        value,
+                        text: line.text,
+                        offset: loc,
+                        length,
+                        bytes,
+                        bits: bits.map(|(first, width)| ExportBits {
+                            first,
+                            width,
+                        }),
+                        link: link.map(|l| l.target),
+                    }
+                },
+
+                LineContent::Check { loc, length, name, passed } =>
+                    ExportNode::Check {
+                        name,
+                        text: line.text,
+                        offset: loc,
+                        length,
+                        passed,
+                    },
+
+                LineContent::Nothing | LineContent::Page { .. } => continue
+            };
+
+            match open.last_mut() {
+                Some(&mut (_, ExportNode::Header { ref mut children, .. })) =>
+                    children.push(node),
+                _ => top.push(node)
+            }
+        }
+        close_export_headers(&mut open, &mut top, 0);
+
+        match serde_json::to_string_pretty(&top) {
+            Ok(json)    => Ok(json),
+            Err(e)      => Err(format!("Failed to encode JSON: {}", e))
+        }
+    }
+
+    /*
+     * Number of bytes from @loc to the end of the last field at or after it,
+     * i.e. the size of one record when the struct describes a table entry
+     */
+    pub fn extent(&mut self, file: &mut File, loc: u64,
+                  limits: &CfgStructLimits)
+        -> Result<u64, String>
+    {
+        let (lines, res) = self.evaluate(file, loc, limits);
+        res?;
+        Ok(lines.iter().filter_map(|l| match l.content {
+            LineContent::Data { loc: fl, length, .. } if fl >= loc =>
+                Some(fl.saturating_add(length) - loc),
+            _ => None
+        }).max().unwrap_or(0))
+    }
+
+    /*
+     * Executes the struct at @loc and returns the first field whose path
+     * matches @path and whose value compares to @value as given by @op, as
+     * its name and value.  @path is the field name, optionally preceded by
+     * (the last few of) its headers' titles, separated by dots.
+     */
+    pub fn find_match(&mut self, file: &mut File, loc: u64,
+                      limits: &CfgStructLimits, path: &str, op: CompareOp,
+                      value: &str)
+        -> Result<Option<(String, String)>, String>
+    {
+        let (lines, res) = self.evaluate(file, loc, limits);
+        res?;
+        let mut headers: Vec<String> = Vec::new();
+
+        for line in lines {
+            match line.content {
+                LineContent::Header { path } => headers = path,
+
+                LineContent::Data { name, value: field_value, .. } => {
+                    let mut full = headers.clone();
+                    full.push(name.clone());
+                    let full = full.join(".");
+
+                    let matches_path =
+                        full == path || full.ends_with(&format!(".{}", path));
+                    if matches_path && op.test(&field_value, value)? {
+                        return Ok(Some((name, field_value)));
+                    }
+                },
+
+                _ => ()
+            }
+        }
+
+        Ok(None)
+    }
+
+    /*
+     * Executes the struct like evaluate() and returns the number of checks
+     * (including checksums) in its output, and a line for every one that
+     * failed, with its file range and headers.  An error stopping the struct
+     * is reported as a failure, too.
+     */
+    pub fn validate(&mut self, file: &mut File, loc: u64,
+                    limits: &CfgStructLimits)
+        -> (usize, Vec<String>)
+    {
+        let (lines, res) = self.evaluate(file, loc, limits);
+        let mut headers: Vec<String> = Vec::new();
+        let mut checks = 0;
+        let mut failures = Vec::new();
+
+        for line in lines {
+            let range = match line.content {
+                LineContent::Header { path } => {
+                    headers = path;
+                    continue;
+                },
+
+                LineContent::Check { loc, length, .. } |
+                LineContent::Data { loc, length, .. } => (loc, length),
+                _ => continue
+            };
+
+            if let Some(passed) = line.check {
+                checks += 1;
+                if passed {
+                    continue;
+                }
+
+                let range = format!("{:#x}+{:#x}", range.0, range.1);
+                failures.push(if headers.is_empty() {
+                    format!("{:20}{}", range, line.text)
+                } else {
+                    format!("{:20}{}: {}", range, headers.join("."),
+                            line.text)
+                });
+            }
+        }
+
+        if let Err(e) = res {
+            failures.push(format!("{:20}Struct failed: {}", "", e));
+        }
+
+        (checks, failures)
+    }
+
+    // Drops the cached output if it depends on the given file range
+    fn file_modified(&mut self, offset: u64, length: u64) {
+        let affected = match self.cache {
+            Some(ref deps) => deps.reads.iter().any(|&(o, l)| {
+                offset < o.saturating_add(l) &&
+                o < offset.saturating_add(length)
+            }),
+            None => false
+        };
+
+        if affected {
+            self.cache = None;
+        }
+    }
+
+    /*
+     * Executes a single instruction like step(), but enforces the execution
+     * limits (the time limit only if @m has a start time) and puts the
+     * faulting PC into error messages.
+     */
+    fn checked_step(&mut self, m: &mut Machine, file: &mut File,
+                    max_lines: usize, limits: &CfgStructLimits)
+        -> Result<bool, String>
+    {
+        if m.executed >= limits.max_instructions {
+            return Err(format!("pc {:#x}: Instruction limit ({}) exceeded",
+                               m.pc, limits.max_instructions));
+        }
+        // Checking the time is not free, so do it only every now and then
+        if let Some(st) = m.start_time {
+            if m.executed.is_multiple_of(1024) &&
+               st.elapsed() > Duration::from_millis(limits.max_time_ms)
+            {
+                return Err(format!("pc {:#x}: Time limit ({} ms) exceeded",
+                                   m.pc, limits.max_time_ms));
+            }
+        }
+
+        let insn_pc = m.pc;
+        let cont = match self.step(m, file, max_lines, limits) {
+            Ok(c)   => c,
+            Err(e)  => return Err(format!("pc {:#x}: {}", insn_pc, e))
+        };
+
+        if let Some((ofs, len)) = m.last_access {
+            m.add_read(ofs, len);
+        }
+
+        if m.stack.len() > limits.max_stack ||
+           m.fstack.len() > limits.max_stack ||
+           m.sstack.len() > limits.max_stack
+        {
+            return Err(format!("pc {:#x}: Stack overflow", insn_pc));
+        }
+
+        Ok(cont)
+    }
+
+    pub fn is_debugging(&self) -> bool {
+        self.debug.is_some()
+    }
+
+    pub fn debug_start(&mut self, loc: u64) {
+        self.debug = Some(DebugSession {
+            machine: Machine::new(loc),
+            finished: false,
+        });
+
+        self.lines.clear();
+        self.scroll = 0;
+        self.cache = None;
+    }
+
+    // Ends debugging and returns the $LOC the session was started with
+    pub fn debug_stop(&mut self) -> Option<u64> {
+        // The output is the debug session's
+        self.cache = None;
+
+        match self.debug.take() {
+            Some(s) => Some(s.machine.loc),
+            None    => None
+        }
+    }
+
+    // While debugging, the lower part of the struct area shows the VM state
+    pub fn debug_output_height(display: &mut Display) -> usize {
+        let height = display.h() as usize;
+        if height > DEBUG_PANEL_HEIGHT + 2 {
+            height - DEBUG_PANEL_HEIGHT - 2
+        } else {
+            0
+        }
+    }
+
+    /*
+     * Executes one instruction (@run == false) or runs until a breakpoint is
+     * hit or execution finishes (@run == true).
+     */
+    pub fn debug_step(&mut self, file: &mut File, limits: &CfgStructLimits,
+                      run: bool)
+        -> Result<DebugStop, String>
+    {
+        let mut session = match self.debug.take() {
+            Some(s) => s,
+            None    => return Err(String::from("Not debugging"))
+        };
+        if session.finished {
+            self.debug = Some(session);
+            return Err(String::from("Execution has finished"));
+        }
+        session.machine.start_time = if run {
+            Some(Instant::now())
+        } else {
+            None
+        };
+
+        let mut first = true;
+        let res = loop {
+            if !first && self.pc_breakpoints.contains(&session.machine.pc) {
+                break Ok(DebugStop::Breakpoint);
+            }
+            first = false;
+
+            match self.checked_step(&mut session.machine, file,
+                                    limits.max_output_lines, limits)
+            {
+                Ok(true)    => (),
+                Ok(false)   => {
+                    session.finished = true;
+                    break Ok(DebugStop::Finished);
+                },
+                Err(e)      => {
+                    session.finished = true;
+                    break Err(e);
+                }
+            }
+
+            if let Some((ofs, len)) = session.machine.last_access {
+                if self.file_breakpoints.iter()
+                       .any(|b| *b >= ofs && *b < ofs + len)
+                {
+                    break Ok(DebugStop::Breakpoint);
+                }
+            }
+
+            if !run {
+                break Ok(DebugStop::Stepped);
+            }
+        };
+
+        self.debug = Some(session);
+        res
+    }
+
+    /*
+     * Recreates the output of the current debug session by re-executing the
+     * instructions executed so far (e.g. after the file has changed).
+     */
+    pub fn debug_redraw(&mut self, file: &mut File,
+                        limits: &CfgStructLimits)
+        -> Result<(), String>
+    {
+        let (loc, executed) = match self.debug {
+            Some(ref s) => (s.machine.loc, s.machine.executed),
+            None        => return Ok(())
+        };
+
+        let mut m = Machine::new(loc);
+        self.lines.clear();
+        while m.executed < executed &&
+              self.checked_step(&mut m, file, limits.max_output_lines,
+                                limits)?
+        {
+        }
+
+        Ok(())
+    }
+
+    // The file range accessed by the last instruction executed in debugging
+    pub fn debug_access(&self) -> Option<(u64, u64)> {
+        match self.debug {
+            Some(ref s) => s.machine.last_access,
+            None        => None
+        }
+    }
+
+    pub fn debug_draw_panel(&self, display: &mut Display, start_x: usize) {
+        let height = display.h() as usize;
+        let width = display.w() as usize;
+        if height < DEBUG_PANEL_HEIGHT + 2 || width <= start_x {
+            return;
+        }
+
+        let y0 = height - 2 - DEBUG_PANEL_HEIGHT;
+        for (i, line) in self.debug_info().iter().enumerate() {
+            display.set_cursor_pos(start_x, y0 + i);
+            display.clear_line();
+            display.write(line.chars().take(width - start_x).collect());
+        }
+    }
+
+    fn debug_info(&self) -> Vec<String> {
+        let session = match self.debug {
+            Some(ref s) => s,
+            None        => return Vec::new()
+        };
+        let m = &session.machine;
+
+        let state = if session.finished {
+            String::from("finished")
+        } else {
+            format!("{:#06x}: {}", m.pc, self.disassemble(m.pc))
+        };
+
+        let stack: Vec<String> =
+            m.stack.iter().map(|v| format!("{:#x}", v)).collect();
+        let fstack: Vec<String> =
+            m.fstack.iter().map(|v| format!("{}", v)).collect();
+        let sstack: Vec<String> =
+            m.sstack.iter().map(|v| format!("{:?}", v)).collect();
+        let wram: Vec<String> =
+            m.wram.iter().enumerate().filter(|&(_, v)| *v != 0)
+                  .map(|(i, v)| format!("[{}]={:#x}", i, v)).collect();
+
+        let access = match m.last_access {
+            Some((ofs, len)) => format!("{:#x}..{:#x}", ofs, ofs + len),
+            None             => String::from("-")
+        };
+
+        let mut bps: Vec<String> =
+            self.pc_breakpoints.iter().map(|b| format!("pc {:#x}", b))
+                .collect();
+        bps.extend(self.file_breakpoints.iter()
+                       .map(|b| format!("file {:#x}", b)));
+
+        vec![
+            format!("── debug: {} ({} insns) ──", self.name, m.executed),
+            format!("pc {}", state),
+            format!("stack:  {}", stack.join(" ")),
+            format!("fstack: {}", fstack.join(" ")),
+            format!("sstack: {}", sstack.join(" ")),
+            format!("wram:   {}", wram.join(" ")),
+            format!("load:   {}", access),
+            format!("break:  {}", bps.join(", ")),
+        ]
+    }
+
+    pub fn toggle_pc_breakpoint(&mut self, pc: usize) {
+        if let Some(i) = self.pc_breakpoints.iter().position(|b| *b == pc) {
+            self.pc_breakpoints.remove(i);
+        } else {
+            self.pc_breakpoints.push(pc);
+        }
+    }
+
+    pub fn toggle_file_breakpoint(&mut self, offset: u64) {
+        if let Some(i) = self.file_breakpoints.iter().position(|b| *b == offset)
+        {
+            self.file_breakpoints.remove(i);
+        } else {
+            self.file_breakpoints.push(offset);
+        }
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.pc_breakpoints.clear();
+        self.file_breakpoints.clear();
+    }
+
+    // Returns a human-readable form of the instruction at @pc
+    fn disassemble(&self, pc: usize) -> String {
+        let opcode = match self.code.buffer.get(pc) {
+            Some(o) => *o,
+            None    => return String::from("(end of code)")
+        };
+        let sub = self.code.buffer.get(pc + 1).cloned().unwrap_or(0);
+        let arg = self.code.buffer.get(pc + 2).cloned().unwrap_or(0);
+        let arg2 = self.code.buffer.get(pc + 3).cloned().unwrap_or(0);
+        let constant = self.load_constant_u64(pc + 1).unwrap_or(0);
+
+        let with_sub = |names: &[&str]| -> String {
+            match names.get(sub as usize) {
+                Some(n) => String::from(*n),
+                None    => format!("{:#04x} {:#04x}", opcode, sub)
+            }
+        };
+
+        match opcode {
+            0x00 => String::from("stop"),
+            0x01 => with_sub(&["f2le", "f2be"]),
+            0x10 => format!("lic {:#x}", constant),
+            0x11 => format!("lfc {}", f64::from_bits(constant)),
+            0x12 => match self.load_constant_utf8_string(pc + 9,
+                                                         Some(constant))
+            {
+                Ok((s, _))  => format!("lsc {:?}", s),
+                Err(_)      => String::from("lsc (invalid)")
+            },
+            0x14 => String::from("lic $LOC"),
+            0x18 => with_sub(&["flu64", "fli64", "flu32", "fli32",
+                               "flu16", "fli16", "flu8", "fli8"]),
+            0x19 => with_sub(&["flf64", "flf32"]),
+            0x1a => with_sub(&["flsutf8null", "flsutf8sized",
+                               "flsasciinull", "flsasciisized",
+                               "flsutf8nulll", "flsasciinulll",
+                               "flsutf16lenulll", "flsutf16lesized",
+                               "flsutf16benulll", "flsutf16besized",
+                               "flsutf32lenulll", "flsutf32lesized",
+                               "flsutf32benulll", "flsutf32besized",
+                               "flslatin1nulll", "flslatin1sized",
+                               "flsebcdicnulll", "flsebcdicsized",
+                               "flsutf8bsized"]),
+            0x1b => with_sub(&["flleb128u", "flleb128s", "flzigzag", "flvlq",
+                               "flbcd"]),
+            0x1c => String::from("sli"),
+            0x28 => format!("{} {}", with_sub(&["osu", "osi", "olu", "oli"]),
+                            arg),
+            0x29 => with_sub(&["osf"]),
+            0x2a => with_sub(&["oss", "ossnull", "ossutf16le",
+                               "ossutf16lenull", "ossutf16be",
+                               "ossutf16benull", "ossutf32le",
+                               "ossutf32lenull", "ossutf32be",
+                               "ossutf32benull", "osslatin1", "osslatin1null",
+                               "ossebcdic", "ossebcdicnull"]),
+            0x2b => format!("oh{}", sub),
+            0x2c => String::from("ssi"),
+            0x30 => format!("{} {} (table)", with_sub(&["oeu", "oei", "ofl"]),
+                            arg),
+            0x31 => format!("{} (table)", with_sub(&["oguid", "ouuid"])),
+            0x32 => match sub {
+                0x03 | 0x04 => format!("{} {}", with_sub(&["", "", "", "ofxu",
+                                                           "ofxi"]), arg),
+                _ => with_sub(&["otunix", "otntfs", "otfat"])
+            },
+            0x33 => with_sub(&["oipv4", "oipv6", "omac", "ohex"]),
+            0x34 => match sub {
+                0x00 => format!("oab{}", arg),
+                _    => with_sub(&["", "oaend"])
+            },
+            0x36 => with_sub(&["crc32", "crc32c", "adler32"]),
+            0x37 => with_sub(&["ocrc32", "ocrc32c", "oadler32", "osha1",
+                               "osha256"]),
+            0x39 => format!("{} {}", with_sub(&["ovleb128u", "ovleb128s",
+                                                "ovzigzag", "ovvlq", "ovbcd"]),
+                            arg),
+            0x38 => {
+                let width = self.code.buffer.get(pc + 4).cloned()
+                                .unwrap_or(0);
+                format!("{} {} {} {}", with_sub(&["obfu", "obfi"]), arg,
+                        arg2, width)
+            },
+            0x3a => String::from("ochk"),
+            0x35 => match self.load_constant_utf8_string(pc + 9,
+                                                         Some(constant))
+            {
+                Ok((s, _))  => format!("desc {:?}", s),
+                Err(_)      => String::from("desc (invalid)")
+            },
+            0x80 => String::from("iswap"),
+            0x81 => String::from("idup"),
+            0x82 => String::from("idrop"),
+            0x83 => String::from("ineg"),
+            0x84 => String::from("iadd"),
+            0x85 => String::from("iand"),
+            0x86 => String::from("ior"),
+            0x87 => String::from("ixor"),
+            0x88 => String::from("inot"),
+            0x89 => String::from("isub"),
+            0x8a => String::from("imul"),
+            0x8b => String::from("idivu"),
+            0x8c => String::from("imodu"),
+            0x8d => String::from("ishl"),
+            0x8e => String::from("ishr"),
+            0x8f => format!("{} {} {}", with_sub(&["ibextu", "ibexts",
+                                                   "ibins"]), arg, arg2),
+            0x90 => String::from("ieq"),
+            0x91 => String::from("iltu"),
+            0x92 => String::from("ilts"),
+            0x93 => String::from("lnot"),
+            0xc2 => String::from("sdrop"),
+            0xc4 => String::from("scat"),
+            0xc8 => format!("{} {}", with_sub(&["sfu", "sfi"]), arg),
+            0xe0..=0xe3 => {
+                let name = ["jmp", "jz", "jnz", "jnn"][(opcode - 0xe0) as usize];
+                format!("{} {:#x}", name,
+                        (pc as u64).wrapping_add(constant))
+            },
+            0xff => String::from("panic"),
+            _ => format!("{:#04x}", opcode)
+        }
+    }
+
+    /*
+     * Executes a single instruction.  Returns false when execution is to stop
+     * (because of a stop instruction, because the end of the code has been
+     * reached, or because there is no more space for output).
+     */
+    fn step(&mut self, m: &mut Machine, file: &mut File, max_lines: usize,
+            limits: &CfgStructLimits)
+        -> Result<bool, String>
+    {
+        if m.pc >= self.code.buffer.len() {
+            return Ok(false);
+        }
+
+        let opcode = self.code.buffer[m.pc];
+        m.pc += 1;
+        m.executed += 1;
+        m.last_access = None;
+
+        match opcode {
+            0x00 => { // stop
+                return Ok(false)
+            },
+
+            0x01 => { // Switch endianness
+                let mode = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                match mode {
+                    0x00 => { // f2le
+                        m.file_be = false;
+                    },
+
+                    0x01 => { //f2be
+                        m.file_be = true;
+                    },
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, mode))
+                    }
+                }
+            },
+
+
+            0x10 => { // lic <constant>
+                let c = self.load_constant_u64(m.pc)?;
+                m.pc += 8;
+
+                m.stack.push(c);
+            },
+
+            0x11 => { // lfc <constant>
+                let c = self.load_constant_u64(m.pc)?;
+                m.pc += 8;
+
+                m.fstack.push(f64::from_bits(c));
+            },
+
+            0x12 => { // lsc <constant>
+                let len = self.load_constant_u64(m.pc)?;
+                m.pc += 8;
+
+                let (string, bytelen) =
+                    self.load_constant_utf8_string(m.pc, Some(len))?;
+                m.pc += bytelen;
+
+                m.sstack.push(string);
+            },
+
+            0x14 => { // lic $LOC
+                m.uses_loc = true;
+                m.stack.push(m.loc);
+            },
+
+            0x18 => { // Load integer from file
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                let offset = self.stack_pop(&mut m.stack)?;
+                let (len, sign): (usize, bool) = match subfunc {
+                    0x00 => (8, false), // flu64
+                    0x01 => (8, false), // fli64 (same as flu64)
+                    0x02 => (4, false), // flu32
+                    0x03 => (4, true),  // fli32
+                    0x04 => (2, false), // flu16
+                    0x05 => (2, true),  // fli16
+                    0x06 => (1, false), // flu8
+                    0x07 => (1, true),  // fli8
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+                m.last_access = Some((offset, len as u64));
+
+                let mut val = 0u64;
+                for i in 0..len {
+                    let ofs = offset + i as u64;
+
+                    if m.file_be {
+                        val <<= 8;
+                        val |= file.read_u8(ofs)? as u64;
+                    } else {
+                        val |= (file.read_u8(ofs)? as u64) << (i * 8);
+                    }
+                }
+
+                if sign && ((val >> (len * 8 - 1)) & 1) != 0 {
+                    // Sign extension
+                    val |= u64::MAX - ((1u64 << (len * 8)) - 1);
+                }
+
+                m.stack.push(val);
+            },
+
+            0x19 => { // Load floating point value from file
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                let offset = self.stack_pop(&mut m.stack)?;
+                let len: usize = match subfunc {
+                    0x00 => 8, // flf64
+                    0x01 => 4, // flf32
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+                m.last_access = Some((offset, len as u64));
+
+                let mut val = 0u64;
+                for i in 0..len {
+                    let ofs = offset + i as u64;
+
+                    if m.file_be {
+                        val <<= 8;
+                        val |= file.read_u8(ofs)? as u64;
+                    } else {
+                        val |= (file.read_u8(ofs)? as u64) << (i * 8);
+                    }
+                }
+
+                if len == 8 {
+                    m.fstack.push(f64::from_bits(val));
+                } else {
+                    m.fstack.push(f32::from_bits(val as u32) as f64);
+                }
+            },
+
+            0x1b => { // Load variable-length integer from file
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                let read = |o: u64| file.read_u8(o);
+                let (val, len) = match subfunc {
+                    0x00..=0x03 => {
+                        // flleb128u/flleb128s/flzigzag/flvlq
+                        let offset = self.stack_pop(&mut m.stack)?;
+                        let (val, len) =
+                            decode_varint(subfunc, read, offset)?;
+                        m.last_access = Some((offset, len));
+                        (val, len)
+                    },
+
+                    0x04 => { // flbcd
+                        let len = self.stack_pop(&mut m.stack)?;
+                        let offset = self.stack_pop(&mut m.stack)?;
+                        let val = decode_bcd(read, offset, len)?;
+                        m.last_access = Some((offset, len));
+                        (val, len)
+                    },
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+
+                m.stack.push(val);
+                m.stack.push(len);
+            },
+
+            0x1a => { // Load string from file
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                // The original UTF-8 and ASCII loads come first, then null-
+                // terminated and sized loads for each other encoding
+                let (encoding, sized, with_len) = match subfunc {
+                    0x00 | 0x02 => (Encoding::Utf8, false, false),
+                    0x01 | 0x03 => (Encoding::Utf8, true, false),
+                    0x04 | 0x05 => (Encoding::Utf8, false, true),
+
+                    0x06..=0x11 => {
+                        let e = Encoding::new((subfunc - 0x06) / 2 + 1);
+                        (e.unwrap(), subfunc & 1 != 0, subfunc & 1 == 0)
+                    },
+
+                    // Like flsutf8sized, but with the length in bytes
+                    0x12 => (Encoding::Utf8, true, false),
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+
+                let len = if sized {
+                    Some(self.stack_pop(&mut m.stack)?)
+                } else {
+                    None
+                };
+                let offset = self.stack_pop(&mut m.stack)?;
+
+                let (string, bytelen) = if encoding == Encoding::Utf8 {
+                    let utf8 = subfunc != 0x02 && subfunc != 0x03 &&
+                               subfunc != 0x05;
+                    self.load_file_utf8_string(file, offset, len,
+                                               subfunc == 0x12, utf8,
+                                               limits)?
+                } else {
+                    self.load_file_string(file, offset, len, encoding,
+                                          limits)?
+                };
+                m.last_access = Some((offset, bytelen as u64));
+
+                m.sstack.push(string);
+                if with_len {
+                    m.stack.push(bytelen as u64);
+                }
+            },
+
+            0x1c => { // sli
+                let address = self.stack_pop(&mut m.stack)?;
+                match m.wram.get(address as usize) {
+                    Some(v) if address < m.wram.len() as u64 =>
+                        m.stack.push(*v),
+                    _ => return Err(format!("WRAM read beyond end: {:#x}",
+                                            address))
+                }
+            },
+
+
+            0x28 => { // Output integer
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                let base = self.code_u8(m.pc)? as usize;
+                m.pc += 1;
+
+                let (signed, is_link) = match subfunc {
+                    0x00 => (false, false), // osu
+                    0x01 => (true,  false), // osi
+                    0x02 => (false, true),  // olu
+                    0x03 => (true,  true),  // oli
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+
+                let name = self.stack_pop(&mut m.sstack)?;
+                let target_struct = if is_link {
+                    Some(self.stack_pop(&mut m.sstack)?)
+                } else {
+                    None
+                };
+                let value = self.stack_pop(&mut m.stack)?;
+                let link_base = if is_link {
+                    Some(self.stack_pop(&mut m.stack)?)
+                } else {
+                    None
+                };
+                let orig_length = self.stack_pop(&mut m.stack)?;
+                let orig_offset = self.stack_pop(&mut m.stack)?;
+
+                let string = self.format_int(value, signed, base)?;
+
+                let link = link_base.map(|lb| Link {
+                    target: (Wrapping(lb) + Wrapping(value)).0,
+                    target_struct: match target_struct {
+                        Some(ref s) if !s.is_empty() => Some(s.clone()),
+                        _ => None
+                    },
+                });
+
+                let mut text = format!("{}: {}", name, string);
+                if let Some(ref l) = link {
+                    text.push_str(" →");
+                    if link_base != Some(0) {
+                        text.push_str(&format!(" {:#x}", l.target));
+                    }
+                    if let Some(ref s) = l.target_struct {
+                        text.push_str(&format!(" ({})", s));
+                    }
+                }
+
+                if !self.output_line(max_lines, text,
+                                     LineContent::Data {
+                                         loc: orig_offset,
+                                         length: orig_length,
+                                         name,
+                                         value: string,
+                                         kind: FieldKind::Int {
+                                             signed,
+                                             base,
+                                         },
+                                         big_endian: m.file_be,
+                                         link,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
+
+                m.last_output_was_not_header = true;
+            },
+
+            0x30 => { // Output symbolic integer
+                let subfunc = self.code_u8(m.pc)?;
+                let base = self.code_u8(m.pc + 1)? as usize;
+                let (table, table_len) =
+                    self.load_symbol_table(m.pc + 2, 8)?;
+                m.pc += 2 + table_len;
+
+                let name = self.stack_pop(&mut m.sstack)?;
+                let value = self.stack_pop(&mut m.stack)?;
+                let orig_length = self.stack_pop(&mut m.stack)?;
+                let orig_offset = self.stack_pop(&mut m.stack)?;
+
+                let entries: Vec<(u64, String)> = table.into_iter().map(
+                    |(key, sym)| (self.bytes_to_u64(&key), sym)
+                ).collect();
+
+                let (signed, text) = match subfunc {
+                    0x00 | 0x01 => { // oeu/oei
+                        let signed = subfunc == 0x01;
+                        match entries.iter().find(|e| e.0 == value) {
+                            Some(e) => (signed, e.1.clone()),
+                            None    =>
+                                (signed,
+                                 self.format_int(value, signed, base)?)
+                        }
+                    },
+
+                    0x02 => { // ofl
+                        let mut names = Vec::new();
+                        let mut rem = value;
+                        for &(mask, ref sym) in entries.iter() {
+                            if mask != 0 && value & mask == mask {
+                                names.push(sym.clone());
+                                rem &= !mask;
+                            }
+                        }
+                        if rem != 0 || names.is_empty() {
+                            names.push(self.format_int(rem, false, base)?);
+                        }
+                        (false, names.join(" | "))
+                    },
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, text),
+                                     LineContent::Data {
+                                         loc: orig_offset,
+                                         length: orig_length,
+                                         name,
+                                         value:
+                                             self.format_int(value, signed,
+                                                             base)?,
+                                         kind: FieldKind::Int {
+                                             signed,
+                                             base,
+                                         },
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
+
+                m.last_output_was_not_header = true;
+            },
+
+            0x31 => { // Output GUID
+                let subfunc = self.code_u8(m.pc)?;
+                let (table, table_len) =
+                    self.load_symbol_table(m.pc + 1, 16)?;
+                m.pc += 1 + table_len;
+
+                let mixed_endian = match subfunc {
+                    0x00 => true,  // oguid
+                    0x01 => false, // ouuid
 
-pub struct Structs {
-    list: Vec<Struct>,
-}
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
 
-pub struct MouseDownResult {
-    pub need_update: bool,
-    pub highlight: Option<(u64, u64)>,
-}
+                let name = self.stack_pop(&mut m.sstack)?;
+                let offset = self.stack_pop(&mut m.stack)?;
+                m.last_access = Some((offset, 16));
 
+                let mut bytes = Vec::new();
+                for i in 0..16 {
+                    bytes.push(file.read_u8(offset + i)?);
+                }
 
-impl Structs {
-    pub fn load(cfg: &ConfigFile) -> Result<Self, String> {
-        let mut structs = Vec::<Struct>::new();
+                let guid = self.format_guid(&bytes, mixed_endian);
+                let text = match table.iter().find(|e| e.0 == bytes) {
+                    Some(e) => e.1.clone(),
+                    None    => guid.clone()
+                };
+
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, text),
+                                     LineContent::Data {
+                                         loc: offset,
+                                         length: 16,
+                                         name,
+                                         value: guid,
+                                         kind: FieldKind::Guid {
+                                             mixed_endian,
+                                         },
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
 
-        for (name, cs) in cfg.get_structs().iter() {
-            let mut full_path = config::base_dir()?;
-            full_path.push(cs.path.clone());
+                m.last_output_was_not_header = true;
+            },
 
-            let path_str = full_path.as_path().to_string_lossy().into_owned();
+            0x29 => { // Output floating point value
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
 
-            let mut file = File::new(path_str)?;
-            let len = file.len()?;
+                let name = self.stack_pop(&mut m.sstack)?;
+                let value = self.stack_pop(&mut m.fstack)?;
+                let orig_length = self.stack_pop(&mut m.stack)?;
+                let orig_offset = self.stack_pop(&mut m.stack)?;
 
-            let mut buffer = Vec::new();
-            buffer.resize(len as usize, 0);
+                if subfunc != 0x00 {
+                    return Err(format!("Unknown opcode {:x} {:x}",
+                                       opcode, subfunc));
+                }
 
-            file.read(0, &mut buffer)?;
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, value),
+                                     LineContent::Data {
+                                         loc: orig_offset,
+                                         length: orig_length,
+                                         name,
+                                         value: format!("{}", value),
+                                         kind: FieldKind::Float,
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
 
-            let s = Struct {
-                name: name.clone(),
-                code: StructCode {
-                    buffer: buffer,
-                },
-                headers: HashMap::new(),
-                lines: Vec::new(),
-            };
+                m.last_output_was_not_header = true;
+            },
 
-            structs.push(s);
-        }
+            0x2a => { // Output string
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                let name = self.stack_pop(&mut m.sstack)?;
+                let value = self.stack_pop(&mut m.sstack)?;
+                let orig_length = self.stack_pop(&mut m.stack)?;
+                let orig_offset = self.stack_pop(&mut m.stack)?;
+
+                let encoding = match Encoding::new(subfunc >> 1) {
+                    Some(e) => e,
+                    None    => return Err(format!("Unknown opcode {:x} {:x}",
+                                                  opcode, subfunc))
+                };
+
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, value),
+                                     LineContent::Data {
+                                         loc: orig_offset,
+                                         length: orig_length,
+                                         name,
+                                         value,
+                                         kind: FieldKind::Str {
+                                             encoding,
+                                             null_terminated: subfunc & 1 != 0,
+                                         },
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
 
-        Ok(Structs {
-            list: structs,
-        })
-    }
+                m.last_output_was_not_header = true;
+            },
 
-    pub fn len(&self) -> usize {
-        self.list.len()
-    }
+            0x2b => { // oh<level>
+                let level = self.code_u8(m.pc)?;
+                m.pc += 1;
 
-    pub fn get(&self, i: usize) -> &Struct {
-        &self.list[i]
-    }
+                let title = self.stack_pop(&mut m.sstack)?;
 
-    pub fn get_mut(&mut self, i: usize) -> &mut Struct {
-        &mut self.list[i]
-    }
-}
+                m.current_header_path.truncate(level as usize);
+                m.current_header_path.push(title.clone());
 
+                let folded = match self.output_header(m, max_lines, level,
+                                                      title)
+                {
+                    Some(f) => f,
+                    None    => return Ok(false)
+                };
 
-impl Struct {
-    pub fn get_name(&self) -> &str {
-        self.name.as_ref()
-    }
+                if folded {
+                    m.stack.push(0u64);
+                } else {
+                    m.stack.push(1u64);
+                }
+            },
 
-    pub fn update(&mut self, file: &mut File, loc: u64,
-                  display: &mut Display, start_x: usize)
-        -> Result<(), String>
-    {
-        let height = display.h() as usize;
+            0x2c => { // ssi
+                let address = self.stack_pop(&mut m.stack)?;
+                let value = self.stack_pop(&mut m.stack)?;
 
-        let mut last_output_was_not_header = false;
+                if address >= limits.max_wram as u64 {
+                    return Err(format!("WRAM write beyond limit: {:#x}",
+                                       address));
+                }
 
-        let mut pc = 0;
-        let mut file_be = false;
+                let address = address as usize;
+                if address >= m.wram.len() {
+                    m.wram.resize(address + 1, 0);
+                }
+                m.wram[address] = value;
+            },
 
-        let mut stack = Vec::<u64>::new();
-        let mut sstack = Vec::<String>::new();
 
-        let mut wram = Vec::<u64>::new();
+            0x32 => { // Output formatted integer
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
 
-        let mut current_header_path = Vec::<String>::new();
+                let name = self.stack_pop(&mut m.sstack)?;
+                let value = self.stack_pop(&mut m.stack)?;
+                let orig_length = self.stack_pop(&mut m.stack)?;
+                let orig_offset = self.stack_pop(&mut m.stack)?;
 
-        self.lines.clear();
+                let (text, signed) = match subfunc {
+                    0x00 => { // otunix
+                        (self.format_timestamp(value as i64, 0, true), true)
+                    },
 
-        loop {
-            if pc >= self.code.buffer.len() {
-                break;
-            }
+                    0x01 => { // otntfs
+                        // 100 ns intervals since 1601-01-01
+                        let secs = (value / 10000000) as i64 - 11644473600;
+                        let nanos = (value % 10000000) as u32 * 100;
+                        (self.format_timestamp(secs, nanos, true), false)
+                    },
 
-            let opcode = self.code.buffer[pc];
-            pc += 1;
+                    0x02 => { // otfat
+                        (self.format_fat_timestamp(value), false)
+                    },
 
-            match opcode {
-                0x00 => { // stop
-                    break
-                },
+                    0x03 | 0x04 => { // ofxu/ofxi
+                        let bits = self.code_u8(m.pc)? as u32;
+                        m.pc += 1;
+                        self.assert(bits <= 64,
+                                    format!("Invalid number of fraction bits \
+                                             ({})", bits))?;
 
-                0x01 => { // Switch endianness
-                    let mode = self.code.buffer[pc];
-                    pc += 1;
+                        let signed = subfunc == 0x04;
+                        (self.format_fixed_point(value, signed, bits), signed)
+                    },
 
-                    match mode {
-                        0x00 => { // f2le
-                            file_be = false;
-                        },
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, text),
+                                     LineContent::Data {
+                                         loc: orig_offset,
+                                         length: orig_length,
+                                         name,
+                                         value:
+                                             self.format_int(value, signed,
+                                                             10)?,
+                                         kind: FieldKind::Int {
+                                             signed,
+                                             base: 10,
+                                         },
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
+
+                m.last_output_was_not_header = true;
+            },
+
+            0x33 => { // Output byte range
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
+
+                let name = self.stack_pop(&mut m.sstack)?;
+                let length = self.stack_pop(&mut m.stack)?;
+                let offset = self.stack_pop(&mut m.stack)?;
+
+                let required = match subfunc {
+                    0x00 => Some(4),  // oipv4
+                    0x01 => Some(16), // oipv6
+                    0x02 => Some(6),  // omac
+                    0x03 => None,     // ohex
 
-                        0x01 => { //f2be
-                            file_be = true;
-                        },
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
+                if let Some(r) = required {
+                    self.assert(length == r,
+                                format!("Invalid length {} (must be {})",
+                                        length, r))?;
+                }
+                self.assert(length <= limits.max_string_length as u64,
+                            format!("Byte range too long ({} bytes)",
+                                    length))?;
+                m.last_access = Some((offset, length));
+
+                let mut bytes = Vec::new();
+                for i in 0..length {
+                    bytes.push(file.read_u8(offset + i)?);
+                }
 
-                        _ => {
-                            return Err(format!("Unknown opcode {:x} {:x}",
-                                               opcode, mode))
+                let text = match subfunc {
+                    0x00 => bytes.iter().map(|b| format!("{}", b))
+                                 .collect::<Vec<String>>().join("."),
+                    0x01 => self.format_ipv6(&bytes),
+                    0x02 => bytes.iter().map(|b| format!("{:02x}", b))
+                                 .collect::<Vec<String>>().join(":"),
+                    _ => {
+                        let mut t = bytes.iter().take(HEXDUMP_LENGTH)
+                                         .map(|b| format!("{:02x}", b))
+                                         .collect::<Vec<String>>().join(" ");
+                        if bytes.len() > HEXDUMP_LENGTH {
+                            t.push_str(" …");
                         }
+                        t
                     }
-                },
+                };
+
+                let hex = bytes.iter().map(|b| format!("{:02x}", b))
+                               .collect::<Vec<String>>().join(" ");
+
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, text),
+                                     LineContent::Data {
+                                         loc: offset,
+                                         length,
+                                         name,
+                                         value: hex,
+                                         kind: FieldKind::Bytes,
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
 
+                m.last_output_was_not_header = true;
+            },
 
-                0x10 => { // lic <constant>
-                    let c = self.load_constant_u64(pc);
-                    pc += 8;
 
-                    stack.push(c);
-                },
+            0x34 => { // Arrays
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
 
-                0x12 => { // lsc <constant>
-                    let len = self.load_constant_u64(pc);
-                    pc += 8;
+                match subfunc {
+                    0x00 => { // oab<level>
+                        let level = self.code_u8(m.pc)?;
+                        m.pc += 1;
 
-                    let (string, bytelen) =
-                        self.load_constant_utf8_string(pc, Some(len))?;
-                    pc += bytelen;
+                        let title = self.stack_pop(&mut m.sstack)?;
+                        let count = self.stack_pop(&mut m.stack)?;
 
-                    sstack.push(string);
-                },
+                        let text = if count == u64::MAX {
+                            title.clone()
+                        } else {
+                            format!("{} ({})", title, count)
+                        };
+                        m.current_header_path.truncate(level as usize);
+                        m.current_header_path.push(title);
 
-                0x14 => { // lic $LOC
-                    stack.push(loc);
-                },
+                        let folded = match self.output_header(m, max_lines,
+                                                              level, text)
+                        {
+                            Some(f) => f,
+                            None    => return Ok(false)
+                        };
 
-                0x18 => { // Load integer from file
-                    let subfunc = self.code.buffer[pc];
-                    pc += 1;
-
-                    let offset = self.stack_pop(&mut stack)?;
-                    let (len, sign): (usize, bool) = match subfunc {
-                        0x00 => (8, false), // flu64
-                        0x01 => (8, false), // fli64 (same as flu64)
-                        0x02 => (4, false), // flu32
-                        0x03 => (4, true),  // fli32
-                        0x04 => (2, false), // flu16
-                        0x05 => (2, true),  // fli16
-                        0x06 => (1, false), // flu8
-                        0x07 => (1, true),  // fli8
-
-                        _ => {
-                            return Err(format!("Unknown opcode {:x} {:x}",
-                                               opcode, subfunc))
+                        let path = m.current_header_path.clone();
+                        let first = if folded || m.unfold {
+                            0
+                        } else {
+                            std::cmp::min(self.lookup_header(&path).first,
+                                          count)
+                        };
+                        let end = if folded {
+                            0
+                        } else if m.unfold {
+                            count
+                        } else {
+                            std::cmp::min(first.saturating_add(
+                                              ARRAY_PAGE_SIZE),
+                                          count)
+                        };
+
+                        if first > 0 {
+                            let text = format!("↑ Previous {} elements",
+                                               ARRAY_PAGE_SIZE);
+                            if !self.output_line(max_lines, text,
+                                                 LineContent::Page {
+                                                     path: path.clone(),
+                                                     forward: false,
+                                                 })
+                            {
+                                return Ok(false);
+                            }
                         }
-                    };
-
-                    let mut val = 0u64;
-                    for i in 0..len {
-                        let ofs = offset + i as u64;
 
-                        if file_be {
-                            val <<= 8;
-                            val |= file.read_u8(ofs)? as u64;
-                        } else {
-                            val |= (file.read_u8(ofs)? as u64) << (i * 8);
+                        m.arrays.push((path, first, end));
+                        m.stack.push(first);
+                        m.stack.push(end);
+                    },
+
+                    0x01 => { // oaend
+                        let count = self.stack_pop(&mut m.stack)?;
+                        let (path, first, end) =
+                            self.stack_pop(&mut m.arrays)?;
+
+                        if first < end && end < count {
+                            let text = format!("↓ Next {} elements ({}–{} of \
+                                                {} shown)",
+                                               std::cmp::min(ARRAY_PAGE_SIZE,
+                                                             count - end),
+                                               first, end - 1, count);
+                            if !self.output_line(max_lines, text,
+                                                 LineContent::Page {
+                                                     path,
+                                                     forward: true,
+                                                 })
+                            {
+                                return Ok(false);
+                            }
+                            m.last_output_was_not_header = true;
                         }
-                    }
+                    },
 
-                    if sign && ((val >> (len * 8 - 1)) & 1) != 0 {
-                        // Sign extension
-                        val |= std::u64::MAX - ((1u64 << (len * 8)) - 1);
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
                     }
+                }
+            },
 
-                    stack.push(val);
-                },
+            0x35 => { // desc <constant>
+                // Only for showing in the struct list, so just skip it
+                let len = self.load_constant_u64(m.pc)?;
+                m.pc += 8;
+
+                let (_, bytelen) =
+                    self.load_constant_utf8_string(m.pc, Some(len))?;
+                m.pc += bytelen;
+            },
 
-                0x1a => { // Load string from file
-                    let subfunc = self.code.buffer[pc];
-                    pc += 1;
+            0x36 => { // Checksum
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
 
-                    let (string, _) = match subfunc {
-                        0x00 => { // flsutf8null
-                            let offset = self.stack_pop(&mut stack)?;
+                if subfunc > 0x02 {
+                    return Err(format!("Unknown opcode {:x} {:x}", opcode,
+                                       subfunc));
+                }
 
-                            self.load_file_utf8_string(file, offset, None,
-                                                       true)?
-                        },
+                let length = self.stack_pop(&mut m.stack)?;
+                let offset = self.stack_pop(&mut m.stack)?;
+                m.last_access = Some((offset, length));
 
-                        0x01 => { // flsutf8sized
-                            let len = self.stack_pop(&mut stack)?;
-                            let offset = self.stack_pop(&mut stack)?;
+                let sum = self.checksum(file, subfunc, offset, length, None,
+                                        limits)?;
+                m.stack.push(sum.iter().fold(0, |v, &b| (v << 8) | b as u64));
+            },
 
-                            self.load_file_utf8_string(file, offset, Some(len),
-                                                       true)?
-                        },
+            0x37 => { // Output checksum
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
 
-                        0x02 => { // flsasciinull
-                            let offset = self.stack_pop(&mut stack)?;
+                if subfunc > 0x04 {
+                    return Err(format!("Unknown opcode {:x} {:x}", opcode,
+                                       subfunc));
+                }
+                let size = Hasher::new(subfunc).map_or(0, |h| h.size()) as u64;
 
-                            self.load_file_utf8_string(file, offset, None,
-                                                       false)?
-                        },
+                let name = self.stack_pop(&mut m.sstack)?;
+                let stored_ofs = self.stack_pop(&mut m.stack)?;
+                let length = self.stack_pop(&mut m.stack)?;
+                let offset = self.stack_pop(&mut m.stack)?;
+                m.last_access = Some((offset, length));
 
-                        0x03 => { // flsasciisized
-                            let len = self.stack_pop(&mut stack)?;
-                            let offset = self.stack_pop(&mut stack)?;
+                let sum = self.checksum(file, subfunc, offset, length,
+                                        Some((stored_ofs, size)), limits)?;
 
-                            self.load_file_utf8_string(file, offset, Some(len),
-                                                       false)?
-                        },
+                let mut stored = vec![0; size as usize];
+                file.read(stored_ofs, &mut stored)?;
+                m.add_read(stored_ofs, size);
 
-                        _ => {
-                            return Err(format!("Unknown opcode {:x} {:x}",
-                                               opcode, subfunc))
-                        }
+                // Only the 32-bit checksums are stored in file mode
+                let (valid, expected, value, kind) = if size == 4 {
+                    if m.file_be {
+                        stored.reverse();
+                    }
+                    let stored_val = self.bytes_to_u64(&stored);
+                    let sum_val =
+                        sum.iter().fold(0, |v, &b| (v << 8) | b as u64);
+                    (stored_val == sum_val, format!("{:#010x}", sum_val),
+                     format!("{:#x}", stored_val),
+                     FieldKind::Int { signed: false, base: 16 })
+                } else {
+                    let hex = |b: &Vec<u8>| {
+                        b.iter().map(|x| format!("{:02x}", x))
+                         .collect::<Vec<String>>()
                     };
+                    (stored == sum, hex(&sum).concat(), hex(&stored).join(" "),
+                     FieldKind::Bytes)
+                };
+
+                let text = if valid {
+                    format!("{}: valid", name)
+                } else {
+                    format!("{}: mismatch (expected {})", name, expected)
+                };
+
+                if !self.output_check_line(max_lines, text, valid,
+                                           LineContent::Data {
+                                               loc: stored_ofs,
+                                               length: size,
+                                               name,
+                                               value,
+                                               kind,
+                                               big_endian: m.file_be,
+                                               link: None,
+                                               bits: None,
+                                           })
+                {
+                    return Ok(false);
+                }
 
-                    sstack.push(string);
-                },
+                m.last_output_was_not_header = true;
+            },
 
-                0x1c => { // sli
-                    let address = self.stack_pop(&mut stack)? as usize;
-                    stack.push(wram[address]);
-                },
+            0x38 => { // Output bit field
+                let subfunc = self.code_u8(m.pc)?;
+                let base = self.code_u8(m.pc + 1)? as usize;
+                let first = self.code_u8(m.pc + 2)? as u64;
+                let width = self.code_u8(m.pc + 3)? as u64;
+                m.pc += 4;
 
+                let signed = match subfunc {
+                    0x00 => false, // obfu
+                    0x01 => true,  // obfi
 
-                0x28 => { // Output integer
-                    let subfunc = self.code.buffer[pc];
-                    pc += 1;
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
+                    }
+                };
 
-                    let base = self.code.buffer[pc] as usize;
-                    pc += 1;
+                let name = self.stack_pop(&mut m.sstack)?;
+                let value = self.stack_pop(&mut m.stack)?;
+                let length = self.stack_pop(&mut m.stack)?;
+                let offset = self.stack_pop(&mut m.stack)?;
 
-                    let name = self.stack_pop(&mut sstack)?;
-                    let value = self.stack_pop(&mut stack)?;
-                    let orig_length = self.stack_pop(&mut stack)?;
-                    let orig_offset = self.stack_pop(&mut stack)?;
+                if length > 8 {
+                    return Err(format!("Invalid bit field length {}",
+                                       length));
+                }
+                if first + width > length * 8 {
+                    return Err(format!("Bit range {}+{} exceeds the {}-byte \
+                                        field “{}”", first, width, length,
+                                       name));
+                }
+                let mask = bit_mask(first, width)?;
+                let mut bits = (value & mask) >> first;
+                if signed {
+                    let shift = 64 - width;
+                    bits = (((bits << shift) as i64) >> shift) as u64;
+                }
 
-                    let string = match subfunc {
-                        0x00 => self.format_int(value, false, base), // osu
-                        0x01 => self.format_int(value, true,  base), // osi
+                let string = self.format_int(bits, signed, base)?;
+
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, string),
+                                     LineContent::Data {
+                                         loc: offset,
+                                         length,
+                                         name,
+                                         value: string,
+                                         kind: FieldKind::Int {
+                                             signed,
+                                             base,
+                                         },
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: Some((first, width)),
+                                     })
+                {
+                    return Ok(false);
+                }
 
-                        _ => {
-                            return Err(format!("Unknown opcode {:x} {:x}",
-                                               opcode, subfunc))
-                        }
-                    };
+                m.last_output_was_not_header = true;
+            },
 
-                    if !self.output_line(display, start_x, height,
-                                         format!("{}: {}", name, string),
-                                         LineContent::Data {
-                                             loc: orig_offset,
-                                             length: orig_length,
-                                         })
-                    {
-                        break;
-                    }
+            0x39 => { // Output variable-length integer
+                let encoding = self.code_u8(m.pc)?;
+                let base = self.code_u8(m.pc + 1)? as usize;
+                m.pc += 2;
 
-                    last_output_was_not_header = true;
-                },
+                if encoding > 0x04 {
+                    return Err(format!("Unknown opcode {:x} {:x}", opcode,
+                                       encoding));
+                }
+                let signed = encoding == 0x01 || encoding == 0x02;
+
+                let name = self.stack_pop(&mut m.sstack)?;
+                let value = self.stack_pop(&mut m.stack)?;
+                let length = self.stack_pop(&mut m.stack)?;
+                let offset = self.stack_pop(&mut m.stack)?;
+
+                let string = self.format_int(value, signed, base)?;
+
+                if !self.output_line(max_lines,
+                                     format!("{}: {}", name, string),
+                                     LineContent::Data {
+                                         loc: offset,
+                                         length,
+                                         name,
+                                         value: string,
+                                         kind: FieldKind::Varint {
+                                             encoding,
+                                             base,
+                                         },
+                                         big_endian: m.file_be,
+                                         link: None,
+                                         bits: None,
+                                     })
+                {
+                    return Ok(false);
+                }
 
-                0x2a => { // Output string
-                    let subfunc = self.code.buffer[pc];
-                    pc += 1;
+                m.last_output_was_not_header = true;
+            },
 
-                    let name = self.stack_pop(&mut sstack)?;
-                    let value = self.stack_pop(&mut sstack)?;
-                    let orig_length = self.stack_pop(&mut stack)?;
-                    let orig_offset = self.stack_pop(&mut stack)?;
+            0x3a => { // ochk
+                let name = self.stack_pop(&mut m.sstack)?;
+                let condition = self.stack_pop(&mut m.stack)?;
+                let length = self.stack_pop(&mut m.stack)?;
+                let offset = self.stack_pop(&mut m.stack)?;
+
+                let passed = condition != 0;
+                let text = format!("{}: {}", name,
+                                   if passed { "ok" } else { "failed" });
+
+                if !self.output_check_line(max_lines, text, passed,
+                                           LineContent::Check {
+                                               loc: offset,
+                                               length,
+                                               name,
+                                               passed,
+                                           })
+                {
+                    return Ok(false);
+                }
 
-                    if subfunc != 0x00 {
-                        return Err(format!("Unknown opcode {:x} {:x}",
-                                           opcode, subfunc));
-                    }
+                m.last_output_was_not_header = true;
+            },
 
-                    if !self.output_line(display, start_x, height,
-                                         format!("{}: {}", name, value),
-                                         LineContent::Data {
-                                             loc: orig_offset,
-                                             length: orig_length,
-                                         })
-                    {
-                        break;
-                    }
 
-                    last_output_was_not_header = true;
-                },
+            0x80 => { // iswap
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push(x);
+                m.stack.push(y);
+            },
 
-                0x2b => { // oh<level>
-                    let level = self.code.buffer[pc];
-                    pc += 1;
+            0x81 => { // idup
+                let x = self.stack_pop(&mut m.stack)?;
+                m.stack.push(x);
+                m.stack.push(x);
+            },
 
-                    let title = self.stack_pop(&mut sstack)?;
+            0x82 => { // idrop
+                self.stack_pop(&mut m.stack)?;
+            },
 
-                    current_header_path.truncate(level as usize);
-                    current_header_path.push(title.clone());
+            0x83 => { // ineg
+                let x = self.stack_pop(&mut m.stack)?;
+                m.stack.push((Wrapping(0u64) - Wrapping(x)).0);
+            },
 
-                    let folded = {
-                        let hdr = self.lookup_header(&current_header_path);
-                        hdr.folded
-                    };
+            0x84 => { // iadd
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push((Wrapping(x) + Wrapping(y)).0);
+            },
 
-                    if last_output_was_not_header {
-                        if !self.output_line(display, start_x, height,
-                                             String::new(),
-                                             LineContent::Nothing)
-                        {
-                            break;
-                        }
-                    }
+            0x85 => { // iand
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push(x & y);
+            },
 
-                    let color = if level == 0 {
-                        Color::StructH0
-                    } else if level == 1 {
-                        Color::StructH1
-                    } else if level == 2 {
-                        Color::StructH2
-                    } else {
-                        Color::StructH3P
-                    };
-                    display.color_on_ref(&color);
-                    if !self.output_line(display, start_x, height, title,
-                                         LineContent::Header {
-                                             path: current_header_path.clone(),
-                                         })
-                    {
-                        display.color_off_ref(&color);
-                        break;
-                    }
-                    display.color_off_ref(&color);
+            0x86 => { // ior
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push(x | y);
+            },
 
-                    if !folded {
-                        if !self.output_line(display, start_x, height,
-                                             String::new(),
-                                             LineContent::Nothing)
-                        {
-                            break;
-                        }
-                    }
+            0x87 => { // ixor
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push(x ^ y);
+            },
 
-                    last_output_was_not_header = false;
+            0x88 => { // inot
+                let x = self.stack_pop(&mut m.stack)?;
+                m.stack.push(!x);
+            },
 
-                    if folded {
-                        stack.push(0u64);
-                    } else {
-                        stack.push(1u64);
-                    }
-                },
+            0x89 => { // isub
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push((Wrapping(y) - Wrapping(x)).0);
+            },
+
+            0x8a => { // imul
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push((Wrapping(x) * Wrapping(y)).0);
+            },
+
+            0x8b => { // idivu
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                if x == 0 {
+                    return Err(String::from("Division by zero"));
+                }
+                m.stack.push(y / x);
+            },
+
+            0x8c => { // imodu
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                if x == 0 {
+                    return Err(String::from("Division by zero"));
+                }
+                m.stack.push(y % x);
+            },
+
+            0x8d => { // ishl
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push(if x >= 64 { 0 } else { y << x });
+            },
 
-                0x2c => { // ssi
-                    let address = self.stack_pop(&mut stack)? as usize;
-                    let value = self.stack_pop(&mut stack)?;
+            0x8e => { // ishr
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push(if x >= 64 { 0 } else { y >> x });
+            },
 
-                    if address >= wram.len() {
-                        wram.resize(address + 1, 0);
+            0x8f => { // Bit field
+                let subfunc = self.code_u8(m.pc)?;
+                let first = self.code_u8(m.pc + 1)? as u64;
+                let width = self.code_u8(m.pc + 2)? as u64;
+                m.pc += 3;
+
+                let mask = bit_mask(first, width)?;
+                match subfunc {
+                    0x00 => { // ibextu
+                        let x = self.stack_pop(&mut m.stack)?;
+                        m.stack.push((x & mask) >> first);
+                    },
+
+                    0x01 => { // ibexts
+                        let x = self.stack_pop(&mut m.stack)?;
+                        let shift = 64 - width;
+                        let v = (((x & mask) >> first) << shift) as i64;
+                        m.stack.push((v >> shift) as u64);
+                    },
+
+                    0x02 => { // ibins
+                        let v = self.stack_pop(&mut m.stack)?;
+                        let x = self.stack_pop(&mut m.stack)?;
+                        m.stack.push((x & !mask) | ((v << first) & mask));
+                    },
+
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
                     }
-                    wram[address] = value;
-                },
+                }
+            },
 
+            0x90 => { // ieq
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push((y == x) as u64);
+            },
 
-                0x80 => { // iswap
-                    let x = self.stack_pop(&mut stack)?;
-                    let y = self.stack_pop(&mut stack)?;
-                    stack.push(x);
-                    stack.push(y);
-                },
+            0x91 => { // iltu
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push((y < x) as u64);
+            },
 
-                0x81 => { // idup
-                    let x = self.stack_pop(&mut stack)?;
-                    stack.push(x);
-                    stack.push(x);
-                },
+            0x92 => { // ilts
+                let x = self.stack_pop(&mut m.stack)?;
+                let y = self.stack_pop(&mut m.stack)?;
+                m.stack.push(((y as i64) < (x as i64)) as u64);
+            },
 
-                0x82 => { // idrop
-                    self.stack_pop(&mut stack)?;
-                },
+            0x93 => { // lnot
+                let x = self.stack_pop(&mut m.stack)?;
+                m.stack.push((x == 0) as u64);
+            },
 
-                0x83 => { // ineg
-                    let x = self.stack_pop(&mut stack)?;
-                    stack.push((Wrapping(0u64) - Wrapping(x)).0);
-                },
 
-                0x84 => { // iadd
-                    let x = self.stack_pop(&mut stack)?;
-                    let y = self.stack_pop(&mut stack)?;
-                    stack.push((Wrapping(x) + Wrapping(y)).0);
-                },
+            0xc2 => { // sdrop
+                self.stack_pop(&mut m.sstack)?;
+            },
 
-                0x85 => { // iand
-                    let x = self.stack_pop(&mut stack)?;
-                    let y = self.stack_pop(&mut stack)?;
-                    stack.push(x & y);
-                },
+            0xc4 => { // scat
+                let x = self.stack_pop(&mut m.sstack)?;
+                let y = self.stack_pop(&mut m.sstack)?;
+                m.sstack.push(x + y.as_ref());
+            },
 
+            0xc8 => { // Format integer
+                let subfunc = self.code_u8(m.pc)?;
+                m.pc += 1;
 
-                0xe0 => { // jmp <target>
-                    let c = self.load_constant_u64(pc);
-                    pc -= 1; // Go back before the instruction
-                    pc = (Wrapping(pc as u64) + Wrapping(c)).0 as usize;
-                },
+                let base = self.code_u8(m.pc)? as usize;
+                m.pc += 1;
 
-                0xe1 => { // jz <target>
-                    let c = self.load_constant_u64(pc);
-                    pc += 8;
+                let value = self.stack_pop(&mut m.stack)?;
 
-                    if self.stack_pop(&mut stack)? == 0 {
-                        pc -= 9; // Go back before the instruction
+                let string = match subfunc {
+                    0x00 => self.format_int(value, false, base)?, // sfu
+                    0x01 => self.format_int(value, true,  base)?, // sfi
 
-                        pc = (Wrapping(pc as u64) + Wrapping(c)).0 as usize;
+                    _ => {
+                        return Err(format!("Unknown opcode {:x} {:x}",
+                                           opcode, subfunc))
                     }
-                },
+                };
 
-                0xe2 => { // jnz <target>
-                    let c = self.load_constant_u64(pc);
-                    pc += 8;
+                m.sstack.push(string);
+            },
 
-                    if self.stack_pop(&mut stack)? != 0 {
-                        pc -= 9; // Go back before the instruction
 
-                        pc = (Wrapping(pc as u64) + Wrapping(c)).0 as usize;
-                    }
-                },
+            0xe0 => { // jmp <target>
+                let c = self.load_constant_u64(m.pc)?;
+                m.pc -= 1; // Go back before the instruction
+                m.pc = (Wrapping(m.pc as u64) + Wrapping(c)).0 as usize;
+            },
 
-                0xe3 => { // jnn <target>
-                    let c = self.load_constant_u64(pc);
-                    pc += 8;
+            0xe1 => { // jz <target>
+                let c = self.load_constant_u64(m.pc)?;
+                m.pc += 8;
 
-                    if self.stack_pop(&mut stack)? >> 63 == 0 {
-                        pc -= 9; // Go back before the instruction
+                if self.stack_pop(&mut m.stack)? == 0 {
+                    m.pc -= 9; // Go back before the instruction
 
-                        pc = (Wrapping(pc as u64) + Wrapping(c)).0 as usize;
-                    }
-                },
+                    m.pc = (Wrapping(m.pc as u64) + Wrapping(c)).0 as usize;
+                }
+            },
 
+            0xe2 => { // jnz <target>
+                let c = self.load_constant_u64(m.pc)?;
+                m.pc += 8;
 
-                0xff => { // panic
-                    let mut string = String::from("Stack:");
+                if self.stack_pop(&mut m.stack)? != 0 {
+                    m.pc -= 9; // Go back before the instruction
 
-                    while let Some(v) = stack.pop() {
-                        string += format!(" {:#x}", v).as_ref();
-                    }
+                    m.pc = (Wrapping(m.pc as u64) + Wrapping(c)).0 as usize;
+                }
+            },
 
-                    return Err(string)
-                },
+            0xe3 => { // jnn <target>
+                let c = self.load_constant_u64(m.pc)?;
+                m.pc += 8;
 
+                if self.stack_pop(&mut m.stack)? >> 63 == 0 {
+                    m.pc -= 9; // Go back before the instruction
 
-                _ => {
-                    return Err(format!("Unkown opcode {:x}", opcode))
+                    m.pc = (Wrapping(m.pc as u64) + Wrapping(c)).0 as usize;
                 }
-            }
-        }
+            },
 
-        while self.output_line(display, start_x, height, String::new(),
-                               LineContent::Nothing)
-        {
+
+            0xff => { // panic
+                let mut string = String::from("Stack:");
+
+                while let Some(v) = m.stack.pop() {
+                    string += format!(" {:#x}", v).as_ref();
+                }
+
+                return Err(string)
+            },
+
+
+            _ => {
+                return Err(format!("Unkown opcode {:x}", opcode))
+            }
         }
 
-        Ok(())
+        Ok(true)
     }
 
     fn assert(&self, res: bool, errstr: String) -> Result<(), String> {
@@ -508,13 +3083,58 @@ impl Struct {
         }
     }
 
-    fn format_int(&self, mut val: u64, signed: bool, base: usize) -> String {
-        if base > 36 {
-            panic!("Base must not exceed 36, but is {}", base);
+    /*
+     * Computes a checksum over the given file range, with the range @zero
+     * (if given) counting as zeroes.  Returns the result as big-endian bytes.
+     */
+    fn checksum(&self, file: &mut File, algorithm: u8, offset: u64,
+                length: u64, zero: Option<(u64, u64)>,
+                limits: &CfgStructLimits)
+        -> Result<Vec<u8>, String>
+    {
+        self.assert(length <= limits.max_checksum_length,
+                    format!("Checksum range too long ({} bytes)", length))?;
+
+        let mut hasher = match Hasher::new(algorithm) {
+            Some(h) => h,
+            None    => return Err(format!("Unknown checksum {}", algorithm))
+        };
+
+        let mut pos = offset;
+        let end = offset.checked_add(length)
+                        .ok_or(String::from("Checksum range overflows"))?;
+        let mut chunk = Vec::new();
+
+        while pos < end {
+            let len = std::cmp::min(end - pos, checksum::CHUNK_SIZE as u64);
+            chunk.resize(len as usize, 0);
+            file.read(pos, &mut chunk)?;
+
+            if let Some((z_ofs, z_len)) = zero {
+                for (i, b) in chunk.iter_mut().enumerate() {
+                    let p = pos + i as u64;
+                    if p >= z_ofs && p - z_ofs < z_len {
+                        *b = 0;
+                    }
+                }
+            }
+
+            hasher.update(&chunk);
+            pos += len;
+        }
+
+        Ok(hasher.finish())
+    }
+
+    fn format_int(&self, mut val: u64, signed: bool, base: usize)
+        -> Result<String, String>
+    {
+        if !(2..=36).contains(&base) {
+            return Err(format!("Base must be in [2, 36], but is {}", base));
         }
 
         if val == 0 {
-            return String::from("0");
+            return Ok(String::from("0"));
         }
 
         let sign = if signed {
@@ -550,15 +3170,22 @@ impl Struct {
             ret.insert_str(0, format!("0[{}]", base).as_ref());
         }
         ret.insert_str(0, sign);
-        return ret;
+        Ok(ret)
+    }
+
+    fn code_u8(&self, pc: usize) -> Result<u8, String> {
+        match self.code.buffer.get(pc) {
+            Some(b) => Ok(*b),
+            None    => Err(String::from("Instruction exceeds end of code"))
+        }
     }
 
-    fn load_constant_u64(&self, pc: usize) -> u64 {
+    fn load_constant_u64(&self, pc: usize) -> Result<u64, String> {
         let mut val = 0u64;
         for i in 0..8 {
-            val |= (self.code.buffer[pc + i] as u64) << (i * 8);
+            val |= (self.code_u8(pc + i)? as u64) << (i * 8);
         }
-        return val;
+        Ok(val)
     }
 
     fn load_constant_utf8_string(&self, pc: usize, len: Option<u64>)
@@ -575,7 +3202,7 @@ impl Struct {
             let mut codepoint: u32;
             let mut tail_length: usize;
 
-            let start = self.code.buffer[pc + i];
+            let start = self.code_u8(pc + i)?;
             i += 1;
 
             if start & 0x80 == 0x00 {
@@ -594,34 +3221,186 @@ impl Struct {
                 return Err(String::from("Invalid utf-8 string constant"));
             }
 
-            while tail_length > 0 {
-                let byte = self.code.buffer[pc + i];
-                self.assert(byte & 0xc0 == 0x80,
-                            String::from("Invalid utf-8 string constant"))?;
+            while tail_length > 0 {
+                let byte = self.code_u8(pc + i)?;
+                self.assert(byte & 0xc0 == 0x80,
+                            String::from("Invalid utf-8 string constant"))?;
+
+                tail_length -= 1;
+                codepoint |= ((byte & 0x3f) as u32) << (tail_length * 6);
+                i += 1;
+            }
+
+            if len.is_none() && codepoint == 0 {
+                break;
+            }
+
+            match std::char::from_u32(codepoint) {
+                Some(c) => string.push(c),
+                None    =>
+                    return Err(String::from("Invalid utf-8 string constant")),
+            };
+
+            rem -= 1;
+        }
+
+        return Ok((string, i));
+    }
+
+    // ISO 8601 representation of the given time since the Unix epoch
+    fn format_timestamp(&self, secs: i64, nanos: u32, utc: bool) -> String {
+        let days = secs.div_euclid(86400);
+        let tod = secs.rem_euclid(86400);
+
+        // Civil date from days since 1970-01-01 (proleptic Gregorian)
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        let mut ret = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                              year, month, day, tod / 3600, tod / 60 % 60,
+                              tod % 60);
+        if nanos != 0 {
+            let frac = format!("{:09}", nanos);
+            ret.push('.');
+            ret.push_str(frac.trim_end_matches('0'));
+        }
+        if utc {
+            ret.push('Z');
+        }
+        ret
+    }
+
+    // FAT timestamps are (date << 16 | time) in local time
+    fn format_fat_timestamp(&self, value: u64) -> String {
+        let date = (value >> 16) & 0xffff;
+        let time = value & 0xffff;
+
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                1980 + (date >> 9), (date >> 5) & 0xf, date & 0x1f,
+                time >> 11, (time >> 5) & 0x3f, (time & 0x1f) * 2)
+    }
+
+    fn format_fixed_point(&self, value: u64, signed: bool, bits: u32)
+        -> String
+    {
+        let (neg, abs) = if signed && (value as i64) < 0 {
+            (true, (value as i64).unsigned_abs())
+        } else {
+            (false, value)
+        };
+
+        let (int, mut frac) = if bits == 64 {
+            (0, abs as u128)
+        } else {
+            (abs >> bits, (abs & ((1u64 << bits) - 1)) as u128)
+        };
+
+        // Every binary fraction has a finite decimal representation
+        let mut ret = format!("{}{}.", if neg { "-" } else { "" }, int);
+        loop {
+            frac *= 10;
+            ret.push((b'0' + (frac >> bits) as u8) as char);
+            frac &= (1u128 << bits) - 1;
+            if frac == 0 {
+                break;
+            }
+        }
+        ret
+    }
+
+    fn format_ipv6(&self, bytes: &[u8]) -> String {
+        let groups: Vec<u16> = (0..8).map(|i| (bytes[i * 2] as u16) << 8 |
+                                              bytes[i * 2 + 1] as u16)
+                                     .collect();
 
-                tail_length -= 1;
-                codepoint |= ((byte & 0x3f) as u32) << (tail_length * 6);
+        // Find the longest run of zero groups (of at least two) to elide
+        let (mut best_start, mut best_len) = (0, 0);
+        let mut i = 0;
+        while i < 8 {
+            if groups[i] == 0 {
+                let start = i;
+                while i < 8 && groups[i] == 0 {
+                    i += 1;
+                }
+                if i - start > best_len {
+                    best_start = start;
+                    best_len = i - start;
+                }
+            } else {
                 i += 1;
             }
+        }
 
-            if len.is_none() && codepoint == 0 {
-                break;
+        let hex = |g: &[u16]| g.iter().map(|x| format!("{:x}", x))
+                               .collect::<Vec<String>>().join(":");
+        if best_len < 2 {
+            hex(&groups)
+        } else {
+            format!("{}::{}", hex(&groups[..best_start]),
+                    hex(&groups[best_start + best_len..]))
+        }
+    }
+
+    /*
+     * Loads a table of (key, name) pairs as used by the symbolic output
+     * instructions.  Returns the table and its length in bytes.
+     */
+    fn load_symbol_table(&self, pc: usize, key_len: usize)
+        -> Result<(SymbolTable, usize), String>
+    {
+        let count = self.load_constant_u64(pc)?;
+        let mut i = 8;
+        let mut table = Vec::new();
+
+        for _ in 0..count {
+            let mut key = Vec::with_capacity(key_len);
+            for j in 0..key_len {
+                key.push(self.code_u8(pc + i + j)?);
             }
+            i += key_len;
 
-            match std::char::from_u32(codepoint) {
-                Some(c) => string.push(c),
-                None    =>
-                    return Err(String::from("Invalid utf-8 string constant")),
-            };
+            let len = self.load_constant_u64(pc + i)?;
+            i += 8;
+            let (name, bytelen) =
+                self.load_constant_utf8_string(pc + i, Some(len))?;
+            i += bytelen;
 
-            rem -= 1;
+            table.push((key, name));
         }
 
-        return Ok((string, i));
+        Ok((table, i))
+    }
+
+    fn bytes_to_u64(&self, bytes: &[u8]) -> u64 {
+        bytes.iter().enumerate()
+             .fold(0u64, |val, (i, b)| val | ((*b as u64) << (i * 8)))
+    }
+
+    // Mixed-endian GUIDs store the first three groups in little endian
+    fn format_guid(&self, bytes: &[u8], mixed_endian: bool) -> String {
+        let mut b = bytes.to_vec();
+        if mixed_endian {
+            b[0..4].reverse();
+            b[4..6].reverse();
+            b[6..8].reverse();
+        }
+
+        let hex: Vec<String> = b.iter().map(|x| format!("{:02x}", x))
+                                .collect();
+        format!("{}-{}-{}-{}-{}", hex[0..4].concat(), hex[4..6].concat(),
+                hex[6..8].concat(), hex[8..10].concat(), hex[10..16].concat())
     }
 
     fn load_file_utf8_string(&self, file: &mut File, offset: u64,
-                             len: Option<u64>, utf8: bool)
+                             len: Option<u64>, len_in_bytes: bool, utf8: bool,
+                             limits: &CfgStructLimits)
         -> Result<(String, usize), String>
     {
         let mut string = String::new();
@@ -632,9 +3411,15 @@ impl Struct {
         let mut i = 0;
 
         while rem > 0 {
+            if i >= limits.max_string_length as u64 {
+                return Err(format!("String exceeds maximum length ({})",
+                                   limits.max_string_length));
+            }
+
             let mut codepoint: u32;
             let mut tail_length: usize;
 
+            let char_start = i;
             let start = file.read_u8(offset + i)?;
             i += 1;
 
@@ -678,73 +3463,776 @@ impl Struct {
                     return Err(String::from("Invalid utf-8 string")),
             };
 
-            rem -= 1;
+            if len_in_bytes {
+                rem = rem.saturating_sub(i as usize - char_start as usize);
+            } else {
+                rem -= 1;
+            }
         }
 
         return Ok((string, i as usize));
     }
 
-    fn output_line(&mut self, display: &mut Display, x: usize, y_limit: usize,
-                   string: String, line: LineContent)
+    /*
+     * Loads a string in an encoding other than UTF-8.  Invalid code units are
+     * replaced by U+FFFD, as is a partial code unit at the end of a sized
+     * string.
+     */
+    fn load_file_string(&self, file: &mut File, offset: u64,
+                        len: Option<u64>, encoding: Encoding,
+                        limits: &CfgStructLimits)
+        -> Result<(String, usize), String>
+    {
+        let unit_size = encoding.unit_size() as u64;
+        let end = len.unwrap_or(u64::MAX);
+        let mut decoder = Decoder::new(encoding);
+        let mut i = 0u64;
+
+        while i < end {
+            if i >= limits.max_string_length as u64 {
+                return Err(format!("String exceeds maximum length ({})",
+                                   limits.max_string_length));
+            }
+
+            if end - i < unit_size {
+                i = end;
+                decoder.push_invalid();
+                break;
+            }
+
+            let pos = offset.checked_add(i)
+                            .ok_or(String::from("String range overflows"))?;
+            let mut bytes = vec![0; unit_size as usize];
+            file.read(pos, &mut bytes)?;
+            i += unit_size;
+            let unit = encoding.unit(&bytes);
+
+            if len.is_none() && unit == 0 {
+                break;
+            }
+            decoder.push(unit);
+        }
+
+        Ok((decoder.finish(), i as usize))
+    }
+
+    /*
+     * Outputs a header line showing @text (and the empty lines around it) for
+     * the header m.current_header_path (which the caller has to set up).
+     * Returns whether the header is folded, or None if there is no more
+     * space for output.
+     */
+    fn output_header(&mut self, m: &mut Machine, max_lines: usize, level: u8,
+                     text: String)
+        -> Option<bool>
+    {
+        let folded = !m.unfold && {
+            let hdr = self.lookup_header(&m.current_header_path);
+            hdr.folded
+        };
+
+        if m.last_output_was_not_header &&
+           !self.output_line(max_lines, String::new(), LineContent::Nothing)
+        {
+            return None;
+        }
+
+        let color = if level == 0 {
+            Color::StructH0
+        } else if level == 1 {
+            Color::StructH1
+        } else if level == 2 {
+            Color::StructH2
+        } else {
+            Color::StructH3P
+        };
+        if !self.output_colored_line(max_lines, text, Some(color),
+                                     LineContent::Header {
+                                         path: m.current_header_path.clone(),
+                                     })
+        {
+            return None;
+        }
+
+        if !folded &&
+           !self.output_line(max_lines, String::new(), LineContent::Nothing)
+        {
+            return None;
+        }
+
+        m.last_output_was_not_header = false;
+
+        Some(folded)
+    }
+
+    fn output_line(&mut self, max_lines: usize, string: String,
+                   line: LineContent)
+        -> bool
+    {
+        self.output_colored_line(max_lines, string, None, line)
+    }
+
+    fn output_colored_line(&mut self, max_lines: usize, string: String,
+                           color: Option<Color>, line: LineContent)
         -> bool
     {
-        let y = self.lines.len();
+        if self.lines.len() >= max_lines {
+            return false;
+        }
+
+        self.lines.push(OutputLine {
+            text: string,
+            color,
+            content: line,
+            check: None,
+        });
 
-        if y + 1 > y_limit {
+        return true;
+    }
+
+    // Outputs the result of a check, which is highlighted if it failed
+    fn output_check_line(&mut self, max_lines: usize, string: String,
+                         passed: bool, line: LineContent)
+        -> bool
+    {
+        let color = if passed { None } else { Some(Color::ErrorInfo) };
+        if !self.output_colored_line(max_lines, string, color, line) {
             return false;
         }
 
-        display.set_cursor_pos(x, y);
-        display.clear_line();
-        display.write(string);
+        self.lines.last_mut().unwrap().check = Some(passed);
+        true
+    }
+
+    /*
+     * Draws the visible part of the output into a struct pane (which starts
+     * at row @start_y and is @height lines high), keeping the scroll position
+     * in bounds.
+     */
+    pub fn draw(&mut self, display: &mut Display, start_x: usize,
+                start_y: usize, height: usize)
+    {
+        let max_scroll = self.lines.len().saturating_sub(height);
+        if self.scroll > max_scroll {
+            self.scroll = max_scroll;
+        }
 
-        self.lines.push(line);
+        let width = display.w() as usize;
+        for y in 0..height {
+            display.set_cursor_pos(start_x, start_y + y);
+            display.clear_line();
 
-        return true;
+            let line = match self.lines.get(self.scroll + y) {
+                Some(l) => l,
+                None    => continue
+            };
+            let text: String = if width > start_x {
+                line.text.chars().take(width - start_x).collect()
+            } else {
+                String::new()
+            };
+
+            match line.color {
+                Some(ref c) => {
+                    display.color_on_ref(c);
+                    display.write(text);
+                    display.color_off_ref(c);
+                },
+                None => display.write(text)
+            }
+        }
+    }
+
+    pub fn scroll_by(&mut self, delta: isize) {
+        self.scroll = if delta < 0 {
+            self.scroll.saturating_sub(delta.unsigned_abs())
+        } else {
+            std::cmp::min(self.scroll.saturating_add(delta as usize),
+                          self.lines.len().saturating_sub(1))
+        };
+    }
+
+    // Scrolls so that output line @line is visible
+    pub fn scroll_to(&mut self, line: usize, height: usize) {
+        if line < self.scroll {
+            self.scroll = line;
+        } else if height > 0 && line >= self.scroll + height {
+            self.scroll = line + 1 - height;
+        }
+    }
+
+    // Screen row of the given output line, if it is visible
+    pub fn screen_row(&self, line: usize, height: usize) -> Option<usize> {
+        if line >= self.scroll && line < self.scroll + height {
+            Some(line - self.scroll)
+        } else {
+            None
+        }
     }
 
     fn lookup_header(&mut self, hdr_path: &Vec<String>) -> &Header {
         self.headers.entry(hdr_path.clone()).or_insert(Header {
             folded: false,
+            first: 0,
         })
     }
 
     fn lookup_header_mut(&mut self, hdr_path: &Vec<String>) -> &mut Header {
         self.headers.entry(hdr_path.clone()).or_insert(Header {
             folded: false,
+            first: 0,
+        })
+    }
+
+    // The file ranges of all fields in the output
+    pub fn field_ranges(&self) -> Vec<FieldRange> {
+        let mut ranges = Vec::new();
+        let mut level = 0;
+
+        for line in self.lines.iter() {
+            match line.content {
+                LineContent::Header { ref path } => {
+                    level = path.len().saturating_sub(1);
+                },
+
+                LineContent::Data { loc, length, big_endian, bits, .. }
+                    if length > 0 =>
+                {
+                    let (loc, length) =
+                        Self::data_span(loc, length, big_endian, bits);
+                    ranges.push(FieldRange {
+                        start: loc,
+                        length,
+                        level,
+                    });
+                },
+
+                _ => ()
+            }
+        }
+
+        ranges
+    }
+
+    /*
+     * The output as plain text for struct tests: every line with the file
+     * range of its field (and bit range, if any) or its header level in front
+     */
+    pub fn snapshot(&self) -> Vec<String> {
+        self.lines.iter().map(|line| {
+            let prefix = match line.content {
+                LineContent::Nothing => String::new(),
+                LineContent::Header { ref path } =>
+                    format!("h{}", path.len().saturating_sub(1)),
+                LineContent::Page { .. } => String::from("page"),
+                LineContent::Data { loc, length, bits: None, .. } =>
+                    format!("{:#x}+{:#x}", loc, length),
+                LineContent::Data { loc, length, bits: Some((first, width)),
+                                    .. } =>
+                    format!("{:#x}+{:#x}:{}+{}", loc, length, first, width),
+                LineContent::Check { loc, length, .. } =>
+                    format!("{:#x}+{:#x}", loc, length),
+            };
+
+            let line = format!("{:20}{}", prefix, line.text);
+            String::from(line.trim_end())
+        }).collect()
+    }
+
+    /*
+     * Returns the field whose data contains @offset (preferring one that
+     * starts there).
+     */
+    pub fn field_at(&self, offset: u64) -> Option<FieldInfo> {
+        let mut found = None;
+
+        for (i, line) in self.lines.iter().enumerate() {
+            if let LineContent::Data { loc, length, ref name, ref value,
+                                       ref link, big_endian, bits,
+                                       .. } = line.content
+            {
+                let (loc, length) =
+                    Self::data_span(loc, length, big_endian, bits);
+                if offset < loc || offset >= loc + length {
+                    continue;
+                }
+
+                let better = match found {
+                    None                            => true,
+                    Some((_, found_loc, _, _, _, _)) => found_loc != offset &&
+                                                        loc == offset,
+                };
+                if better {
+                    found = Some((i, loc, name, value, link, bits));
+                }
+            }
+        }
+
+        found.map(|(i, _, name, value, link, bits)| FieldInfo {
+            line: i,
+            name: name.clone(),
+            value: value.clone(),
+            link: link.clone(),
+            bits: bits.map(|(first, width)| (first, first + width - 1)),
         })
     }
 
-    pub fn mouse_down(&mut self, line: usize)
+    // The bytes actually holding a field's data (only part of a bit field's)
+    fn data_span(loc: u64, length: u64, big_endian: bool,
+                 bits: Option<(u64, u64)>)
+        -> (u64, u64)
+    {
+        match bits {
+            Some((first, width)) => {
+                let (loc, length, _) =
+                    bit_span(loc, length, big_endian, first, width);
+                (loc, length)
+            },
+            None => (loc, length)
+        }
+    }
+
+    /*
+     * Encodes @input as the new value of the field on the given line.
+     * Returns the offset and the data to write there.  Bit fields are merged
+     * into the surrounding bits currently in @file.
+     */
+    pub fn encode_field(&self, file: &mut File, line: usize, input: &str)
+        -> Result<(u64, Vec<u8>), String>
+    {
+        let content = self.lines.get(line).map(|l| &l.content);
+        let (loc, length, kind, big_endian, bits) = match content {
+            Some(&LineContent::Data { loc, length, ref kind, big_endian,
+                                      bits, .. }) =>
+                (loc, length, kind.clone(), big_endian, bits),
+            _ => return Err(String::from("Not a field"))
+        };
+
+        if bits.is_some() {
+            return self.encode_bit_field(file, line, input);
+        }
+
+        let mut bytes: Vec<u8> = match kind {
+            FieldKind::Int { signed, base } => {
+                if length == 0 || length > 8 {
+                    return Err(format!("Cannot encode a {}-byte integer",
+                                       length));
+                }
+
+                let val = self.parse_int(input, signed, base)?;
+                let bits = length * 8;
+                let fits = if bits == 64 {
+                    true
+                } else if signed {
+                    let min = -(1i64 << (bits - 1));
+                    let max = (1i64 << (bits - 1)) - 1;
+                    (val as i64) >= min && (val as i64) <= max
+                } else {
+                    val >> bits == 0
+                };
+                if !fits {
+                    return Err(format!("{} does not fit into {} bytes", input,
+                                       length));
+                }
+
+                (0..length).map(|i| (val >> (i * 8)) as u8).collect()
+            },
+
+            FieldKind::Float => {
+                let val = match input.trim().parse::<f64>() {
+                    Ok(v)   => v,
+                    Err(e)  => return Err(format!("{}: {}", input, e))
+                };
+
+                let bits = match length {
+                    4 => (val as f32).to_bits() as u64,
+                    8 => val.to_bits(),
+                    _ => return Err(format!("Cannot encode a {}-byte float",
+                                            length))
+                };
+
+                (0..length).map(|i| (bits >> (i * 8)) as u8).collect()
+            },
+
+            FieldKind::Str { encoding, null_terminated } => {
+                let mut bytes = encoding.encode(input)?;
+                // Keep the last code unit for the terminator
+                let max = if null_terminated {
+                    length.saturating_sub(encoding.unit_size() as u64)
+                } else {
+                    length
+                };
+                if bytes.len() as u64 > max {
+                    return Err(format!("String too long (max. {} bytes)",
+                                       max));
+                }
+                bytes.resize(length as usize, 0);
+
+                // Strings are not subject to endianness
+                return Ok((loc, bytes));
+            },
+
+            FieldKind::Guid { mixed_endian } => {
+                let digits: String = input.chars()
+                    .filter(|c| *c != '-' && *c != '{' && *c != '}' &&
+                                !c.is_whitespace())
+                    .collect();
+                if digits.len() != 32 || !digits.is_ascii() {
+                    return Err(String::from("A GUID must have 32 hex digits"));
+                }
+
+                let mut bytes = Vec::new();
+                for i in 0..16 {
+                    match u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16) {
+                        Ok(b)   => bytes.push(b),
+                        Err(_)  => return Err(format!("Invalid GUID “{}”",
+                                                      input))
+                    }
+                }
+                if mixed_endian {
+                    bytes[0..4].reverse();
+                    bytes[4..6].reverse();
+                    bytes[6..8].reverse();
+                }
+
+                // The byte order is fixed by the GUID format
+                return Ok((loc, bytes));
+            },
+
+            FieldKind::Bytes => {
+                let digits: String = input.chars()
+                    .filter(|c| !c.is_whitespace()).collect();
+                if digits.len() as u64 != length * 2 || !digits.is_ascii() {
+                    return Err(format!("Expected {} hex bytes", length));
+                }
+
+                let mut bytes = Vec::new();
+                for i in 0..(length as usize) {
+                    match u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16) {
+                        Ok(b)   => bytes.push(b),
+                        Err(_)  => return Err(format!("Invalid hex bytes \
+                                                       “{}”", input))
+                    }
+                }
+
+                return Ok((loc, bytes));
+            },
+
+            FieldKind::Varint { encoding, base } => {
+                let signed = encoding == 0x01 || encoding == 0x02;
+                let val = self.parse_int(input, signed, base)?;
+
+                // Keep the length, so nothing after the field moves
+                return match encode_varint(encoding, val, length) {
+                    Some(bytes) => Ok((loc, bytes)),
+                    None        => Err(format!("{} does not fit into {} bytes",
+                                               input, length))
+                };
+            },
+        };
+
+        if big_endian {
+            bytes.reverse();
+        }
+        Ok((loc, bytes))
+    }
+
+    fn encode_bit_field(&self, file: &mut File, line: usize, input: &str)
+        -> Result<(u64, Vec<u8>), String>
+    {
+        let content = self.lines.get(line).map(|l| &l.content);
+        let (loc, length, signed, base, big_endian, first, width) =
+            match content {
+                Some(&LineContent::Data { loc, length,
+                                          kind: FieldKind::Int { signed,
+                                                                 base },
+                                          big_endian,
+                                          bits: Some((first, width)),
+                                          .. }) =>
+                    (loc, length, signed, base, big_endian, first, width),
+                _ => return Err(String::from("Not a bit field"))
+            };
+
+        if length == 0 || length > 8 {
+            return Err(format!("Cannot encode a {}-byte integer", length));
+        }
+
+        let val = self.parse_int(input, signed, base)?;
+        let fits = if width == 64 {
+            true
+        } else if signed {
+            let min = -(1i64 << (width - 1));
+            let max = (1i64 << (width - 1)) - 1;
+            (val as i64) >= min && (val as i64) <= max
+        } else {
+            val >> width == 0
+        };
+        if !fits {
+            return Err(format!("{} does not fit into {} bits", input, width));
+        }
+
+        let mut bytes = vec![0; length as usize];
+        file.read(loc, &mut bytes)?;
+        if big_endian {
+            bytes.reverse();
+        }
+
+        let mask = bit_mask(first, width)?;
+        let old = self.bytes_to_u64(&bytes);
+        let new = (old & !mask) | ((val << first) & mask);
+
+        let mut bytes: Vec<u8> =
+            (0..length).map(|i| (new >> (i * 8)) as u8).collect();
+        if big_endian {
+            bytes.reverse();
+        }
+
+        // Only write the bytes that contain the field
+        let (span_loc, span_len, _) =
+            bit_span(loc, length, big_endian, first, width);
+        let start = (span_loc - loc) as usize;
+        Ok((span_loc, bytes[start..start + span_len as usize].to_vec()))
+    }
+
+    // Parses an integer as displayed by format_int()
+    fn parse_int(&self, input: &str, signed: bool, base: usize)
+        -> Result<u64, String>
+    {
+        let input = input.trim();
+        let (neg, digits) = match input.strip_prefix('-') {
+            Some(rest)  => (true, rest),
+            None        => (false, input)
+        };
+
+        if neg && !signed {
+            return Err(String::from("Field is unsigned"));
+        }
+
+        let (base, digits) = if let Some(hex) = digits.strip_prefix("0x") {
+            (16, hex)
+        } else if let Some(oct) = digits.strip_prefix("0o") {
+            (8, oct)
+        } else if let Some(bin) = digits.strip_prefix("0b") {
+            (2, bin)
+        } else if digits.starts_with("0[") {
+            match digits.find(']') {
+                Some(end) => match digits[2..end].parse::<usize>() {
+                    Ok(b) if (2..=36).contains(&b)  => (b, &digits[end + 1..]),
+                    _ => return Err(format!("Invalid base in {}", input))
+                },
+                None => return Err(format!("Invalid base in {}", input))
+            }
+        } else {
+            (base, digits)
+        };
+
+        let val = match u64::from_str_radix(digits, base as u32) {
+            Ok(v)   => v,
+            Err(e)  => return Err(format!("{}: {}", input, e))
+        };
+
+        if neg {
+            if val > 1u64 << 63 {
+                return Err(format!("{} is out of range", input));
+            }
+            Ok(val.wrapping_neg())
+        } else {
+            Ok(val)
+        }
+    }
+
+    // @row is relative to the top of the struct pane
+    pub fn mouse_down(&mut self, row: usize)
         -> Result<MouseDownResult, String>
     {
+        let line = self.scroll + row;
         if line >= self.lines.len() {
             return Ok(MouseDownResult {
                 need_update: false,
                 highlight: None,
+                link: None,
             });
         }
 
-        match self.lines[line].clone() {
+        match self.lines[line].content.clone() {
             LineContent::Nothing => Ok(MouseDownResult {
                 need_update: false,
                 highlight: None,
+                link: None,
             }),
 
             LineContent::Header { path } => {
+                self.cache = None;
                 let hdr = self.lookup_header_mut(&path);
                 hdr.folded = !hdr.folded;
 
                 Ok(MouseDownResult {
                     need_update: true,
                     highlight: None,
+                    link: None,
+                })
+            },
+
+            LineContent::Page { path, forward } => {
+                self.cache = None;
+                let hdr = self.lookup_header_mut(&path);
+                hdr.first = if forward {
+                    hdr.first.saturating_add(ARRAY_PAGE_SIZE)
+                } else {
+                    hdr.first.saturating_sub(ARRAY_PAGE_SIZE)
+                };
+
+                Ok(MouseDownResult {
+                    need_update: true,
+                    highlight: None,
+                    link: None,
                 })
             },
 
-            LineContent::Data { loc, length } => Ok(MouseDownResult {
+            LineContent::Data { loc, length, link, big_endian, bits, .. } =>
+                Ok(MouseDownResult {
+                    need_update: false,
+                    highlight: if link.is_some() {
+                        None
+                    } else {
+                        Some(Self::data_span(loc, length, big_endian, bits))
+                    },
+                    link,
+                }),
+
+            LineContent::Check { loc, length, .. } => Ok(MouseDownResult {
                 need_update: false,
-                highlight: Some((loc, length)),
+                highlight: if length > 0 { Some((loc, length)) } else { None },
+                link: None,
             }),
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_bcd, decode_varint, encode_varint};
+
+    fn varint(encoding: u8, bytes: &[u8]) -> Result<(u64, u64), String> {
+        decode_varint(encoding, |o| match bytes.get(o as usize) {
+            Some(&b) => Ok(b),
+            None     => Err(String::from("EOF"))
+        }, 0)
+    }
+
+    fn bcd(bytes: &[u8]) -> Result<u64, String> {
+        decode_bcd(|o| Ok(bytes[o as usize]), 0, bytes.len() as u64)
+    }
+
+    #[test]
+    fn uleb128() {
+        assert_eq!(varint(0x00, &[0x00]), Ok((0, 1)));
+        assert_eq!(varint(0x00, &[0x7f]), Ok((127, 1)));
+        assert_eq!(varint(0x00, &[0x80, 0x01]), Ok((128, 2)));
+        assert_eq!(varint(0x00, &[0xe5, 0x8e, 0x26, 0xff]), Ok((624485, 3)));
+
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(varint(0x00, &max), Ok((!0, 10)));
+    }
+
+    #[test]
+    fn sleb128() {
+        assert_eq!(varint(0x01, &[0x02]), Ok((2, 1)));
+        assert_eq!(varint(0x01, &[0x7f]), Ok((-1i64 as u64, 1)));
+        assert_eq!(varint(0x01, &[0x80, 0x7f]), Ok((-128i64 as u64, 2)));
+        assert_eq!(varint(0x01, &[0xc0, 0xbb, 0x78]),
+                   Ok((-123456i64 as u64, 3)));
+
+        let min = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f];
+        assert_eq!(varint(0x01, &min), Ok((i64::MIN as u64, 10)));
+    }
+
+    #[test]
+    fn zigzag() {
+        assert_eq!(varint(0x02, &[0x00]), Ok((0, 1)));
+        assert_eq!(varint(0x02, &[0x01]), Ok((-1i64 as u64, 1)));
+        assert_eq!(varint(0x02, &[0x02]), Ok((1, 1)));
+        assert_eq!(varint(0x02, &[0x03]), Ok((-2i64 as u64, 1)));
+        assert_eq!(varint(0x02, &[0xac, 0x02]), Ok((150, 2)));
+    }
+
+    #[test]
+    fn vlq() {
+        assert_eq!(varint(0x03, &[0x7f]), Ok((0x7f, 1)));
+        assert_eq!(varint(0x03, &[0x81, 0x00]), Ok((0x80, 2)));
+        assert_eq!(varint(0x03, &[0xff, 0xff, 0x7f]), Ok((0x1fffff, 3)));
+
+        let max = [0x81, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(varint(0x03, &max), Ok((!0, 10)));
+    }
+
+    #[test]
+    fn beyond_64_bits() {
+        // Eleventh byte
+        let long = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80,
+                    0x00];
+        for encoding in 0x00..0x04 {
+            assert_eq!(varint(encoding, &long),
+                       Err(if encoding == 0x03 {
+                           String::from("VLQ at 0x0 is too long")
+                       } else {
+                           String::from("LEB128 number at 0x0 is too long")
+                       }));
+        }
+
+        // Bits above bit 63 in the tenth byte
+        let wide = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02];
+        assert_eq!(varint(0x00, &wide),
+                   Err(String::from("LEB128 number at 0x0 exceeds 64 bits")));
+        let bad_sign = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80,
+                        0x01];
+        assert_eq!(varint(0x01, &bad_sign),
+                   Err(String::from("LEB128 number at 0x0 exceeds 64 bits")));
+        let vlq = [0x82, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00];
+        assert_eq!(varint(0x03, &vlq),
+                   Err(String::from("VLQ at 0x0 exceeds 64 bits")));
+
+        assert_eq!(varint(0x00, &[0x80]), Err(String::from("EOF")));
+    }
+
+    #[test]
+    fn bcd_numbers() {
+        assert_eq!(bcd(&[]), Ok(0));
+        assert_eq!(bcd(&[0x12, 0x34]), Ok(1234));
+        assert_eq!(bcd(&[0x00, 0x09]), Ok(9));
+        assert_eq!(bcd(&[0x18, 0x44, 0x67, 0x44, 0x07, 0x37, 0x09, 0x55, 0x16,
+                         0x15]),
+                   Ok(!0));
+
+        assert_eq!(bcd(&[0x18, 0x44, 0x67, 0x44, 0x07, 0x37, 0x09, 0x55, 0x16,
+                         0x16]),
+                   Err(String::from("BCD number at 0x0 exceeds 64 bits")));
+        assert_eq!(bcd(&[0x1a]),
+                   Err(String::from("Invalid BCD digit at 0x0")));
+        assert_eq!(bcd(&[0; 11]),
+                   Err(String::from("BCD numbers can have at most 10 bytes, \
+                                     not 11")));
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = [0u64, 1, 63, 64, 127, 128, 300, 0x7fff_ffff, !0,
+                      -1i64 as u64, -64i64 as u64, -65i64 as u64,
+                      i64::MIN as u64];
+        for encoding in 0x00..0x04 {
+            for &val in values.iter() {
+                for length in 1..11 {
+                    if let Some(bytes) = encode_varint(encoding, val, length) {
+                        assert_eq!(varint(encoding, &bytes),
+                                   Ok((val, length)),
+                                   "{} {:#x} {}", encoding, val, length);
+                    }
+                }
+            }
+        }
+
+        assert_eq!(encode_varint(0x04, 1234, 3), Some(vec![0x00, 0x12, 0x34]));
+        assert_eq!(encode_varint(0x04, 1234, 1), None);
+    }
+}
@@ -101,7 +101,10 @@ fn write64(file: &mut std::fs::File, val: u64) -> Result<(), String> {
  *   - +0x0: u64 modified address
  *   - +0x8: u8 old byte
  *   - +0x9: u8 new byte
- *   - +0xa: 6 bytes reserved
+ *   - +0xa: u8 flags
+ *           - bit 0: Joined, i.e. part of the same change as the previous
+ *             block (undone and redone together with it)
+ *   - +0xb: 5 bytes reserved
  *
  * When performing a change, a new data block describing it is written at the
  * current position and the file is truncated beyond this block.  The position
@@ -109,14 +112,18 @@ fn write64(file: &mut std::fs::File, val: u64) -> Result<(), String> {
  *
  * When performing an undo, the position is updated to point at the previous
  * block and the information therein as read and used to perform the undo.
+ * This is repeated as long as the block was joined to its predecessor.
  * If the position is 0x10, no undo is possible.
  *
  * When performing a redo, the block at the current position is read and the
  * information therein is used to perform the redo.  The position is then
- * updated to point to the next block.
+ * updated to point to the next block.  This is repeated as long as that next
+ * block is joined to the one just read.
  * If the position is the EOF, no redo is possible.
  */
 
+const FLAG_JOINED: u8 = 0x01;
+
 impl UndoFile {
     pub fn new(config: &mut ConfigFile, for_filename: String) -> Result<Self, String> {
         let fname = config.get_undo_filename(&for_filename)?;
@@ -198,15 +205,18 @@ impl UndoFile {
      *   3. undo_file.settle()?;
      *
      * For undos, you do this:
-     *   1. (addr, old) = undo_file.undo()?;
+     *   1. [(addr, old)...] = undo_file.undo()?;
      *   2. binary_file_modify()?;
      *   3. undo_file.settle()?;
      *
      * For redos, you do this:
-     *   1. (addr, new) = undo_file.redo()?;
+     *   1. [(addr, new)...] = undo_file.redo()?;
      *   2. binary_file_modify()?;
      *   3. undo_file.settle()?;
      *
+     * Changes that should be undone as a whole are entered with
+     * enter_joined() for every byte but the first.
+     *
      * This should allow the undo file to generally stay consistent even in case
      * of errors, and allow the user to undo/redo things if modifying the file
      * itself failed somehow.
@@ -243,19 +253,30 @@ impl UndoFile {
     pub fn enter(&mut self, address: u64, old: u8, new: u8)
         -> Result<(), String>
     {
-        match self.do_enter(address, old, new) {
+        match self.do_enter(address, old, new, 0) {
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(format!("{} (redo may be garbage)", e))
+        }
+    }
+
+    // Like enter(), but the change is undone and redone with the previous one
+    pub fn enter_joined(&mut self, address: u64, old: u8, new: u8)
+        -> Result<(), String>
+    {
+        match self.do_enter(address, old, new, FLAG_JOINED) {
             Ok(_)   => Ok(()),
             Err(e)  => Err(format!("{} (redo may be garbage)", e))
         }
     }
 
-    fn do_enter(&mut self, address: u64, old: u8, new: u8)
+    fn do_enter(&mut self, address: u64, old: u8, new: u8, flags: u8)
         -> Result<(), String>
     {
         seek(&mut self.file, std::io::SeekFrom::Start(self.loc))?;
         write64(&mut self.file, address)?;
         write8(&mut self.file, old)?;
         write8(&mut self.file, new)?;
+        write8(&mut self.file, flags)?;
 
         self.loc += 0x10;
         self.lof = self.loc;
@@ -264,44 +285,70 @@ impl UndoFile {
         Ok(())
     }
 
-    pub fn undo(&mut self) -> Result<Option<(u64, u8)>, String> {
+    pub fn undo(&mut self) -> Result<Option<Vec<(u64, u8)>>, String> {
         match self.do_undo() {
             Ok(r)   => Ok(r),
             Err(e)  => Err(format!("{} (log is unchanged)", e))
         }
     }
 
-    fn do_undo(&mut self) -> Result<Option<(u64, u8)>, String> {
+    fn do_undo(&mut self) -> Result<Option<Vec<(u64, u8)>>, String> {
         if self.loc == 0x10 {
             return Ok(None);
         }
 
-        self.loc -= 0x10;
-        seek(&mut self.file, std::io::SeekFrom::Start(self.loc))?;
-        let address = read64(&mut self.file)?;
-        let old = read8(&mut self.file)?;
+        let mut loc = self.loc;
+        let mut changes = Vec::new();
+        loop {
+            loc -= 0x10;
+            seek(&mut self.file, std::io::SeekFrom::Start(loc))?;
+            let address = read64(&mut self.file)?;
+            let old = read8(&mut self.file)?;
+            read8(&mut self.file)?;
+            let flags = read8(&mut self.file)?;
+            changes.push((address, old));
+
+            if flags & FLAG_JOINED == 0 || loc == 0x10 {
+                break;
+            }
+        }
+        self.loc = loc;
 
-        Ok(Some((address, old)))
+        Ok(Some(changes))
     }
 
-    pub fn redo(&mut self) -> Result<Option<(u64, u8)>, String> {
+    pub fn redo(&mut self) -> Result<Option<Vec<(u64, u8)>>, String> {
         match self.do_redo() {
             Ok(r)   => Ok(r),
             Err(e)  => Err(format!("{} (log is unchanged)", e))
         }
     }
 
-    pub fn do_redo(&mut self) -> Result<Option<(u64, u8)>, String> {
+    pub fn do_redo(&mut self) -> Result<Option<Vec<(u64, u8)>>, String> {
         if self.loc == self.lof {
             return Ok(None);
         }
 
-        seek(&mut self.file, std::io::SeekFrom::Start(self.loc))?;
-        let address = read64(&mut self.file)?;
-        read8(&mut self.file)?;
-        let new = read8(&mut self.file)?;
-        self.loc += 0x10;
+        let mut loc = self.loc;
+        let mut changes = Vec::new();
+        loop {
+            seek(&mut self.file, std::io::SeekFrom::Start(loc))?;
+            let address = read64(&mut self.file)?;
+            read8(&mut self.file)?;
+            let new = read8(&mut self.file)?;
+            changes.push((address, new));
+            loc += 0x10;
+
+            if loc == self.lof {
+                break;
+            }
+            seek(&mut self.file, std::io::SeekFrom::Start(loc + 0xa))?;
+            if read8(&mut self.file)? & FLAG_JOINED == 0 {
+                break;
+            }
+        }
+        self.loc = loc;
 
-        Ok(Some((address, new)))
+        Ok(Some(changes))
     }
 }